@@ -0,0 +1,330 @@
+// Generated from protocol.rs, do not edit.
+
+/**
+ * An audio rendition of a media stream, announced with `#EXT-X-MEDIA` in its master playlist.
+ */
+export interface AudioTrack {
+    /**
+     * Whether players start out with this track.
+     */
+    default: boolean;
+    /**
+     * The `GROUP-ID` the track belongs to.
+     */
+    group: string;
+    language?: string | null;
+    /**
+     * Unique within the group, this is what participants pick tracks by.
+     */
+    name: string;
+    /**
+     * Playlist of the track relative to the master playlist, `None` when the audio is muxed into the video renditions.
+     */
+    uri?: string | null;
+}
+
+/**
+ * Identifier for a badge, used to display some status for a user.
+ */
+export type BadgeId = number;
+
+export interface ChatMessage {
+    from: UserId;
+    msg: string;
+}
+
+export interface ParticipantInfo {
+    avatar: BadgeId;
+    badges: (BadgeId)[];
+    name: string;
+    user_id: UserId;
+}
+
+export interface ParticipantUpdate {
+    /**
+     * The `AudioTrack::name` the participant is listening to, if known.
+     */
+    audio?: string | null;
+    /**
+     * Whether the participant's player picks the quality on its own.
+     */
+    auto_quality: boolean;
+    badges: (BadgeId)[];
+    buffered: number;
+    duration: number;
+    /**
+     * The `Stream::quality` the participant is playing, if known.
+     */
+    quality?: number | null;
+    state: PlayState;
+    user_id: UserId;
+}
+
+/**
+ * The state the media player can be in.
+ */
+export type PlayState = "Play" | "Pause";
+
+/**
+ * Why a message from the participant was refused.
+ */
+export type ProtocolError = {
+    Malformed: {
+        details: string;
+    };
+} | {
+    FrameTooLarge: {
+        max: number;
+    };
+} | {
+    InvalidNumber: {
+        field: string;
+    };
+} | {
+    InvalidTime: {
+        field: string;
+    };
+} | {
+    InvalidName: {
+        max_length: number;
+    };
+};
+
+/**
+ * What happens to a participant that exceeds a rate limit.
+ */
+export type RateLimitPenalty = "Warn" | "Ignore" | "Disconnect";
+
+/**
+ * Why the server refused a client's handshake.
+ */
+export type RejectReason = "UnsupportedVersion" | "HandshakeRequired" | "DuplicateHello";
+
+/**
+ * Info about a media stream, containing a sortable quality number and the file name of the HLS playlist. A quality of 0 marks a master playlist, any other is a single rendition.
+ */
+export interface Stream {
+    playlist: string;
+    quality: number;
+}
+
+/**
+ * Info about a media stream, containing the directory slug for the data and a list of all available HLS playlists.
+ */
+export interface StreamInfo {
+    audio_tracks: (AudioTrack)[];
+    duration: number;
+    name: string;
+    slug: string;
+    state: PlayState;
+    streams: (Stream)[];
+    /**
+     * The track participants start out with, `None` for no subtitles.
+     */
+    subtitle?: string | null;
+    /**
+     * Seconds every subtitle cue is shifted by.
+     */
+    subtitle_offset: number;
+    subtitles: (SubtitleTrack)[];
+    /**
+     * WebVTT index of thumbnails to show when hovering the timeline, relative to the directory of the stream.
+     */
+    thumbnails?: string | null;
+}
+
+/**
+ * A subtitle track of a media stream, served as WebVTT.
+ */
+export interface SubtitleTrack {
+    /**
+     * The file name of the track next to the stream's HLS data.
+     */
+    id: string;
+    label: string;
+    /**
+     * Language code taken from the file name, like `en` in `movie.en.srt`.
+     */
+    language?: string | null;
+}
+
+/**
+ * Milliseconds since UNIX time epoch.
+ */
+export type Time = number;
+
+export type ToSessionMessage = "Ping" | {
+    Welcome: {
+        capabilities: (string)[];
+        version: number;
+    };
+} | {
+    Rejected: {
+        min_version: number;
+        reason: RejectReason;
+        version: number;
+    };
+} | {
+    RoomState: {
+        current_stream?: StreamInfo | null;
+        participants: (ParticipantInfo)[];
+        user_id: UserId;
+    };
+} | {
+    RoomUpdate: {
+        participants: (ParticipantUpdate)[];
+    };
+} | {
+    NewParticipant: {
+        avatar: BadgeId;
+        badges: (BadgeId)[];
+        name: string;
+        user_id: UserId;
+    };
+} | {
+    ByeParticipant: {
+        user_id: UserId;
+    };
+} | {
+    NewStream: StreamInfo;
+} | {
+    SetState: {
+        state: PlayState;
+        user: UserId;
+    };
+} | {
+    DoSeek: {
+        duration: number;
+        user: UserId;
+    };
+} | {
+    SubtitlesChanged: {
+        offset: number;
+        subtitle?: string | null;
+        user: UserId;
+    };
+} | {
+    Timing: {
+        /**
+         * Server time minus the client's timestamp on the reply.
+         */
+        offset: number;
+        /**
+         * Time between the server sending a ping and receiving the reply.
+         */
+        rtt: number;
+    };
+} | {
+    ChatMessage: ChatMessage;
+} | {
+    RateLimited: {
+        kind: string;
+        penalty: RateLimitPenalty;
+    };
+} | {
+    Error: ProtocolError;
+} | {
+    Restarting: {
+        duration: number;
+    };
+} | {
+    SuggestQuality: {
+        quality: number;
+    };
+};
+
+/**
+ * Unique identifier for a user. Handed out by the server.
+ */
+export type UserId = number;
+
+/**
+ * A message sent by the user to the server.
+ */
+export type UserMessage = {
+    Hello: {
+        avatar: BadgeId;
+        /**
+         * Optional features the client would like to use.
+         */
+        features?: (string)[];
+        name: string;
+        time: Time;
+        /**
+         * The protocol version spoken by the client. Clients predating the handshake don't send this and end up as version 0.
+         */
+        version?: number;
+    };
+} | "Goodbye" | {
+    State: {
+        /**
+         * The `AudioTrack::name` of the track being played, if known.
+         */
+        audio?: string | null;
+        /**
+         * Whether the player picks the rendition on its own.
+         */
+        auto_quality?: boolean;
+        /**
+         * Amount of video buffered from the perspective of the current time.
+         */
+        buffered: number;
+        /**
+         * The current time of the user's media.
+         */
+        duration: number;
+        /**
+         * The time when the time of the user's media was last updated.
+         */
+        duration_time: Time;
+        /**
+         * The `Stream::quality` of the rendition being played, if known.
+         */
+        quality?: number | null;
+        /**
+         * The current state of the user's media.
+         */
+        state: PlayState;
+        /**
+         * Time when the play state was set.
+         */
+        state_time: Time;
+        /**
+         * Time when the state was sent by the user.
+         */
+        time: Time;
+    };
+} | {
+    Seek: {
+        /**
+         * The time the user wants to seek to.
+         */
+        duration: number;
+        /**
+         * The time when the message was sent by the user.
+         */
+        time: Time;
+    };
+} | {
+    SetState: {
+        /**
+         * The state the user wants to change to.
+         */
+        state: PlayState;
+        /**
+         * The time when the message was sent by the user.
+         */
+        time: Time;
+    };
+} | {
+    SetSubtitles: {
+        /**
+         * Seconds to shift every cue by, negative to show them earlier.
+         */
+        offset: number;
+        /**
+         * A `SubtitleTrack::id` of the current media, `None` for no subtitles.
+         */
+        subtitle?: string | null;
+    };
+};
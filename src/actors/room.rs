@@ -1,23 +1,33 @@
-use actix::{Actor, Addr, AsyncContext, Context, Handler, Message, StreamHandler};
+use actix::{Actor, Addr, AsyncContext, Context, Handler, Message, MessageResult, Recipient, StreamHandler};
 
-use crate::protocol::{badges, UserId, BadgeId, StreamInfo, Stream, ToSessionMessage, ParticipantInfo, ClientMessage, PlayState, ParticipantUpdate, UserMessage,ClientTime,ServerTime, Time};
-use crate::actors::{WebsocketTransport};
+use crate::protocol::{badges, capabilities, UserId, BadgeId, StreamInfo, Stream, AudioTrack, SubtitleTrack, ToSessionMessage, ParticipantInfo, ClientMessage, PlayState, ParticipantUpdate, UserMessage,ClientTime,ServerTime, Time};
+use crate::clock::{SharedClock, SystemClock};
+use crate::audit::{AuditError, AuditEvent, AuditEventKind, AuditLog};
+use crate::recording::Recorder;
+use crate::metrics;
 use stop_token::{StopSource, StopToken};
 
-use log::*;
+use tracing::{debug, info, info_span, trace, warn, Instrument, Span};
 
 use chrono::{TimeZone, DateTime, Utc};
+use serde::{Deserialize, Serialize};
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
 use std::ops::Deref;
 use std::fmt;
+use std::rc::Rc;
+
+/// Participants with less than this many seconds buffered are running low.
+const LOW_BUFFER: f32 = 5.0;
+/// How many states in a row have to run low before a lower rendition is suggested.
+const LOW_BUFFER_STATES: u32 = 3;
 
 fn get_majority_time(times: &[f32], window: f32) -> f32 {
     0f32
 }
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StreamMetadata {
     pub title: String,
     pub duration: String,
@@ -25,32 +35,82 @@ pub struct StreamMetadata {
     pub imdb: Option<String>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct RoomMetadata {
     pub name: String,
     pub stream: StreamMetadata,
 }
 
-#[derive(Debug, Clone)]
+/// Where the HLS data of a stream comes from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MediaSource {
+    /// The directory named after the slug in the media directory.
+    Local,
+    /// A master or media playlist elsewhere, played through the proxy.
+    Remote { url: String },
+}
+
+impl Default for MediaSource {
+    fn default() -> Self {
+        MediaSource::Local
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MediaStream {
     pub slug: String,
     pub name: String,
     pub streams: Vec<Stream>,
     pub meta: StreamMetadata,
+    /// Length of the media in seconds, if known.
+    pub length: Option<f32>,
+    /// Audio renditions announced in the master playlist.
+    #[serde(default)]
+    pub audio_tracks: Vec<AudioTrack>,
+    /// Subtitle sidecars found next to the HLS data.
+    #[serde(default)]
+    pub subtitles: Vec<SubtitleTrack>,
+    /// WebVTT index of the trickplay thumbnails, relative to the directory of the stream.
+    #[serde(default)]
+    pub thumbnails: Option<String>,
+    #[serde(default)]
+    pub source: MediaSource,
 }
 
 impl MediaStream {
-    fn to_stream_info(&self, duration: f32, state: PlayState) -> StreamInfo {
+    fn to_stream_info(&self, duration: f32, state: PlayState, subtitle: Option<String>, subtitle_offset: f32) -> StreamInfo {
         StreamInfo {
             slug: self.slug.clone(),
             name: self.name.clone(),
             streams: self.streams.clone(),
             duration,
-            state
+            state,
+            audio_tracks: self.audio_tracks.clone(),
+            subtitles: self.subtitles.clone(),
+            thumbnails: self.thumbnails.clone(),
+            subtitle,
+            subtitle_offset,
         }
     }
 }
 
+/// What is kept of a room across a restart. Participants have to reconnect, but keep their user
+/// ids since the cookies are kept.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomSnapshot {
+    pub name: String,
+    pub stream: Option<MediaStream>,
+    /// Where the room was paused.
+    pub duration: f32,
+    pub cookies: HashMap<String, UserId>,
+    pub free_user_id: u32,
+    pub admins: HashSet<String>,
+    #[serde(default)]
+    pub subtitle: Option<String>,
+    #[serde(default)]
+    pub subtitle_offset: f32,
+}
+
 pub struct Room {
     name: String,
     cookies: HashMap<String, UserId>,
@@ -61,13 +121,55 @@ pub struct Room {
     state_set: ServerTime,
     position_set: ServerTime,
     duration: f32,
+    /// The subtitle track participants start out with.
+    subtitle: Option<String>,
+    /// Seconds every subtitle cue is shifted by.
+    subtitle_offset: f32,
+    clock: SharedClock,
+    /// Parent span of everything happening in the room, carries the room code.
+    span: Span,
+    audit: Option<AuditLog>,
+    /// Cookies of participants that passed the admin check on login.
+    admins: HashSet<String>,
+    recorder: Option<Rc<Recorder>>,
 }
 
 impl Room {
+    /// Limits a position to the length of the current media.
+    fn clamp_position(&self, duration: f32) -> f32 {
+        match self.current_stream.as_ref().and_then(|s| s.length) {
+            Some(length) if duration > length => {
+                debug!(duration, length, "clamping position to media length");
+                length
+            }
+            _ => duration,
+        }
+    }
+
+    /// `quality` if the current media has a rendition of it, the only ones a participant can be
+    /// playing. Anything else is treated as unknown.
+    fn known_quality(&self, quality: Option<u32>) -> Option<u32> {
+        let streams = self.current_stream.as_ref().map(|s| s.streams.as_slice()).unwrap_or_default();
+
+        match quality {
+            Some(quality) if quality == 0 || !streams.iter().any(|s| s.quality == quality) => {
+                debug!(quality, "ignoring unknown quality");
+                None
+            }
+            quality => quality,
+        }
+    }
+
     pub fn new(name: String, stream: Option<MediaStream>) -> Self {
-        let now = Utc::now();
+        Self::with_clock(name, stream, SystemClock::shared())
+    }
+
+    pub fn with_clock(name: String, stream: Option<MediaStream>, clock: SharedClock) -> Self {
+        let now = clock.now();
+        let span = info_span!("room", room = %name);
 
         Self {
+            span,
             name,
             cookies: HashMap::new(),
             participants: Vec::new(),
@@ -77,16 +179,78 @@ impl Room {
             state_set: ServerTime(now),
             position_set: ServerTime(now),
             duration: 0f32,
+            subtitle: None,
+            subtitle_offset: 0f32,
+            clock,
+            audit: None,
+            admins: HashSet::new(),
+            recorder: None,
+        }
+    }
+
+    /// Recreates a room saved by `Shutdown`. It starts out paused where it was left.
+    pub fn from_snapshot(snapshot: RoomSnapshot, clock: SharedClock) -> Self {
+        let mut room = Self::with_clock(snapshot.name, snapshot.stream, clock);
+
+        room.duration = snapshot.duration;
+        room.cookies = snapshot.cookies;
+        room.free_user_id = snapshot.free_user_id;
+        room.admins = snapshot.admins;
+        room.subtitle = snapshot.subtitle;
+        room.subtitle_offset = snapshot.subtitle_offset;
+
+        room
+    }
+
+    pub fn stream(&self) -> Option<&MediaStream> {
+        self.current_stream.as_ref()
+    }
+
+    /// Records every message going in and out of the room to `recorder`, see `recording`.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(Rc::new(recorder));
+        self
+    }
+
+    /// Records joins, leaves, seeks and state changes to `log`.
+    pub fn with_audit_log(mut self, log: AuditLog) -> Self {
+        self.audit = Some(log);
+        self
+    }
+
+    fn audit(&mut self, user: Option<UserId>, event: AuditEventKind) {
+        let name = user
+            .and_then(|user| self.participants.iter().find(|p| p.user_id == user))
+            .map(|p| p.name.clone());
+
+        if let Some(log) = &self.audit {
+            log.append(&AuditEvent {
+                time: Time(self.clock.now().timestamp_millis()),
+                user,
+                name,
+                event,
+            });
         }
     }
 
+    /// The host is whoever got the first user id in the room. Cookies carry a random part handed
+    /// out on login, so nobody else can pass for them by picking the same nickname.
+    fn is_host_or_admin(&self, cookie: &str) -> bool {
+        self.cookies.get(cookie) == Some(&UserId(0)) || self.admins.contains(cookie)
+    }
+
+    /// Like `is_host_or_admin`, for a participant.
+    fn is_host_or_admin_user(&self, user_id: UserId) -> bool {
+        self.cookies.iter().any(|(cookie, id)| *id == user_id && self.is_host_or_admin(cookie))
+    }
+
     fn set_stream_position(&mut self, duration: f32) {
-        debug!("Setting stream position to {}", duration);
+        debug!(duration, "setting stream position");
         self.duration = duration;
     }
 
     fn get_stream_position(&self) -> f32 {
-        let now = Utc::now();
+        let now = self.clock.now();
 
         if self.room_state == PlayState::Pause {
             let since = *self.state_set - *self.position_set;
@@ -114,16 +278,19 @@ impl Room {
     }
 
     fn announce_seek(&mut self, src: UserId, time: Time, duration: f32) {
-        debug!("{:?} seeking to {}", src, duration);
+        debug!(user = src.0, duration, "seeking");
 
+        let from = self.get_stream_position();
         self.set_stream_position(duration);
 
         if let Some(mapping) = self.get_time_mapping(src) {
             self.position_set = mapping.convert(ClientTime(convert_time(time)));
         } else {
-            warn!("Couldn't find time mapping for state setter {:?}", src);
+            warn!(user = src.0, "couldn't find time mapping for state setter");
         }
 
+        self.audit(Some(src), AuditEventKind::Seek { from, to: duration });
+
         let message = ToSessionMessage::DoSeek {
             user: src,
             duration,
@@ -137,7 +304,7 @@ impl Room {
     }
 
     fn announce_state(&mut self, src: UserId, time: Time, state: PlayState) {
-        debug!("{:?} set state to {:?}", src, state);
+        debug!(user = src.0, ?state, "setting state");
 
         self.room_state = state;
         let prev = self.state_set.clone();
@@ -147,13 +314,19 @@ impl Room {
 
             if state == PlayState::Pause {
                 let since = *self.state_set - *prev;
-                self.position_set = ServerTime(Utc::now());
+                self.position_set = ServerTime(self.clock.now());
                 self.set_stream_position(self.duration + to_seconds(since));
             }
         } else {
-            warn!("Couldn't find time mapping for state setter {:?}", src);
+            warn!(user = src.0, "couldn't find time mapping for state setter");
         }
 
+        let position = self.get_stream_position();
+        self.audit(Some(src), match state {
+            PlayState::Play => AuditEventKind::Play { position },
+            PlayState::Pause => AuditEventKind::Pause { position },
+        });
+
         let message = ToSessionMessage::SetState {
             user: src,
             state,
@@ -166,6 +339,42 @@ impl Room {
         }
     }
 
+    fn announce_subtitles(&mut self, src: UserId, subtitle: Option<String>, offset: f32) {
+        let known = match &subtitle {
+            Some(id) => self.current_stream.as_ref().map_or(false, |s| s.subtitles.iter().any(|t| &t.id == id)),
+            None => true,
+        };
+
+        if !known {
+            warn!(user = src.0, ?subtitle, "ignoring unknown subtitle track");
+            return;
+        }
+
+        if !self.is_host_or_admin_user(src) {
+            warn!(user = src.0, ?subtitle, "ignoring subtitles set by someone other than the host or an admin");
+            return;
+        }
+
+        debug!(user = src.0, ?subtitle, offset, "setting subtitles");
+
+        self.subtitle = subtitle.clone();
+        self.subtitle_offset = offset;
+
+        self.audit(Some(src), AuditEventKind::Subtitles { subtitle: subtitle.clone(), offset });
+
+        let message = ToSessionMessage::SubtitlesChanged {
+            user: src,
+            subtitle,
+            offset,
+        };
+
+        for participant in &self.participants {
+            if participant.user_id != src {
+                participant.send_message(message.clone());
+            }
+        }
+    }
+
     fn update_participant_time(&mut self, src: UserId, time: Time) {
         todo!()
     }
@@ -194,19 +403,32 @@ impl Room {
         state_time: Time,
         buffered: f32,
         time: Time,
+        quality: Option<u32>,
+        auto_quality: bool,
+        audio: Option<String>,
     ) {
         if let Some(participant) = self.participants.iter_mut().find(|p| p.user_id == user_id) {
-            participant.receive_state(
+            if let Some(mapping) = participant.receive_state(
                 server_time,
                 duration,
                 duration_time,
                 state,
                 state_time,
                 buffered,
-                time
-            );
+                time,
+                quality,
+                auto_quality,
+                audio,
+            ) {
+                metrics::RTT
+                    .with_label_values(&[&self.name])
+                    .observe(to_seconds(mapping.rtt()) as f64);
+            }
+
+            let streams = self.current_stream.as_ref().map(|s| s.streams.as_slice()).unwrap_or_default();
+            participant.check_buffer(streams);
         } else {
-            warn!("Tried to update non-existant participant!");
+            warn!(user = user_id.0, "tried to update non-existant participant");
         }
 
         self.announce_participant_updates(self.get_room_updates());
@@ -216,16 +438,14 @@ impl Room {
         &mut self,
         name: String,
         avatar: BadgeId,
+        capabilities: Vec<String>,
         cookie: String,
         user_id: UserId,
         room: Addr<Room>,
-        transport: Addr<WebsocketTransport>,
+        transport: Recipient<ToSessionMessage>,
         time: Time,
     ) {
-        debug!(
-            "Adding new participant {:?} ({:?}) to room {:?}",
-            name, user_id, self.name
-        );
+        debug!(user = user_id.0, %name, "adding participant");
 
         let mut badges = Vec::new();
         match user_id.0 {
@@ -242,33 +462,43 @@ impl Room {
             name,
             avatar,
             badges,
+            capabilities,
             cookie,
             user_id,
             room,
             transport,
+            self.clock.clone(),
+            self.recorder.clone(),
             time);
+        if let Some(idx) = self.participants.iter().position(|p| p.user_id == user_id) {
+            debug!(user = user_id.0, "reconnected, replacing its previous connection");
+            self.participants.remove(idx);
+            self.announce_participant_left(user_id);
+        }
+
         let msg = participant.get_announce_message();
         self.announce_participant_new(msg);
 
         participant.send_message(self.get_room_state_for_uid(user_id));
         self.participants.push(participant);
+        self.audit(Some(user_id), AuditEventKind::Join);
+
+        self.update_participant_metrics();
     }
 
     fn remove_participant(
         &mut self,
         user_id: UserId,
     ) {
-        debug!(
-            "Removing participant ({:?}) from room {:?}",
-            user_id, self.name
-        );
-
+        debug!(user = user_id.0, "removing participant");
+        self.audit(Some(user_id), AuditEventKind::Leave);
 
         if let Some(idx) = self.participants.iter().position(|p| p.user_id == user_id) {
             self.participants.remove(idx);
         }
 
         self.announce_participant_left(user_id);
+        self.update_participant_metrics();
     }
 
     fn send_participant_ping(&mut self, user_id: UserId, ping_id: u32) {
@@ -277,26 +507,39 @@ impl Room {
                 ping_id,
             );
         } else {
-            warn!("Tried to ping non-existant participant {:?}", user_id);
+            warn!(user = user_id.0, "tried to ping non-existant participant");
         }
     }
 
+    fn update_participant_metrics(&self) {
+        metrics::PARTICIPANTS
+            .with_label_values(&[&self.name])
+            .set(self.participants.len() as i64);
+    }
+
     fn get_room_updates(&self) -> Vec<ParticipantUpdate> {
         let mut updates = Vec::new();
 
-        let now = Utc::now();
+        let now = self.clock.now();
+        let position = self.get_stream_position();
+        let drift = metrics::DRIFT.with_label_values(&[&self.name]);
 
         for p in &self.participants {
             if let Some(duration) = p.get_playing_time(ServerTime(now)) {
+                drift.observe((duration - position).abs() as f64);
+
                 updates.push(ParticipantUpdate {
                     user_id: p.user_id,
                     duration,
                     buffered: p.buffered,
                     state: p.state,
                     badges: p.badges.clone(),
+                    quality: p.quality,
+                    auto_quality: p.auto_quality,
+                    audio: p.audio.clone(),
                 });
             } else {
-                warn!("Skipped sending updates for {:?} since it was missing time mapping", p.user_id);
+                warn!(user = p.user_id.0, "skipped sending updates, missing time mapping");
             }
         }
 
@@ -312,13 +555,28 @@ impl Room {
                 avatar: p.avatar,
                 badges: p.badges.clone(),
             }).collect::<Vec<_>>(),
-            current_stream: self.current_stream.as_ref().map(|s| s.to_stream_info(self.get_stream_position(), self.room_state)),
+            current_stream: self.current_stream.as_ref().map(|s| s.to_stream_info(
+                self.get_stream_position(),
+                self.room_state,
+                self.subtitle.clone(),
+                self.subtitle_offset,
+            )),
         }
     }
 }
 
 impl Actor for Room {
     type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        if let Some(stream) = &self.current_stream {
+            let event = AuditEventKind::StreamChange {
+                slug: stream.slug.clone(),
+                name: stream.name.clone(),
+            };
+            self.audit(None, event);
+        }
+    }
 }
 
 pub struct SendPing(UserId, u32);
@@ -331,6 +589,7 @@ impl Handler<SendPing> for Room {
     type Result = ();
 
     fn handle(&mut self, msg: SendPing, _ctx: &mut Self::Context) -> Self::Result {
+        let _room = self.span.enter();
         self.send_participant_ping(msg.0, msg.1);
     }
 }
@@ -347,34 +606,109 @@ impl Handler<GetRoomMeta> for Room {
     }
 }
 
+#[derive(Message)]
+#[rtype(result = "Option<MediaStream>")]
+pub struct GetStream;
+
+impl Handler<GetStream> for Room {
+    type Result = Option<MediaStream>;
+
+    fn handle(&mut self, _msg: GetStream, _ctx: &mut Self::Context) -> Self::Result {
+        self.current_stream.clone()
+    }
+}
+
+/// Seconds the room shifts every subtitle cue by.
+#[derive(Message)]
+#[rtype(result = "f32")]
+pub struct GetSubtitleOffset;
+
+impl Handler<GetSubtitleOffset> for Room {
+    type Result = f32;
+
+    fn handle(&mut self, _msg: GetSubtitleOffset, _ctx: &mut Self::Context) -> Self::Result {
+        self.subtitle_offset
+    }
+}
+
+/// Whether the cookie belongs to someone that joined the room.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct IsMember(pub String);
+
+impl Handler<IsMember> for Room {
+    type Result = bool;
+
+    fn handle(&mut self, msg: IsMember, _ctx: &mut Self::Context) -> Self::Result {
+        self.cookies.contains_key(&msg.0)
+    }
+}
+
+/// Whether the cookie belongs to the host or an admin of the room.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct IsHostOrAdmin(pub String);
+
+impl Handler<IsHostOrAdmin> for Room {
+    type Result = bool;
+
+    fn handle(&mut self, msg: IsHostOrAdmin, _ctx: &mut Self::Context) -> Self::Result {
+        self.is_host_or_admin(&msg.0)
+    }
+}
+
 impl Handler<ClientMessage> for Room {
     type Result = anyhow::Result<()>;
 
     fn handle(&mut self, msg: ClientMessage, ctx: &mut Self::Context) -> Self::Result {
+        let span = info_span!(parent: &self.span, "message", user = msg.from.0, kind = msg.message.kind());
+        let _message = span.enter();
+
+        if let Some(recorder) = &self.recorder {
+            recorder.incoming(&msg);
+        }
+
         match msg.message {
-            UserMessage::Hello { name, avatar, time } => {
-                self.add_participant(name, avatar, msg.cookie, msg.from, ctx.address(), msg.addr, time);
+            UserMessage::Hello { name, avatar, time, features, .. } => {
+                self.add_participant(name, avatar, features, msg.cookie, msg.from, ctx.address(), msg.addr, time);
             }
             /*UserMessage::Pong(time) => {
                 self.update_participant_time(msg.from, time);
             }*/
             UserMessage::Goodbye => {
-                self.remove_participant(msg.from);
+                // A participant that reconnected before its old connection timed out has
+                // already been replaced, so only the current transport may remove it.
+                let current = self.participants.iter().any(|p| p.user_id == msg.from && p.transport == msg.addr);
+
+                if current {
+                    self.remove_participant(msg.from);
+                } else {
+                    debug!("ignoring goodbye from stale connection");
+                }
             }
             UserMessage::Seek { duration, time } => {
+                let duration = self.clamp_position(duration);
                 self.announce_seek(msg.from, time, duration);
             }
             UserMessage::SetState { state, time } => {
                 self.announce_state(msg.from, time, state);
             }
+            UserMessage::SetSubtitles { subtitle, offset } => {
+                self.announce_subtitles(msg.from, subtitle, offset);
+            }
             UserMessage::State {
                 duration,
                 duration_time,
                 state,
                 state_time,
                 buffered,
-                time
+                time,
+                quality,
+                auto_quality,
+                audio,
             } => {
+                let duration = self.clamp_position(duration);
+                let quality = self.known_quality(quality);
                 self.update_participant_state(
                     msg.server_time,
                     msg.from,
@@ -383,7 +717,10 @@ impl Handler<ClientMessage> for Room {
                     state,
                     state_time,
                     buffered,
-                    time
+                    time,
+                    quality,
+                    auto_quality,
+                    audio,
                 );
             }
             _ => {}
@@ -393,6 +730,115 @@ impl Handler<ClientMessage> for Room {
     }
 }
 
+/// Pauses the room and tells everyone the server is restarting. Replies with what is needed to
+/// restore the room afterwards.
+#[derive(Message)]
+#[rtype(result = "RoomSnapshot")]
+pub struct Shutdown;
+
+impl Handler<Shutdown> for Room {
+    type Result = MessageResult<Shutdown>;
+
+    fn handle(&mut self, _msg: Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        let _room = self.span.clone().entered();
+
+        let duration = self.get_stream_position();
+        let now = ServerTime(self.clock.now());
+
+        info!(duration, participants = self.participants.len(), "pausing room for shutdown");
+
+        self.room_state = PlayState::Pause;
+        self.state_set = now.clone();
+        self.position_set = now;
+        self.set_stream_position(duration);
+        self.audit(None, AuditEventKind::Pause { position: duration });
+
+        for participant in &self.participants {
+            participant.send_message(ToSessionMessage::Restarting { duration });
+        }
+
+        MessageResult(RoomSnapshot {
+            name: self.name.clone(),
+            stream: self.current_stream.clone(),
+            duration,
+            cookies: self.cookies.clone(),
+            free_user_id: self.free_user_id,
+            admins: self.admins.clone(),
+            subtitle: self.subtitle.clone(),
+            subtitle_offset: self.subtitle_offset,
+        })
+    }
+}
+
+/// Tells the room the server disconnected a participant, so it ends up in the audit log.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Kicked {
+    pub user_id: UserId,
+    pub reason: String,
+}
+
+impl Handler<Kicked> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: Kicked, _ctx: &mut Self::Context) -> Self::Result {
+        let _room = self.span.clone().entered();
+        warn!(user = msg.user_id.0, reason = %msg.reason, "participant kicked");
+
+        self.audit(Some(msg.user_id), AuditEventKind::Kick { reason: msg.reason });
+    }
+}
+
+/// A message a participant's transport sent without the room, so it ends up in the recording.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct TransportSent {
+    pub user_id: UserId,
+    pub message: ToSessionMessage,
+    pub time: Time,
+}
+
+impl Handler<TransportSent> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: TransportSent, _ctx: &mut Self::Context) -> Self::Result {
+        if let Some(recorder) = &self.recorder {
+            recorder.transport(msg.user_id, &msg.message, msg.time);
+        }
+    }
+}
+
+/// Marks the participant with this cookie as an admin of the room.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct GrantAdmin(pub String);
+
+impl Handler<GrantAdmin> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: GrantAdmin, _ctx: &mut Self::Context) -> Self::Result {
+        self.admins.insert(msg.0);
+    }
+}
+
+/// Hands out the audit log to the participant with this cookie, if they may read it. Reading
+/// is left to the caller, so the room doesn't wait on the disk.
+#[derive(Message)]
+#[rtype(result = "Result<AuditLog, AuditError>")]
+pub struct GetAuditLog(pub String);
+
+impl Handler<GetAuditLog> for Room {
+    type Result = Result<AuditLog, AuditError>;
+
+    fn handle(&mut self, msg: GetAuditLog, _ctx: &mut Self::Context) -> Self::Result {
+        if !self.is_host_or_admin(&msg.0) {
+            return Err(AuditError::Forbidden);
+        }
+
+        self.audit.clone().ok_or(AuditError::Disabled)
+    }
+}
+
 pub struct GetUserId(pub String);
 
 impl Message for GetUserId {
@@ -409,6 +855,10 @@ impl Handler<GetUserId> for Room {
             let uid = UserId(self.free_user_id);
             self.free_user_id += 1;
 
+            if let Some(recorder) = &self.recorder {
+                recorder.identify(&msg.0, uid);
+            }
+
             self.cookies.insert(msg.0, uid);
 
             Some(uid)
@@ -446,6 +896,16 @@ impl TimeMapping {
     fn time_since(&self, time: ServerTime) -> chrono::Duration {
         *time - *self.server_time
     }
+
+    /// Time between sending the ping and receiving the reply.
+    fn rtt(&self) -> chrono::Duration {
+        *self.server_time - *self.requested_time
+    }
+
+    /// Difference between the server's and the client's clock, including the reply's trip time.
+    fn offset(&self) -> chrono::Duration {
+        *self.server_time - *self.client_time
+    }
 }
 
 impl fmt::Debug for TimeMapping {
@@ -455,16 +915,14 @@ impl fmt::Debug for TimeMapping {
     }
 }
 
-async fn participant_ping_loop(user_id: UserId, room: Addr<Room>) {
-    use tokio::time::delay_for;
-
+async fn participant_ping_loop(user_id: UserId, room: Addr<Room>, clock: SharedClock) {
     let mut ping_num = 0;
 
     loop {
         room.send(SendPing(user_id, ping_num)).await.unwrap();
         ping_num += 1;
 
-        delay_for(Duration::from_millis(5000)).await;
+        clock.sleep(Duration::from_millis(5000)).await;
     }
 }
 
@@ -474,6 +932,7 @@ pub struct Participant {
     name: String,
     avatar: BadgeId,
     badges: Vec<BadgeId>,
+    capabilities: Vec<String>,
     cookie: String,
 
     duration: f32,
@@ -483,13 +942,23 @@ pub struct Participant {
 
     buffered: f32,
 
+    quality: Option<u32>,
+    auto_quality: bool,
+    /// States in a row the participant's buffer ran low in.
+    low_buffer_states: u32,
+    /// The last rendition suggested, so the participant isn't nagged about it again.
+    suggested_quality: Option<u32>,
+    audio: Option<String>,
+
     time: Option<TimingInfo>,
     mapping: Option<TimeMapping>,
 
-    transport: Addr<WebsocketTransport>,
+    transport: Recipient<ToSessionMessage>,
 
     last_ping: Option<ServerTime>,
 
+    clock: SharedClock,
+    recorder: Option<Rc<Recorder>>,
     stop_source: StopSource,
 }
 
@@ -498,23 +967,29 @@ impl Participant {
         name: String,
         avatar: BadgeId,
         badges: Vec<BadgeId>,
+        capabilities: Vec<String>,
         cookie: String,
         user_id: UserId,
         room: Addr<Room>,
-        transport: Addr<WebsocketTransport>,
+        transport: Recipient<ToSessionMessage>,
+        clock: SharedClock,
+        recorder: Option<Rc<Recorder>>,
         created: Time
     ) -> Self {
         let created = ClientTime(convert_time(created));
         let stop_source = StopSource::new();
         let stop_token = stop_source.stop_token();
 
-        tokio::spawn(stop_token.stop_future(participant_ping_loop(user_id, room)));
+        // Spawned from within the room's message span, so the loop logs under the room.
+        let ping_loop = participant_ping_loop(user_id, room, clock.clone()).in_current_span();
+        tokio::spawn(stop_token.stop_future(ping_loop));
 
         Self {
             user_id,
             name,
             avatar,
             badges,
+            capabilities,
             cookie,
 
             duration: 0f32,
@@ -523,12 +998,20 @@ impl Participant {
             state_time: created.clone(),
             buffered: 0f32,
 
+            quality: None,
+            auto_quality: false,
+            low_buffer_states: 0,
+            suggested_quality: None,
+            audio: None,
+
             time: None,
             mapping: None,
 
             transport,
 
             last_ping: None,
+            clock,
+            recorder,
             stop_source,
         }
     }
@@ -544,10 +1027,18 @@ impl Participant {
         message
     }
 
+    fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
     fn send_message(&self, message: ToSessionMessage) {
-        trace!("{:?} -> {:?}", self.user_id, message);
+        trace!(user = self.user_id.0, ?message, "sending");
+
+        if let Some(recorder) = &self.recorder {
+            recorder.outgoing(self.user_id, &message, Time(self.clock.now().timestamp_millis()));
+        }
 
-        self.transport.do_send(message);
+        let _ = self.transport.do_send(message);
     }
 
     fn get_playing_time(&self, at_time: ServerTime) -> Option<f32> {
@@ -564,9 +1055,9 @@ impl Participant {
     }
 
     fn send_ping(&mut self, ping_id: u32) {
-        self.last_ping = Some(ServerTime(Utc::now()));
+        self.last_ping = Some(ServerTime(self.clock.now()));
 
-        self.transport.do_send(ToSessionMessage::Ping);
+        self.send_message(ToSessionMessage::Ping);
     }
 
     fn receive_state(
@@ -578,7 +1069,10 @@ impl Participant {
         state_time: Time,
         buffered: f32,
         time: Time,
-    ) {
+        quality: Option<u32>,
+        auto_quality: bool,
+        audio: Option<String>,
+    ) -> Option<&TimeMapping> {
         self.duration = duration;
         self.duration_time = ClientTime(convert_time(duration_time));
 
@@ -587,16 +1081,64 @@ impl Participant {
 
         self.buffered = buffered;
 
-        if let Some(ping_time) = &self.last_ping {
-            let client_time = ClientTime(convert_time(time));
+        self.quality = quality;
+        self.auto_quality = auto_quality;
+        self.audio = audio;
+
+        // Only the first state after a ping answers it, the ones sent in between on their own
+        // say nothing about the round trip.
+        let ping_time = match self.last_ping.take() {
+            Some(ping_time) => ping_time,
+            None => {
+                trace!(user = self.user_id.0, "state without a ping to answer");
+                return None;
+            }
+        };
 
-            let mapping = TimeMapping::new(ping_time.clone(), server_time, client_time);
+        let client_time = ClientTime(convert_time(time));
+        let mapping = TimeMapping::new(ping_time, server_time, client_time);
 
-            debug!("Received participant mapping: {:?}", mapping);
+        debug!(user = self.user_id.0, ?mapping, "received participant mapping");
 
-            self.mapping = Some(mapping);
-        } else {
-            warn!("No corresponding ping time for received pong!");
+        if self.has_capability(capabilities::TIMING) {
+            self.send_message(ToSessionMessage::Timing {
+                rtt: to_millis(mapping.rtt()),
+                offset: to_millis(mapping.offset()),
+            });
+        }
+
+        self.mapping = Some(mapping);
+        self.mapping.as_ref()
+    }
+
+    /// Suggests the next lower rendition once the buffer ran low for a few states in a row.
+    /// Players picking their rendition on their own are left alone.
+    fn check_buffer(&mut self, streams: &[Stream]) {
+        let quality = match self.quality {
+            Some(quality) if !self.auto_quality && self.state == PlayState::Play && self.buffered < LOW_BUFFER => quality,
+            _ => {
+                self.low_buffer_states = 0;
+                return;
+            }
+        };
+
+        self.low_buffer_states += 1;
+
+        if self.low_buffer_states < LOW_BUFFER_STATES || !self.has_capability(capabilities::QUALITY) {
+            return;
+        }
+
+        self.low_buffer_states = 0;
+
+        let lower = streams.iter().map(|s| s.quality).filter(|&q| q > 0 && q < quality).max();
+
+        if let Some(lower) = lower {
+            if self.suggested_quality != Some(lower) {
+                debug!(user = self.user_id.0, quality, lower, "buffer running low, suggesting lower quality");
+
+                self.suggested_quality = Some(lower);
+                self.send_message(ToSessionMessage::SuggestQuality { quality: lower });
+            }
         }
     }
 }
@@ -605,6 +1147,10 @@ fn to_seconds(duration: chrono::Duration) -> f32 {
     (duration.num_milliseconds() as f64 / 1000.0) as f32
 }
 
+fn to_millis(duration: chrono::Duration) -> f32 {
+    duration.num_microseconds().map(|us| us as f32 / 1000.0).unwrap_or(f32::MAX)
+}
+
 fn convert_time(time: Time) -> DateTime<Utc> {
     let secs = time.0 / 1000;
     let nano_secs = (time.0 % 1_000) * 100_000_0;
@@ -1,8 +1,9 @@
-use actix::{Actor, Addr, AsyncContext, Context, Handler, Message, StreamHandler};
+use actix::{Actor, Addr, AsyncContext, Context, Handler, Message, ResponseFuture, StreamHandler};
 
 use std::collections::HashMap;
 
-use crate::actors::Room;
+use crate::actors::{Room, RoomSnapshot, Shutdown};
+use crate::metrics;
 
 // TODO: persist rooms in database
 #[derive(Default)]
@@ -39,6 +40,7 @@ impl Handler<RegisterRoom> for RoomRepository {
 
     fn handle(&mut self, msg: RegisterRoom, _ctx: &mut Self::Context) -> Self::Result {
         self.rooms.insert(msg.0, msg.1);
+        metrics::ROOMS.set(self.rooms.len() as i64);
     }
 }
 
@@ -53,5 +55,31 @@ impl Handler<RemoveRoom> for RoomRepository {
 
     fn handle(&mut self, msg: RemoveRoom, _ctx: &mut Self::Context) -> Self::Result {
         self.rooms.remove(&msg.0);
+        metrics::ROOMS.set(self.rooms.len() as i64);
+        let _ = metrics::PARTICIPANTS.remove_label_values(&[&msg.0]);
+    }
+}
+
+/// Pauses every room and tells its participants the server is restarting. Returns the state of
+/// every room so it can be restored after the restart.
+pub struct ShutdownRooms;
+
+impl Message for ShutdownRooms {
+    type Result = Vec<RoomSnapshot>;
+}
+
+impl Handler<ShutdownRooms> for RoomRepository {
+    type Result = ResponseFuture<Vec<RoomSnapshot>>;
+
+    fn handle(&mut self, _msg: ShutdownRooms, _ctx: &mut Self::Context) -> Self::Result {
+        let rooms = self.rooms.values().cloned().collect::<Vec<_>>();
+
+        Box::pin(async move {
+            futures::future::join_all(rooms.iter().map(|room| room.send(Shutdown)))
+                .await
+                .into_iter()
+                .filter_map(Result::ok)
+                .collect()
+        })
     }
 }
@@ -0,0 +1,278 @@
+use actix::{Actor, AsyncContext, Context, Handler, Message, MessageResult};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::actors::{MediaSource, MediaStream};
+use crate::library::MediaLibrary;
+use crate::transcode::{self, Stage, TranscodeConfig, TranscodeError};
+use crate::trickplay;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct JobId(pub u64);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum JobState {
+    Queued,
+    /// `progress` goes from 0 to 1 in every stage.
+    Running { stage: Stage, progress: f32 },
+    /// The media was added to the library under `slug`.
+    Done { slug: String },
+    Failed { error: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub id: JobId,
+    pub name: String,
+    pub state: JobState,
+}
+
+/// What a job does.
+enum Work {
+    /// Transcodes the file into new media.
+    Transcode(PathBuf),
+    /// Takes the thumbnails of the media in the library under the slug.
+    Thumbnails(String),
+}
+
+/// Runs transcoding jobs one after the other, since a single ffmpeg keeps every core busy
+/// already. Finished media is added to the library.
+pub struct Transcoder {
+    config: TranscodeConfig,
+    library: MediaLibrary,
+
+    jobs: BTreeMap<JobId, Job>,
+    /// Jobs waiting for their turn.
+    queue: VecDeque<(JobId, Work)>,
+    running: bool,
+    next_id: u64,
+}
+
+impl Transcoder {
+    pub fn new(config: TranscodeConfig, library: MediaLibrary) -> Self {
+        Self {
+            config,
+            library,
+            jobs: BTreeMap::new(),
+            queue: VecDeque::new(),
+            running: false,
+            next_id: 1,
+        }
+    }
+
+    fn set_state(&mut self, id: JobId, state: JobState) {
+        if let Some(job) = self.jobs.get_mut(&id) {
+            job.state = state;
+        }
+    }
+
+    fn add_job(&mut self, name: String, work: Work, ctx: &mut Context<Self>) -> JobId {
+        let id = JobId(self.next_id);
+        self.next_id += 1;
+
+        self.jobs.insert(id, Job { id, name, state: JobState::Queued });
+        self.queue.push_back((id, work));
+        self.start_next(ctx);
+
+        id
+    }
+
+    fn start_next(&mut self, ctx: &mut Context<Self>) {
+        if self.running {
+            return;
+        }
+
+        let (id, work) = match self.queue.pop_front() {
+            Some(job) => job,
+            None => return,
+        };
+
+        self.running = true;
+
+        match work {
+            Work::Transcode(input) => self.start_transcode(id, input, ctx),
+            Work::Thumbnails(slug) => self.start_thumbnails(id, slug, ctx),
+        }
+    }
+
+    fn start_transcode(&mut self, id: JobId, input: PathBuf, ctx: &mut Context<Self>) {
+        let name = self.jobs[&id].name.clone();
+        let slug = transcode::unused_slug(&self.config.media_dir, &name);
+        let dir = self.config.media_dir.join(&slug);
+        let config = self.config.clone();
+        let addr = ctx.address();
+
+        self.set_state(id, JobState::Running { stage: Stage::Transcoding, progress: 0.0 });
+
+        let job = async move {
+            let report = addr.clone();
+            let result = transcode::transcode(&config, &input, &dir, |stage, progress| {
+                report.do_send(JobProgress(id, stage, progress));
+            }).await;
+
+            if result.is_err() {
+                let _ = fs::remove_dir_all(&dir);
+            }
+            if let Err(e) = fs::remove_file(&input) {
+                warn!(path = %input.display(), error = ?e, "failed to remove transcoded upload");
+            }
+
+            let result = result.map(|(probe, streams)| transcode::media_stream(&slug, &name, &probe, streams));
+            addr.do_send(JobFinished { id, result });
+        };
+
+        actix_rt::spawn(job.instrument(info_span!("transcode", job = id.0)));
+    }
+
+    fn start_thumbnails(&mut self, id: JobId, slug: String, ctx: &mut Context<Self>) {
+        let stream = self.library.find(&slug);
+        let dir = self.config.media_dir.join(&slug);
+        let config = self.config.clone();
+        let addr = ctx.address();
+
+        self.set_state(id, JobState::Running { stage: Stage::Thumbnails, progress: 0.0 });
+
+        let job = async move {
+            let result = match stream {
+                Some(stream) => {
+                    let report = addr.clone();
+                    trickplay::backfill(&config, &dir, &stream.streams, |progress| {
+                        report.do_send(JobProgress(id, Stage::Thumbnails, progress));
+                    }).await.map(|()| stream)
+                }
+                None => Err(TranscodeError::Probe(format!("{} isn't in the library anymore", slug))),
+            };
+
+            addr.do_send(JobFinished { id, result });
+        };
+
+        actix_rt::spawn(job.instrument(info_span!("thumbnails", job = id.0)));
+    }
+}
+
+impl Actor for Transcoder {
+    type Context = Context<Self>;
+}
+
+/// Queues `input` for transcoding. The file is removed once the job is done.
+#[derive(Message)]
+#[rtype(result = "Job")]
+pub struct Transcode {
+    pub input: PathBuf,
+    /// What the media is called in the library.
+    pub name: String,
+}
+
+impl Handler<Transcode> for Transcoder {
+    type Result = MessageResult<Transcode>;
+
+    fn handle(&mut self, msg: Transcode, ctx: &mut Self::Context) -> Self::Result {
+        info!(name = %msg.name, "queueing transcoding job");
+
+        let id = self.add_job(msg.name, Work::Transcode(msg.input), ctx);
+
+        MessageResult(self.jobs[&id].clone())
+    }
+}
+
+/// Queues a job taking the thumbnails of every local media in the library that has none.
+/// Replies with the jobs.
+#[derive(Message)]
+#[rtype(result = "Vec<Job>")]
+pub struct BackfillThumbnails;
+
+impl Handler<BackfillThumbnails> for Transcoder {
+    type Result = MessageResult<BackfillThumbnails>;
+
+    fn handle(&mut self, _msg: BackfillThumbnails, ctx: &mut Self::Context) -> Self::Result {
+        let missing = self
+            .library
+            .streams()
+            .into_iter()
+            .filter(|s| s.thumbnails.is_none() && s.source == MediaSource::Local)
+            .collect::<Vec<_>>();
+
+        let ids = missing
+            .into_iter()
+            .map(|stream| {
+                info!(slug = %stream.slug, "queueing thumbnails");
+                self.add_job(stream.name, Work::Thumbnails(stream.slug), ctx)
+            })
+            .collect::<Vec<_>>();
+
+        MessageResult(ids.iter().map(|id| self.jobs[id].clone()).collect())
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Option<Job>")]
+pub struct GetJob(pub JobId);
+
+impl Handler<GetJob> for Transcoder {
+    type Result = Option<Job>;
+
+    fn handle(&mut self, msg: GetJob, _ctx: &mut Self::Context) -> Self::Result {
+        self.jobs.get(&msg.0).cloned()
+    }
+}
+
+/// Every job since the server started, oldest first.
+#[derive(Message)]
+#[rtype(result = "Vec<Job>")]
+pub struct ListJobs;
+
+impl Handler<ListJobs> for Transcoder {
+    type Result = MessageResult<ListJobs>;
+
+    fn handle(&mut self, _msg: ListJobs, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.jobs.values().cloned().collect())
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct JobProgress(JobId, Stage, f32);
+
+impl Handler<JobProgress> for Transcoder {
+    type Result = ();
+
+    fn handle(&mut self, msg: JobProgress, _ctx: &mut Self::Context) -> Self::Result {
+        self.set_state(msg.0, JobState::Running { stage: msg.1, progress: msg.2 });
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+struct JobFinished {
+    id: JobId,
+    result: Result<MediaStream, TranscodeError>,
+}
+
+impl Handler<JobFinished> for Transcoder {
+    type Result = ();
+
+    fn handle(&mut self, msg: JobFinished, ctx: &mut Self::Context) -> Self::Result {
+        let state = match msg.result.map(|stream| (stream.slug.clone(), self.library.add(stream))) {
+            Ok((slug, Ok(()))) => {
+                info!(job = msg.id.0, %slug, "transcoding job done");
+                JobState::Done { slug }
+            }
+            Ok((slug, Err(e))) => {
+                error!(job = msg.id.0, %slug, error = ?e, "failed to add media to library");
+                JobState::Failed { error: format!("failed to add media to library: {}", e) }
+            }
+            Err(e) => {
+                warn!(job = msg.id.0, error = %e, "transcoding job failed");
+                JobState::Failed { error: e.to_string() }
+            }
+        };
+
+        self.set_state(msg.id, state);
+        self.running = false;
+        self.start_next(ctx);
+    }
+}
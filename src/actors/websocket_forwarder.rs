@@ -0,0 +1,147 @@
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
+use actix_web::http::header;
+use actix_web_actors::ws;
+use futures::{channel::mpsc, stream::LocalBoxStream, SinkExt, StreamExt};
+
+use tracing::{debug, info, info_span, warn, Span};
+
+use crate::cluster::NodeInfo;
+use crate::codec::Encoding;
+
+/// Stands in for a `WebsocketTransport` on a node that doesn't own the room. Frames are passed
+/// through unchanged between the participant and a websocket session on the owning node, the
+/// owner's pings and the participant's pongs included, so the owner's heartbeat notices
+/// participants that went quiet.
+pub struct WebsocketForwarder {
+    /// Frames for the owning node, written by a separate task since the `awc` sink isn't an
+    /// actor.
+    upstream: mpsc::UnboundedSender<ws::Message>,
+    /// Frames from the owning node, handed to the context once the actor is started.
+    incoming: Option<LocalBoxStream<'static, Result<ws::Frame, ws::ProtocolError>>>,
+
+    span: Span,
+}
+
+impl WebsocketForwarder {
+    /// Opens a websocket session on `owner`, passing on the participant's cookies so it is
+    /// identified the same way it would be on the owner.
+    pub async fn connect(
+        client: &awc::Client,
+        owner: &NodeInfo,
+        room_code: &str,
+        cookie: Option<&str>,
+        encoding: Encoding,
+        max_frame_size: usize,
+    ) -> Result<Self, awc::error::WsClientError> {
+        let url = format!("{}/websocket/{}", owner.address.replacen("http", "ws", 1), room_code);
+
+        let mut request = client
+            .ws(url)
+            .max_frame_size(max_frame_size)
+            .protocols(&[encoding.protocol()]);
+
+        if let Some(cookie) = cookie {
+            request = request.header(header::COOKIE, cookie);
+        }
+
+        let (_response, framed) = request.connect().await?;
+        let (mut sink, stream) = framed.split();
+        let (upstream, mut outgoing) = mpsc::unbounded::<ws::Message>();
+
+        actix_rt::spawn(async move {
+            while let Some(message) = outgoing.next().await {
+                if let Err(e) = sink.send(message).await {
+                    warn!(error = ?e, "failed to forward frame to owner");
+                    break;
+                }
+            }
+
+            let _ = sink.close().await;
+        });
+
+        Ok(Self {
+            upstream,
+            incoming: Some(stream.boxed_local()),
+            span: info_span!("forwarded_session", room = %room_code, owner = %owner.id),
+        })
+    }
+
+    fn forward(&self, message: ws::Message, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.upstream.unbounded_send(message).is_err() {
+            ctx.stop();
+        }
+    }
+}
+
+impl Actor for WebsocketForwarder {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let _session = self.span.enter();
+        info!("forwarding session to owner");
+
+        if let Some(incoming) = self.incoming.take() {
+            ctx.add_stream(incoming);
+        }
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        let _session = self.span.enter();
+        debug!("forwarded session ended");
+
+        // Ends the writer task, which closes the session on the owner.
+        self.upstream.close_channel();
+    }
+}
+
+/// Frames from the participant.
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketForwarder {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let _session = self.span.clone().entered();
+
+        match msg {
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Nop) => {}
+            Ok(ws::Message::Close(reason)) => {
+                self.forward(ws::Message::Close(reason.clone()), ctx);
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(message) => self.forward(message, ctx),
+            Err(e) => {
+                warn!(error = ?e, "websocket protocol error");
+                ctx.stop();
+            }
+        }
+    }
+}
+
+/// Frames from the owning node.
+impl StreamHandler<Result<ws::Frame, ws::ProtocolError>> for WebsocketForwarder {
+    fn handle(&mut self, msg: Result<ws::Frame, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let _session = self.span.clone().entered();
+
+        match msg {
+            Ok(ws::Frame::Text(bytes)) => match String::from_utf8(bytes.to_vec()) {
+                Ok(txt) => ctx.text(txt),
+                Err(e) => warn!(error = ?e, "owner sent invalid utf-8"),
+            },
+            Ok(ws::Frame::Binary(bytes)) => ctx.binary(bytes),
+            Ok(ws::Frame::Continuation(item)) => ctx.write_raw(ws::Message::Continuation(item)),
+            Ok(ws::Frame::Ping(msg)) => ctx.ping(&msg),
+            Ok(ws::Frame::Pong(_)) => {}
+            Ok(ws::Frame::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Err(e) => {
+                warn!(error = ?e, "websocket protocol error from owner");
+                ctx.stop();
+            }
+        }
+    }
+
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        ctx.stop();
+    }
+}
@@ -1,34 +1,367 @@
-use actix::{Handler, Message, Actor, Addr, AsyncContext, ActorContext, StreamHandler};
+use actix::{Handler, Message, Actor, ActorFuture, Addr, AsyncContext, ActorContext, StreamHandler, WrapFuture};
 use actix_web_actors::ws;
 
 use stop_token::StopSource;
 
-use log::*;
+use tracing::{debug, error, info, info_span, trace, warn, Span};
 
-use crate::actors::{GetUserId, Room};
+use crate::actors::{GetUserId, Kicked, Room, TransportSent};
+use crate::codec::{Encoding, Frame};
+use crate::clock::SharedClock;
+use crate::rate_limit::{RateLimit, TokenBucket};
+use crate::validation::{self, ValidationLimits};
+use crate::metrics;
 
 use chrono::{DateTime, Utc, TimeZone};
 
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
 
-use crate::protocol::{ServerTime, ClientTime, UserId,UserMessage,ClientMessage,ToSessionMessage};
+use crate::protocol::{
+    capabilities,
+    ServerTime, ClientTime, Time, UserId, UserMessage, ClientMessage, ToSessionMessage, RejectReason,
+    RateLimitPenalty, ProtocolError, PROTOCOL_VERSION, MIN_PROTOCOL_VERSION,
+};
+
+/// How fast a participant may send each kind of message.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    pub seek: RateLimit,
+    pub set_state: RateLimit,
+    pub state: RateLimit,
+    /// Room settings like the subtitles.
+    pub settings: RateLimit,
+    /// Everything else, mostly handshakes.
+    pub other: RateLimit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            seek: RateLimit::new(5.0, 1.0),
+            set_state: RateLimit::new(5.0, 2.0),
+            // Clients answer every ping and report unexpected player changes on top of that.
+            state: RateLimit::new(10.0, 5.0),
+            // Nudging the subtitle offset takes a few tries.
+            settings: RateLimit::new(10.0, 1.0),
+            other: RateLimit::new(3.0, 0.1),
+        }
+    }
+}
+
+impl RateLimits {
+    fn for_message(&self, message: &UserMessage) -> RateLimit {
+        match message {
+            UserMessage::Seek { .. } => self.seek,
+            UserMessage::SetState { .. } => self.set_state,
+            UserMessage::State { .. } => self.state,
+            UserMessage::SetSubtitles { .. } => self.settings,
+            _ => self.other,
+        }
+    }
+}
+
+/// Settings shared by all websocket transports.
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+    /// How often a websocket ping is sent to the client.
+    pub heartbeat_interval: Duration,
+    /// How long the client may stay silent before the connection is considered dead.
+    pub client_timeout: Duration,
+
+    pub rate_limits: RateLimits,
+    /// What happens to participants going over their rate limits.
+    pub rate_limit_penalty: RateLimitPenalty,
+    /// Seeks arriving closer together than this are merged, only the last one is passed on.
+    pub seek_debounce: Duration,
+
+    pub validation: ValidationLimits,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval: Duration::from_secs(5),
+            client_timeout: Duration::from_secs(15),
+            rate_limits: RateLimits::default(),
+            rate_limit_penalty: RateLimitPenalty::Ignore,
+            seek_debounce: Duration::from_millis(250),
+            validation: ValidationLimits::default(),
+        }
+    }
+}
+
+impl TransportConfig {
+    /// The default configuration, overridden by any `TMTUSYNC_*` environment variables set.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+
+        if let Some(secs) = env_secs("TMTUSYNC_HEARTBEAT_INTERVAL") {
+            config.heartbeat_interval = secs;
+        }
+        if let Some(secs) = env_secs("TMTUSYNC_CLIENT_TIMEOUT") {
+            config.client_timeout = secs;
+        }
+        if let Some(secs) = env_secs("TMTUSYNC_SEEK_DEBOUNCE") {
+            config.seek_debounce = secs;
+        }
+        if let Ok(penalty) = std::env::var("TMTUSYNC_RATE_LIMIT_PENALTY") {
+            match penalty.as_str() {
+                "warn" => config.rate_limit_penalty = RateLimitPenalty::Warn,
+                "ignore" => config.rate_limit_penalty = RateLimitPenalty::Ignore,
+                "disconnect" => config.rate_limit_penalty = RateLimitPenalty::Disconnect,
+                _ => warn!(
+                    "Ignoring invalid TMTUSYNC_RATE_LIMIT_PENALTY={:?}, expected warn, ignore or disconnect",
+                    penalty
+                ),
+            }
+        }
+
+        config
+    }
+}
+
+fn env_secs(name: &str) -> Option<Duration> {
+    let value = std::env::var(name).ok()?;
+
+    match value.parse::<f32>() {
+        Ok(secs) if secs > 0.0 => Some(Duration::from_secs_f32(secs)),
+        _ => {
+            warn!("Ignoring invalid {}={:?}, expected seconds", name, value);
+            None
+        }
+    }
+}
 
 pub struct WebsocketTransport {
     room: Addr<Room>,
     user_id: UserId,
     cookie: String,
     stop_source: StopSource,
+    encoding: Encoding,
+    clock: SharedClock,
+    config: TransportConfig,
+
+    /// When we last heard anything from the client. This measures the connection itself, so it
+    /// uses the real monotonic clock rather than `clock`.
+    last_heartbeat: Instant,
+
+    /// One token bucket per kind of message.
+    buckets: HashMap<&'static str, TokenBucket>,
+    /// Kinds of messages the participant has been told they are sending too fast.
+    limited: HashSet<&'static str>,
+
+    /// When the last seek was passed on to the room.
+    last_seek: Option<DateTime<Utc>>,
+    /// The latest seek held back by the debounce, sent once the debounce window is over.
+    pending_seek: Option<(ServerTime, UserMessage)>,
+
+    /// Features agreed on during the handshake, `None` until the client has said `Hello`.
+    capabilities: Option<Vec<String>>,
+
+    /// Span of the session, carries the room code and user id.
+    span: Span,
 }
 
 impl WebsocketTransport {
-    pub async fn new(cookie: String, user_id: UserId, room: Addr<Room>) -> Self {
+    pub async fn new(
+        cookie: String,
+        user_id: UserId,
+        room: Addr<Room>,
+        room_code: String,
+        encoding: Encoding,
+        clock: SharedClock,
+        config: TransportConfig,
+    ) -> Self {
         let stop_source = StopSource::new();
+        let span = info_span!("session", room = %room_code, user = user_id.0, ?encoding);
 
         Self {
             room,
             user_id,
             cookie,
             stop_source,
+            encoding,
+            clock,
+            config,
+            last_heartbeat: Instant::now(),
+            buckets: HashMap::new(),
+            limited: HashSet::new(),
+            last_seek: None,
+            pending_seek: None,
+            capabilities: None,
+            span,
+        }
+    }
+
+    fn start_heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.run_interval(self.config.heartbeat_interval, |act, ctx| {
+            let _session = act.span.clone().entered();
+
+            if act.last_heartbeat.elapsed() > act.config.client_timeout {
+                warn!(elapsed = ?act.last_heartbeat.elapsed(), "timed out, disconnecting");
+
+                if act.capabilities.is_some() {
+                    act.room.do_send(Kicked { user_id: act.user_id, reason: "timed out".into() });
+                }
+                ctx.stop();
+                return;
+            }
+
+            ctx.ping(b"");
+        });
+    }
+
+    /// Takes a token for the message. Returns `false` if the message should be dropped.
+    fn check_rate_limit(
+        &mut self,
+        message: &UserMessage,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> bool {
+        let now = self.clock.now();
+        let kind = message.kind();
+        let limit = self.config.rate_limits.for_message(message);
+
+        let bucket = self.buckets
+            .entry(kind)
+            .or_insert_with(|| TokenBucket::new(limit, now));
+
+        if bucket.try_take(now) {
+            self.limited.remove(kind);
+            return true;
+        }
+
+        let penalty = self.config.rate_limit_penalty;
+
+        // Only tell the participant once per burst instead of answering every dropped message.
+        if self.limited.insert(kind) {
+            warn!(kind, ?penalty, "rate limited");
+
+            self.send(&ToSessionMessage::RateLimited {
+                kind: kind.to_string(),
+                penalty,
+            }, ctx);
+        }
+
+        match penalty {
+            RateLimitPenalty::Warn => true,
+            RateLimitPenalty::Ignore => false,
+            RateLimitPenalty::Disconnect => {
+                let reason = format!("too many {} messages", kind);
+
+                self.room.do_send(Kicked { user_id: self.user_id, reason: reason.clone() });
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some(reason),
+                }));
+                ctx.stop();
+                false
+            }
+        }
+    }
+
+    /// Passes seeks on at most once per debounce window. Seeks arriving within the window replace
+    /// each other and only the last one is sent when the window is over.
+    fn debounce_seek(
+        &mut self,
+        server_time: ServerTime,
+        message: UserMessage,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        let debounce = self.config.seek_debounce;
+        let now = self.clock.now();
+        let since_last = self.last_seek
+            .map(|at| (now - at).to_std().unwrap_or_default())
+            .unwrap_or(debounce);
+
+        if since_last >= debounce && self.pending_seek.is_none() {
+            self.last_seek = Some(now);
+            self.forward(server_time, message, ctx);
+            return;
+        }
+
+        trace!("seek debounced");
+
+        if self.pending_seek.replace((server_time, message)).is_none() {
+            let wait = self.clock.sleep(debounce.checked_sub(since_last).unwrap_or_default());
+
+            ctx.spawn(wait.into_actor(self).map(|_, act, ctx| {
+                let _session = act.span.clone().entered();
+
+                if let Some((server_time, message)) = act.pending_seek.take() {
+                    act.last_seek = Some(act.clock.now());
+                    act.forward(server_time, message, ctx);
+                }
+            }));
+        }
+    }
+
+    /// Sends a message of the transport's own, the room's messages go through the handler.
+    fn send(&self, message: &ToSessionMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        metrics::MESSAGES_SENT.with_label_values(&[message.kind()]).inc();
+        self.room.do_send(TransportSent {
+            user_id: self.user_id,
+            message: message.clone(),
+            time: Time(self.clock.now().timestamp_millis()),
+        });
+
+        match self.encoding.encode(message) {
+            Ok(Frame::Text(txt)) => ctx.text(txt),
+            Ok(Frame::Binary(bytes)) => ctx.binary(bytes),
+            Err(e) => error!(kind = message.kind(), error = ?e, "failed to serialize message"),
+        }
+    }
+
+    fn reject(&mut self, reason: RejectReason, ctx: &mut ws::WebsocketContext<Self>) {
+        warn!(?reason, "rejecting participant");
+
+        self.send(&ToSessionMessage::Rejected {
+            reason,
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+        }, ctx);
+
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Protocol,
+            description: None,
+        }));
+        ctx.stop();
+    }
+
+    /// Checks that the message is valid at this point of the handshake. Returns `false` if the
+    /// client was rejected and the message shouldn't be passed on to the room.
+    fn check_handshake(
+        &mut self,
+        message: &UserMessage,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> bool {
+        match (message, self.capabilities.is_some()) {
+            (UserMessage::Hello { version, features, .. }, false) => {
+                if *version < MIN_PROTOCOL_VERSION || *version > PROTOCOL_VERSION {
+                    self.reject(RejectReason::UnsupportedVersion, ctx);
+                    return false;
+                }
+
+                let capabilities = capabilities::negotiate(features);
+                debug!(version, ?capabilities, "handshake complete");
+
+                self.send(&ToSessionMessage::Welcome {
+                    version: PROTOCOL_VERSION,
+                    capabilities: capabilities.clone(),
+                }, ctx);
+                self.capabilities = Some(capabilities);
+
+                true
+            }
+            (UserMessage::Hello { .. }, true) => {
+                self.reject(RejectReason::DuplicateHello, ctx);
+                false
+            }
+            (_, false) => {
+                self.reject(RejectReason::HandshakeRequired, ctx);
+                false
+            }
+            (_, true) => true,
         }
     }
 
@@ -37,34 +370,120 @@ impl WebsocketTransport {
         server_time: ServerTime,
         message: UserMessage,
         ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        if let Err(e) = validation::validate(&message, *server_time, &self.config.validation) {
+            warn!(kind = message.kind(), error = ?e, "invalid message");
+
+            self.send(&ToSessionMessage::Error(e), ctx);
+
+            // Without a valid hello there is nothing sensible to continue with.
+            if let UserMessage::Hello { .. } = message {
+                ctx.stop();
+            }
+
+            return;
+        }
+
+        if !self.check_handshake(&message, ctx) {
+            return;
+        }
+
+        if !self.check_rate_limit(&message, ctx) {
+            return;
+        }
+
+        // The room only gets to see the features both sides agreed on.
+        let message = match message {
+            UserMessage::Hello { name, avatar, time, version, .. } => UserMessage::Hello {
+                name,
+                avatar,
+                time,
+                version,
+                features: self.capabilities.clone().unwrap_or_default(),
+            },
+            message => message,
+        };
+
+        if let UserMessage::Seek { .. } = message {
+            self.debounce_seek(server_time, message, ctx);
+        } else {
+            self.forward(server_time, message, ctx);
+        }
+    }
+
+    fn forward(
+        &mut self,
+        server_time: ServerTime,
+        message: UserMessage,
+        ctx: &mut ws::WebsocketContext<Self>,
     ) {
         self.room.do_send(ClientMessage {
             from: self.user_id,
             cookie: self.cookie.clone(),
             message: message,
-            addr: ctx.address(),
+            addr: ctx.address().recipient(),
             server_time,
         });
     }
 
+    /// Refuses frames over the size limit. Returns `false` if the frame should be dropped.
+    fn check_frame_size(&mut self, size: usize, ctx: &mut ws::WebsocketContext<Self>) -> bool {
+        let max = self.config.validation.max_frame_size;
+
+        if size <= max {
+            return true;
+        }
+
+        warn!(size, max, "frame too large");
+        self.send(&ToSessionMessage::Error(ProtocolError::FrameTooLarge { max }), ctx);
+
+        false
+    }
+
     fn handle_websocket_text(&mut self, txt: String, ctx: &mut ws::WebsocketContext<Self>) {
-        trace!("{:?} <- {:?}", self.user_id, txt);
-        let now = Utc::now();
+        trace!(%txt, "received text frame");
+
+        if !self.check_frame_size(txt.len(), ctx) {
+            return;
+        }
+
+        let now = self.clock.now();
+
+        let message = self.encoding.decode_text::<UserMessage>(&txt);
+        self.handle_decoded(ServerTime(now), message, ctx);
+    }
 
-        match serde_json::from_str::<UserMessage>(&txt) {
+    fn handle_websocket_binary(&mut self, bytes: &[u8], ctx: &mut ws::WebsocketContext<Self>) {
+        trace!(size = bytes.len(), "received binary frame");
+
+        if !self.check_frame_size(bytes.len(), ctx) {
+            return;
+        }
+        let now = self.clock.now();
+
+        let message = self.encoding.decode_binary::<UserMessage>(bytes);
+        self.handle_decoded(ServerTime(now), message, ctx);
+    }
+
+    fn handle_decoded(
+        &mut self,
+        server_time: ServerTime,
+        message: anyhow::Result<UserMessage>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) {
+        match message {
             Ok(message) => {
-                trace!("{:?} <- {:?}", self.user_id, message);
+                trace!(?message, "received");
+                metrics::MESSAGES_RECEIVED.with_label_values(&[message.kind()]).inc();
 
-                self.handle_message(ServerTime(now), message, ctx);
+                self.handle_message(server_time, message, ctx);
             }
             Err(e) => {
-                warn!("Error parsing message from participant {:?}: {:?}", self.user_id, e);
-
-                let err_message =
-                    serde_json::to_string(&ToSessionMessage::Error(format!("{:?}", e)))
-                        .unwrap();
+                warn!(error = ?e, "failed to parse message");
 
-                ctx.text(err_message);
+                self.send(&ToSessionMessage::Error(ProtocolError::Malformed {
+                    details: e.to_string(),
+                }), ctx);
             }
         }
     }
@@ -73,13 +492,30 @@ impl WebsocketTransport {
 impl Actor for WebsocketTransport {
     type Context = ws::WebsocketContext<Self>;
 
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let _session = self.span.clone().entered();
+        info!("connected");
+        metrics::WEBSOCKET_CONNECTS.inc();
+
+        self.start_heartbeat(ctx);
+    }
+
     fn stopped(&mut self, ctx: &mut Self::Context) {
+        let _session = self.span.clone().entered();
+        info!("disconnected");
+        metrics::WEBSOCKET_DISCONNECTS.inc();
+
+        // Rejected clients never made it into the room.
+        if self.capabilities.is_none() {
+            return;
+        }
+
         self.room.do_send(ClientMessage {
             from: self.user_id,
             cookie: self.cookie.clone(),
             message: UserMessage::Goodbye,
-            addr: ctx.address(),
-            server_time: ServerTime(Utc::now()),
+            addr: ctx.address().recipient(),
+            server_time: ServerTime(self.clock.now()),
         });
     }
 }
@@ -88,9 +524,22 @@ impl Handler<ToSessionMessage> for WebsocketTransport {
     type Result = anyhow::Result<()>;
 
     fn handle(&mut self, msg: ToSessionMessage, ctx: &mut Self::Context) -> Self::Result {
-        let json = serde_json::to_string(&msg)?;
+        let _session = self.span.enter();
+        metrics::MESSAGES_SENT.with_label_values(&[msg.kind()]).inc();
 
-        ctx.text(json);
+        match self.encoding.encode(&msg)? {
+            Frame::Text(txt) => ctx.text(txt),
+            Frame::Binary(bytes) => ctx.binary(bytes),
+        }
+
+        // The room has been saved already, closing here lets the server drain quickly.
+        if let ToSessionMessage::Restarting { .. } = msg {
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Restart,
+                description: None,
+            }));
+            ctx.stop();
+        }
 
         Ok(())
     }
@@ -98,15 +547,39 @@ impl Handler<ToSessionMessage> for WebsocketTransport {
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketTransport {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let _session = self.span.clone().entered();
+
+        if msg.is_ok() {
+            self.last_heartbeat = Instant::now();
+        }
+
         match msg {
             Ok(ws::Message::Text(txt)) => {
                 self.handle_websocket_text(txt, ctx);
             },
+            Ok(ws::Message::Binary(bytes)) => {
+                self.handle_websocket_binary(&bytes, ctx);
+            },
+            Ok(ws::Message::Ping(msg)) => {
+                ctx.pong(&msg);
+            },
+            Ok(ws::Message::Pong(_)) => {},
             Ok(ws::Message::Close(reason)) => {
                 ctx.close(reason);
                 ctx.stop();
             },
-            _ => {}
+            Ok(ws::Message::Continuation(_)) | Ok(ws::Message::Nop) => {},
+            Err(ws::ProtocolError::Overflow) => {
+                let max = self.config.validation.max_frame_size;
+                warn!(max, "frame over the size limit");
+
+                self.send(&ToSessionMessage::Error(ProtocolError::FrameTooLarge { max }), ctx);
+                ctx.stop();
+            },
+            Err(e) => {
+                warn!(error = ?e, "websocket protocol error");
+                ctx.stop();
+            },
         }
     }
 }
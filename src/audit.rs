@@ -0,0 +1,193 @@
+//! A per-room log of everything that changed the room, kept on disk so it survives restarts.
+//!
+//! Every room appends to its own file of JSON lines, `{dir}/{room}.jsonl`. The log is read back
+//! as a whole when the host or an admin asks for it. Writes happen on a thread of their own, so
+//! a slow disk doesn't hold up the room.
+
+use actix_web::{error::BlockingError, web};
+use chrono::{TimeZone, Utc};
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::error;
+
+use crate::protocol::{Time, UserId};
+
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+#[derive(Error, Debug)]
+pub enum AuditError {
+    #[error("only the host and admins may read the audit log")]
+    Forbidden,
+    #[error("the room has no audit log")]
+    Disabled,
+    #[error("failed to read the audit log: {0}")]
+    Io(#[from] io::Error),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AuditEventKind {
+    Join,
+    Leave,
+    Play { position: f32 },
+    Pause { position: f32 },
+    Seek { from: f32, to: f32 },
+    StreamChange { slug: String, name: String },
+    /// The server disconnected the participant.
+    Kick { reason: String },
+    Subtitles { subtitle: Option<String>, offset: f32 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEvent {
+    pub time: Time,
+    /// The participant that caused the event, `None` for events caused by the server.
+    pub user: Option<UserId>,
+    pub name: Option<String>,
+    pub event: AuditEventKind,
+}
+
+enum Command {
+    Append(String),
+    /// Answered once everything sent before has been written.
+    Sync(oneshot::Sender<()>),
+}
+
+/// Where a room's audit log is stored. Clones write to the same file.
+#[derive(Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+    writer: mpsc::Sender<Command>,
+}
+
+impl AuditLog {
+    /// Opens the audit log of `room` in `dir`, creating both if needed.
+    pub fn open(dir: &Path, room: &str) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join(format!("{}.jsonl", room));
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        let (writer, commands) = mpsc::channel();
+        let writer_path = path.clone();
+        thread::Builder::new()
+            .name(format!("audit-{}", room))
+            .spawn(move || write_events(&writer_path, file, commands))?;
+
+        Ok(Self { path, writer })
+    }
+
+    /// Appends an event. Failing to write is logged rather than returned, since a full disk
+    /// shouldn't stop the room.
+    pub fn append(&self, event: &AuditEvent) {
+        let result = serde_json::to_string(event)
+            .map_err(io::Error::from)
+            .and_then(|line| self.send(Command::Append(line)));
+
+        if let Err(e) = result {
+            error!(path = %self.path.display(), error = ?e, "failed to append to audit log");
+        }
+    }
+
+    fn send(&self, command: Command) -> io::Result<()> {
+        self.writer
+            .send(command)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "audit log writer stopped"))
+    }
+
+    /// Reads every event logged so far. Lines that fail to parse, eg. a line cut short by a
+    /// crash, are skipped.
+    pub async fn read(&self) -> io::Result<Vec<AuditEvent>> {
+        let (synced, wait) = oneshot::channel();
+        self.send(Command::Sync(synced))?;
+        let _ = wait.await;
+
+        let path = self.path.clone();
+
+        web::block(move || read_events(&path)).await.map_err(|e| match e {
+            BlockingError::Error(e) => e,
+            BlockingError::Canceled => io::Error::new(io::ErrorKind::Other, "reading the audit log was canceled"),
+        })
+    }
+}
+
+/// Writes lines until every `AuditLog` writing to `file` is gone.
+fn write_events(path: &Path, mut file: File, commands: mpsc::Receiver<Command>) {
+    for command in commands {
+        match command {
+            Command::Append(line) => {
+                if let Err(e) = writeln!(file, "{}", line) {
+                    error!(path = %path.display(), error = ?e, "failed to append to audit log");
+                }
+            }
+            Command::Sync(synced) => {
+                let _ = synced.send(());
+            }
+        }
+    }
+}
+
+fn read_events(path: &Path) -> io::Result<Vec<AuditEvent>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut events = Vec::new();
+
+    for line in reader.lines() {
+        match serde_json::from_str(&line?) {
+            Ok(event) => events.push(event),
+            Err(e) => error!(path = %path.display(), error = ?e, "skipping broken audit log line"),
+        }
+    }
+
+    Ok(events)
+}
+
+/// Formats events as CSV with the columns `time,user,name,event,position,from,to,detail`.
+pub fn to_csv(events: &[AuditEvent]) -> String {
+    let mut out = String::from("time,user,name,event,position,from,to,detail\n");
+
+    for event in events {
+        let time = Utc.timestamp_millis(event.time.0).to_rfc3339();
+        let user = event.user.map(|u| u.0.to_string()).unwrap_or_default();
+        let name = event.name.as_deref().map(csv_field).unwrap_or_default();
+
+        let (kind, position, from, to, detail) = match &event.event {
+            AuditEventKind::Join => ("join", None, None, None, String::new()),
+            AuditEventKind::Leave => ("leave", None, None, None, String::new()),
+            AuditEventKind::Play { position } => ("play", Some(*position), None, None, String::new()),
+            AuditEventKind::Pause { position } => ("pause", Some(*position), None, None, String::new()),
+            AuditEventKind::Seek { from, to } => ("seek", None, Some(*from), Some(*to), String::new()),
+            AuditEventKind::StreamChange { slug, name } => {
+                ("stream_change", None, None, None, csv_field(&format!("{} ({})", name, slug)))
+            }
+            AuditEventKind::Kick { reason } => ("kick", None, None, None, csv_field(reason)),
+            AuditEventKind::Subtitles { subtitle, offset } => {
+                let subtitle = subtitle.as_deref().unwrap_or("none");
+                ("subtitles", None, None, None, csv_field(&format!("{} {:+}s", subtitle, offset)))
+            }
+        };
+
+        let number = |n: Option<f32>| n.map(|n| n.to_string()).unwrap_or_default();
+
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{}",
+            time, user, name, kind, number(position), number(from), number(to), detail
+        ).unwrap();
+    }
+
+    out
+}
+
+/// Quotes a field if it contains anything CSV treats specially.
+fn csv_field(value: &str) -> String {
+    if value.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,317 @@
+//! Opens many simulated participants against a server and reports how it copes.
+//!
+//! ```text
+//! tmtusync-load <server> <room> [--participants N] [--for SECONDS] [--seek-interval SECONDS]
+//!               [--encoding json|msgpack|cbor] [--pid SERVER_PID]
+//! ```
+//!
+//! Every participant answers pings and reports its state once a second. Every
+//! `--seek-interval` a dedicated participant seeks; the time until the others see the `DoSeek` is
+//! recorded as the broadcast latency. Broadcasts are matched to requests by their order, so the
+//! interval has to be long enough for seeks not to be debounced or rate limited. With `--pid`, the server's CPU and memory use is sampled
+//! from `/proc`.
+
+use futures::StreamExt;
+use tracing::warn;
+
+use tmtusync::client::{ClientConfig, ClientEvent, PlayerState, RoomClient};
+use tmtusync::codec::Encoding;
+use tmtusync::protocol::{PlayState, Time, ToSessionMessage, UserId};
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// How often the load generator prints a line of statistics.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Options {
+    server: String,
+    room: String,
+    participants: usize,
+    run_for: Duration,
+    seek_interval: Duration,
+    encoding: Encoding,
+    pid: Option<u32>,
+}
+
+fn usage() -> ! {
+    eprintln!("usage: tmtusync-load <server> <room> [--participants N] [--for SECONDS] [--seek-interval SECONDS] [--encoding json|msgpack|cbor] [--pid SERVER_PID]");
+    std::process::exit(2);
+}
+
+/// Parses a non-negative number of seconds.
+fn parse_seconds(seconds: &str) -> Result<Duration, String> {
+    let parsed = seconds.trim().parse::<f32>().map_err(|e| format!("{:?}: {}", seconds, e))?;
+
+    if !parsed.is_finite() || parsed < 0.0 {
+        return Err(format!("{:?}: expected a non-negative number of seconds", seconds));
+    }
+
+    Ok(Duration::from_secs_f32(parsed))
+}
+
+fn parse_options() -> Result<Options, Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1);
+
+    let mut options = Options {
+        server: args.next().unwrap_or_else(|| usage()),
+        room: args.next().unwrap_or_else(|| usage()),
+        participants: 50,
+        run_for: Duration::from_secs(60),
+        seek_interval: Duration::from_secs(10),
+        encoding: Encoding::Json,
+        pid: None,
+    };
+
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+
+        match arg.as_str() {
+            "--participants" => options.participants = value.parse()?,
+            "--for" => options.run_for = parse_seconds(&value)?,
+            "--seek-interval" => options.seek_interval = parse_seconds(&value)?,
+            "--encoding" => {
+                options.encoding = match value.as_str() {
+                    "json" => Encoding::Json,
+                    "msgpack" => Encoding::MessagePack,
+                    "cbor" => Encoding::Cbor,
+                    _ => usage(),
+                }
+            }
+            "--pid" => options.pid = Some(value.parse()?),
+            _ => usage(),
+        }
+    }
+
+    // The default limits let one seek a second through, closer ones would go missing.
+    if options.seek_interval < Duration::from_secs(1) {
+        return Err("--seek-interval has to be at least a second".into());
+    }
+
+    Ok(options)
+}
+
+/// Counters shared by all simulated participants.
+#[derive(Default)]
+struct Stats {
+    received: BTreeMap<&'static str, u64>,
+    /// The participant doing the seeking, once it has joined.
+    seeker: Option<UserId>,
+    /// When each seek was requested, in order, used to match up the `DoSeek` broadcasts. The
+    /// room may clamp the position, so it can't tell them apart.
+    seeks: Vec<Instant>,
+    /// Milliseconds between requesting a seek and another participant receiving it.
+    seek_latency: Vec<f32>,
+    disconnects: u64,
+}
+
+impl Stats {
+    /// Records a message received by a participant that has seen `seeks_seen` of the seeker's
+    /// seeks so far.
+    fn record(&mut self, message: &ToSessionMessage, seeks_seen: &mut usize) {
+        let name = message.kind();
+
+        *self.received.entry(name).or_insert(0) += 1;
+
+        if let ToSessionMessage::DoSeek { user, .. } = message {
+            if Some(*user) == self.seeker {
+                if let Some(sent) = self.seeks.get(*seeks_seen) {
+                    self.seek_latency.push(sent.elapsed().as_secs_f32() * 1000.0);
+                }
+
+                *seeks_seen += 1;
+            }
+        }
+    }
+}
+
+fn percentile(sorted: &[f32], p: f32) -> f32 {
+    if sorted.is_empty() {
+        return f32::NAN;
+    }
+
+    let idx = ((sorted.len() - 1) as f32 * p).round() as usize;
+    sorted[idx]
+}
+
+/// CPU time in clock ticks and resident memory in kB of a process, read from `/proc`.
+fn sample_process(pid: u32) -> Option<(u64, u64)> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain spaces, so skip past its closing parenthesis.
+    let fields = stat[stat.rfind(')')? + 2..].split_whitespace().collect::<Vec<_>>();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let rss = status
+        .lines()
+        .find(|l| l.starts_with("VmRSS:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()?;
+
+    Some((utime + stime, rss))
+}
+
+/// A tiny xorshift generator, good enough to pick who seeks where.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+async fn run_participant(mut client: RoomClient, stats: Rc<RefCell<Stats>>) {
+    let mut report = tokio::time::interval(Duration::from_secs(1));
+    let mut position = 0f32;
+    let mut state = PlayState::Play;
+    let mut seeks_seen = 0;
+
+    loop {
+        tokio::select! {
+            event = client.next() => match event {
+                Some(ClientEvent::Message(message)) => {
+                    match &message {
+                        ToSessionMessage::DoSeek { duration, .. } => position = *duration,
+                        ToSessionMessage::SetState { state: s, .. } => state = *s,
+                        _ => {}
+                    }
+
+                    stats.borrow_mut().record(&message, &mut seeks_seen);
+                }
+                Some(ClientEvent::Closed) | None => {
+                    stats.borrow_mut().disconnects += 1;
+                    break;
+                }
+            },
+            _ = report.tick() => {
+                if state == PlayState::Play {
+                    position += 1.0;
+                }
+
+                client.set_player(PlayerState {
+                    duration: position,
+                    duration_time: Time::now(),
+                    state,
+                    state_time: Time::now(),
+                    buffered: 30.0,
+                    ..PlayerState::default()
+                });
+                client.report_state();
+            }
+        }
+    }
+}
+
+#[actix_rt::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tmtusync::telemetry::init();
+
+    let options = parse_options()?;
+    let stats = Rc::new(RefCell::new(Stats::default()));
+    let mut connected = 0;
+
+    let connect_started = Instant::now();
+    for i in 0..options.participants {
+        let mut config = ClientConfig::new(&options.server, &options.room, &format!("load-{}", i));
+        config.encoding = options.encoding;
+
+        match RoomClient::connect(config).await {
+            Ok(client) => {
+                connected += 1;
+                actix_rt::spawn(run_participant(client, stats.clone()));
+            }
+            Err(e) => warn!("Participant {} failed to connect: {}", i, e),
+        }
+    }
+    println!(
+        "connected {}/{} participants in {:.1}s",
+        connected, options.participants, connect_started.elapsed().as_secs_f32()
+    );
+
+    // The seeking participant is a dedicated client so the others only measure broadcasts.
+    let mut seeker_config = ClientConfig::new(&options.server, &options.room, "load-seeker");
+    seeker_config.encoding = options.encoding;
+    let mut seeker = RoomClient::connect(seeker_config).await?;
+
+    // Its seeks are told apart by who sent them, the id arrives with the room state.
+    while seeker.user_id().is_none() {
+        match seeker.next().await {
+            Some(ClientEvent::Message(_)) => {}
+            Some(ClientEvent::Closed) | None => return Err("the seeker was disconnected".into()),
+        }
+    }
+    stats.borrow_mut().seeker = seeker.user_id();
+
+    let mut rng = Rng(Time::now().0 as u64 | 1);
+    let started = Instant::now();
+    let mut seek = tokio::time::interval(options.seek_interval);
+    let mut report = tokio::time::interval(REPORT_INTERVAL);
+    // The first tick fires right away, with nothing to report on yet.
+    report.tick().await;
+    let mut last_report = (Instant::now(), 0u64, sample_process_opt(options.pid));
+
+    while started.elapsed() < options.run_for {
+        tokio::select! {
+            _ = seek.tick() => {
+                let target = (rng.next() % 7200) as f32 + (rng.next() % 1000) as f32 / 1000.0;
+                stats.borrow_mut().seeks.push(Instant::now());
+                seeker.seek(target);
+            }
+            _ = report.tick() => {
+                let stats = stats.borrow();
+                let total = stats.received.values().sum::<u64>();
+                let elapsed = last_report.0.elapsed().as_secs_f32();
+
+                let mut line = format!(
+                    "{:>5.0}s: {:.0} msg/s received, {} disconnects",
+                    started.elapsed().as_secs_f32(),
+                    (total - last_report.1) as f32 / elapsed,
+                    stats.disconnects,
+                );
+
+                let sample = sample_process_opt(options.pid);
+                if let (Some((cpu_then, _)), Some((cpu_now, rss))) = (last_report.2, sample) {
+                    // Clock ticks are almost universally 100 per second on Linux.
+                    let cpu = (cpu_now - cpu_then) as f32 / 100.0 / elapsed * 100.0;
+                    line += &format!(", server cpu {:.0}%, rss {} MiB", cpu, rss / 1024);
+                }
+
+                println!("{}", line);
+                last_report = (Instant::now(), total, sample);
+            }
+        }
+    }
+
+    let stats = stats.borrow();
+    let elapsed = started.elapsed().as_secs_f32();
+
+    println!("messages received per type:");
+    for (name, count) in &stats.received {
+        println!("  {:<16} {:>10} ({:.1}/s)", name, count, *count as f32 / elapsed);
+    }
+
+    let mut latency = stats.seek_latency.clone();
+    latency.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    println!(
+        "seek broadcast latency (ms): p50 {:.1}, p90 {:.1}, p99 {:.1}, max {:.1} ({} samples)",
+        percentile(&latency, 0.5),
+        percentile(&latency, 0.9),
+        percentile(&latency, 0.99),
+        percentile(&latency, 1.0),
+        latency.len()
+    );
+
+    Ok(())
+}
+
+fn sample_process_opt(pid: Option<u32>) -> Option<(u64, u64)> {
+    pid.and_then(sample_process)
+}
@@ -0,0 +1,332 @@
+//! Joins a room as a simulated participant and reports how well it stays in sync.
+//!
+//! ```text
+//! tmtusync-probe <server> <room> [--name NAME] [--encoding json|msgpack|cbor]
+//!                [--script 5:play,20:seek=600,40:pause] [--for SECONDS]
+//! ```
+
+use futures::StreamExt;
+use tracing::{debug, trace};
+
+use tmtusync::client::{ClientConfig, ClientEvent, PlayerState, RoomClient};
+use tmtusync::codec::Encoding;
+use tmtusync::protocol::{capabilities, PlayState, Time, ToSessionMessage};
+
+use std::time::{Duration, Instant};
+
+/// How often the emulated player reports its position to the client.
+const TICK: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy)]
+enum Action {
+    Play,
+    Pause,
+    Seek(f32),
+}
+
+/// Parses a non-negative number of seconds.
+fn parse_seconds(seconds: &str) -> Result<Duration, String> {
+    let parsed = seconds.trim().parse::<f32>().map_err(|e| format!("{:?}: {}", seconds, e))?;
+
+    if !parsed.is_finite() || parsed < 0.0 {
+        return Err(format!("{:?}: expected a non-negative number of seconds", seconds));
+    }
+
+    Ok(Duration::from_secs_f32(parsed))
+}
+
+fn parse_script(script: &str) -> Result<Vec<(Duration, Action)>, String> {
+    let mut actions = script
+        .split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(|step| {
+            let (at, action) = step
+                .split_once(':')
+                .ok_or_else(|| format!("expected <seconds>:<action>, got {:?}", step))?;
+            let at = parse_seconds(at)?;
+
+            let action = match action.trim() {
+                "play" => Action::Play,
+                "pause" => Action::Pause,
+                seek if seek.starts_with("seek=") => Action::Seek(
+                    seek["seek=".len()..].parse().map_err(|e| format!("{:?}: {}", seek, e))?,
+                ),
+                other => return Err(format!("unknown action {:?}", other)),
+            };
+
+            Ok((at, action))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    actions.sort_by_key(|(at, _)| *at);
+
+    Ok(actions)
+}
+
+/// A media position that advances with wall time while playing.
+struct Clock {
+    position: f32,
+    state: PlayState,
+    set_at: Instant,
+}
+
+impl Clock {
+    fn new(position: f32, state: PlayState) -> Self {
+        Self { position, state, set_at: Instant::now() }
+    }
+
+    fn position(&self) -> f32 {
+        match self.state {
+            PlayState::Play => self.position + self.set_at.elapsed().as_secs_f32(),
+            PlayState::Pause => self.position,
+        }
+    }
+
+    fn seek(&mut self, position: f32) {
+        self.position = position;
+        self.set_at = Instant::now();
+    }
+
+    fn set_state(&mut self, state: PlayState) {
+        self.position = self.position();
+        self.state = state;
+        self.set_at = Instant::now();
+    }
+}
+
+#[derive(Default)]
+struct Samples(Vec<f32>);
+
+impl Samples {
+    fn push(&mut self, value: f32) {
+        self.0.push(value);
+    }
+
+    fn summary(&self) -> String {
+        if self.0.is_empty() {
+            return String::from("no samples");
+        }
+
+        let min = self.0.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = self.0.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let avg = self.0.iter().sum::<f32>() / self.0.len() as f32;
+
+        format!("min {:.1}, avg {:.1}, max {:.1} ({} samples)", min, avg, max, self.0.len())
+    }
+}
+
+struct Probe {
+    client: RoomClient,
+    /// Our emulated media player.
+    player: Clock,
+    state_time: Time,
+
+    rtt: Samples,
+    offset: Samples,
+    /// How far ahead of the other participants we are, as far as the server can tell.
+    drift: Samples,
+    server_error: Samples,
+}
+
+impl Probe {
+    fn update_player(&mut self) {
+        self.client.set_player(PlayerState {
+            duration: self.player.position(),
+            duration_time: Time::now(),
+            state: self.player.state,
+            state_time: self.state_time,
+            buffered: 30.0,
+            ..PlayerState::default()
+        });
+    }
+
+    fn perform(&mut self, action: Action) {
+        println!("script: {:?}", action);
+
+        match action {
+            Action::Play | Action::Pause => {
+                let state = if let Action::Play = action { PlayState::Play } else { PlayState::Pause };
+
+                self.client.set_state(state);
+                self.player.set_state(state);
+                self.state_time = Time::now();
+            }
+            Action::Seek(position) => {
+                self.client.seek(position);
+                self.player.seek(position);
+            }
+        }
+
+        self.update_player();
+        self.client.report_state();
+    }
+
+    fn handle(&mut self, message: ToSessionMessage) {
+        match message {
+            ToSessionMessage::RoomState { user_id, current_stream, participants } => {
+                println!("joined as {:?} with {} other participants", user_id, participants.len());
+
+                if let Some(stream) = current_stream {
+                    println!("stream {:?} at {:.1}s ({:?})", stream.slug, stream.duration, stream.state);
+
+                    self.player = Clock::new(stream.duration, stream.state);
+                    self.state_time = Time::now();
+                }
+            }
+            ToSessionMessage::DoSeek { user, duration } => {
+                println!("{:?} seeked to {:.1}s (we were at {:.1}s)", user, duration, self.player.position());
+
+                self.player.seek(duration);
+            }
+            ToSessionMessage::SetState { user, state } => {
+                println!("{:?} set state to {:?} at {:.1}s", user, state, self.player.position());
+
+                self.player.set_state(state);
+                self.state_time = Time::now();
+            }
+            ToSessionMessage::RoomUpdate { participants } => {
+                let position = self.player.position();
+                let id = self.client.user_id();
+
+                let own = participants.iter().find(|p| Some(p.user_id) == id);
+                let others = participants.iter().filter(|p| Some(p.user_id) != id).collect::<Vec<_>>();
+
+                // Both the server's idea of our position and of everybody else's, so they're
+                // estimated at the same instant.
+                let drift = match own {
+                    Some(own) if !others.is_empty() => {
+                        let room = others.iter().map(|p| p.duration).sum::<f32>() / others.len() as f32;
+                        let drift = own.duration - room;
+                        self.drift.push(drift * 1000.0);
+
+                        format!("{:+.0}ms", drift * 1000.0)
+                    }
+                    _ => String::from("n/a"),
+                };
+
+                match own {
+                    Some(update) => {
+                        let error = position - update.duration;
+                        self.server_error.push(error * 1000.0);
+
+                        println!(
+                            "update: at {:.3}s, room drift {}, server sees us at {:.3}s ({:+.0}ms), {} participants",
+                            position, drift, update.duration, error * 1000.0, participants.len()
+                        );
+                    }
+                    None => println!("update: at {:.3}s, server has no mapping for us yet", position),
+                }
+            }
+            ToSessionMessage::Timing { rtt, offset } => {
+                // The offset includes the reply's trip to the server, half the RTT on average.
+                let clock_offset = offset - rtt / 2.0;
+
+                self.rtt.push(rtt);
+                self.offset.push(clock_offset);
+
+                println!("timing: rtt {:.1}ms, clock offset {:+.1}ms", rtt, clock_offset);
+            }
+            ToSessionMessage::NewParticipant { user_id, name, .. } => {
+                println!("{:?} ({}) joined", user_id, name);
+            }
+            ToSessionMessage::ByeParticipant { user_id } => {
+                println!("{:?} left", user_id);
+            }
+            ToSessionMessage::Error(e) => {
+                println!("server error: {:?}", e);
+            }
+            ToSessionMessage::Ping => trace!("ping"),
+            other => debug!("{:?}", other),
+        }
+    }
+
+    fn report(&self) {
+        println!("rtt (ms):          {}", self.rtt.summary());
+        println!("clock offset (ms): {}", self.offset.summary());
+        println!("room drift (ms):   {}", self.drift.summary());
+        println!("server error (ms): {}", self.server_error.summary());
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: tmtusync-probe <server> <room> [--name NAME] [--encoding json|msgpack|cbor] [--script SCRIPT] [--for SECONDS]");
+    std::process::exit(2);
+}
+
+#[actix_rt::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tmtusync::telemetry::init();
+
+    let mut args = std::env::args().skip(1);
+    let server = args.next().unwrap_or_else(|| usage());
+    let room = args.next().unwrap_or_else(|| usage());
+
+    let mut config = ClientConfig::new(&server, &room, "probe");
+    config.features.push(capabilities::TIMING.to_string());
+
+    let mut script = Vec::new();
+    let mut run_for = None;
+
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+
+        match arg.as_str() {
+            "--name" => config.nickname = value,
+            "--encoding" => {
+                config.encoding = match value.as_str() {
+                    "json" => Encoding::Json,
+                    "msgpack" => Encoding::MessagePack,
+                    "cbor" => Encoding::Cbor,
+                    _ => usage(),
+                }
+            }
+            "--script" => script = parse_script(&value)?,
+            "--for" => run_for = Some(parse_seconds(&value)?),
+            _ => usage(),
+        }
+    }
+
+    let client = RoomClient::connect(config).await?;
+    println!("connected, capabilities: {:?}", client.room().capabilities);
+
+    let mut probe = Probe {
+        client,
+        player: Clock::new(0f32, PlayState::Pause),
+        state_time: Time::now(),
+        rtt: Samples::default(),
+        offset: Samples::default(),
+        drift: Samples::default(),
+        server_error: Samples::default(),
+    };
+
+    let started = Instant::now();
+    let mut script = script.into_iter().peekable();
+    let mut tick = tokio::time::interval(TICK);
+
+    loop {
+        tokio::select! {
+            event = probe.client.next() => match event {
+                Some(ClientEvent::Message(message)) => probe.handle(message),
+                Some(ClientEvent::Closed) | None => {
+                    println!("connection closed");
+                    break;
+                }
+            },
+            _ = tick.tick() => {
+                while let Some((_, action)) = script.next_if(|(at, _)| *at <= started.elapsed()) {
+                    probe.perform(action);
+                }
+
+                probe.update_player();
+
+                if run_for.map_or(false, |d| started.elapsed() >= d) {
+                    break;
+                }
+            }
+        }
+    }
+
+    probe.report();
+
+    Ok(())
+}
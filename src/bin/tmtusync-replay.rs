@@ -0,0 +1,52 @@
+//! Replays a room recording into a fresh room and prints where its output differs.
+//!
+//! ```text
+//! tmtusync-replay <recording.jsonl>
+//! ```
+//!
+//! Recordings are written by the server when `TMTUSYNC_RECORD_DIR` is set. Exits with a non-zero
+//! status if the replayed room didn't send exactly what was recorded.
+
+use tmtusync::recording;
+
+use std::path::PathBuf;
+
+fn usage() -> ! {
+    eprintln!("usage: tmtusync-replay <recording.jsonl>");
+    std::process::exit(2);
+}
+
+fn describe(message: &Option<tmtusync::protocol::ToSessionMessage>) -> String {
+    match message {
+        Some(message) => serde_json::to_string(message).unwrap_or_else(|e| e.to_string()),
+        None => String::from("nothing"),
+    }
+}
+
+#[actix_rt::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tmtusync::telemetry::init();
+
+    let path: PathBuf = std::env::args().nth(1).unwrap_or_else(|| usage()).into();
+
+    let events = recording::load(&path)?;
+    let report = recording::replay(events).await?;
+
+    for mismatch in &report.mismatches {
+        println!("user {} message #{}:", mismatch.user.0, mismatch.index);
+        println!("  recorded: {}", describe(&mismatch.expected));
+        println!("  replayed: {}", describe(&mismatch.actual));
+    }
+
+    println!(
+        "{} mismatches in {} recorded messages",
+        report.mismatches.len(),
+        report.messages
+    );
+
+    if !report.is_clean() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
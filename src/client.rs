@@ -0,0 +1,417 @@
+//! A client for the room protocol, speaking to `/websocket/{code}` the same way `app.js` does.
+//!
+//! The client has to run on an actix system since the underlying `awc` connection isn't `Send`.
+
+use actix_web::{http::header, web::Bytes};
+use awc::ws::{Frame, Message};
+use futures::{channel::mpsc, SinkExt, Stream, StreamExt};
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{error, trace, warn};
+
+use crate::codec::{self, Encoding};
+use crate::protocol::{
+    BadgeId, ParticipantInfo, ParticipantUpdate, PlayState, RejectReason, StreamInfo, Time,
+    ToSessionMessage, UserId, UserMessage, PROTOCOL_VERSION,
+};
+
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("http request failed: {0}")]
+    Http(String),
+    #[error("room {0:?} does not exist")]
+    RoomNotFound(String),
+    #[error("server did not hand out an identity cookie")]
+    MissingCookie,
+    #[error("websocket connection failed: {0}")]
+    Websocket(String),
+    #[error("server rejected the handshake: {0:?}")]
+    Rejected(RejectReason),
+    #[error("connection closed during the handshake")]
+    Closed,
+}
+
+/// How to join a room.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Address of the server, eg. `http://127.0.0.1:8080`.
+    pub server: String,
+    pub room: String,
+    pub nickname: String,
+    pub avatar: BadgeId,
+    pub encoding: Encoding,
+    pub features: Vec<String>,
+}
+
+impl ClientConfig {
+    pub fn new(server: &str, room: &str, nickname: &str) -> Self {
+        Self {
+            server: server.trim_end_matches('/').to_string(),
+            room: room.to_string(),
+            nickname: nickname.to_string(),
+            avatar: BadgeId(0),
+            encoding: Encoding::Json,
+            features: Vec::new(),
+        }
+    }
+}
+
+/// The state of the local media player, reported to the server whenever it pings us.
+#[derive(Debug, Clone)]
+pub struct PlayerState {
+    pub duration: f32,
+    pub duration_time: Time,
+    pub state: PlayState,
+    pub state_time: Time,
+    pub buffered: f32,
+    /// The `Stream::quality` being played, if known.
+    pub quality: Option<u32>,
+    pub auto_quality: bool,
+    /// The `AudioTrack::name` being played, if known.
+    pub audio: Option<String>,
+}
+
+impl Default for PlayerState {
+    fn default() -> Self {
+        let now = Time::now();
+
+        Self {
+            duration: 0f32,
+            duration_time: now,
+            state: PlayState::Pause,
+            state_time: now,
+            buffered: 0f32,
+            quality: None,
+            auto_quality: false,
+            audio: None,
+        }
+    }
+}
+
+/// The client's view of the room, built from the messages received so far.
+#[derive(Debug, Clone, Default)]
+pub struct RoomView {
+    pub user_id: Option<UserId>,
+    pub capabilities: Vec<String>,
+    pub participants: Vec<ParticipantInfo>,
+    pub updates: Vec<ParticipantUpdate>,
+    pub stream: Option<StreamInfo>,
+}
+
+impl RoomView {
+    fn apply(&mut self, message: &ToSessionMessage) {
+        match message {
+            ToSessionMessage::Welcome { capabilities, .. } => {
+                self.capabilities = capabilities.clone();
+            }
+            ToSessionMessage::RoomState { user_id, participants, current_stream } => {
+                self.user_id = Some(*user_id);
+                self.participants = participants.clone();
+                self.stream = current_stream.clone();
+            }
+            ToSessionMessage::RoomUpdate { participants } => {
+                self.updates = participants.clone();
+            }
+            ToSessionMessage::NewParticipant { user_id, name, avatar, badges } => {
+                self.participants.retain(|p| p.user_id != *user_id);
+                self.participants.push(ParticipantInfo {
+                    user_id: *user_id,
+                    name: name.clone(),
+                    avatar: *avatar,
+                    badges: badges.clone(),
+                });
+            }
+            ToSessionMessage::ByeParticipant { user_id } => {
+                self.participants.retain(|p| p.user_id != *user_id);
+                self.updates.retain(|p| p.user_id != *user_id);
+            }
+            ToSessionMessage::NewStream(stream) => {
+                self.stream = Some(stream.clone());
+            }
+            ToSessionMessage::SetState { state, .. } => {
+                if let Some(stream) = &mut self.stream {
+                    stream.state = *state;
+                }
+            }
+            ToSessionMessage::DoSeek { duration, .. } => {
+                if let Some(stream) = &mut self.stream {
+                    stream.duration = *duration;
+                }
+            }
+            ToSessionMessage::SubtitlesChanged { subtitle, offset, .. } => {
+                if let Some(stream) = &mut self.stream {
+                    stream.subtitle = subtitle.clone();
+                    stream.subtitle_offset = *offset;
+                }
+            }
+            ToSessionMessage::Restarting { duration } => {
+                if let Some(stream) = &mut self.stream {
+                    stream.state = PlayState::Pause;
+                    stream.duration = *duration;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Something that happened on the connection.
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// A message from the server. Pings have already been answered when this is received.
+    Message(ToSessionMessage),
+    /// The server closed the connection.
+    Closed,
+}
+
+struct Shared {
+    room: RoomView,
+    player: PlayerState,
+}
+
+/// A connection to a room.
+pub struct RoomClient {
+    /// Frames to send, encoded already so the reader can queue pongs as well.
+    outgoing: mpsc::UnboundedSender<Message>,
+    encoding: Encoding,
+    events: mpsc::UnboundedReceiver<ClientEvent>,
+    shared: Rc<RefCell<Shared>>,
+    /// The identity cookie handed out on login, as sent in a `Cookie` header.
+    cookie: String,
+}
+
+#[derive(Serialize)]
+struct LoginForm<'a> {
+    nickname: &'a str,
+    avatar: u32,
+    room: &'a str,
+}
+
+/// Logs in through the index form and returns the identity cookie handed out by the server.
+async fn login(client: &awc::Client, config: &ClientConfig) -> Result<String, ClientError> {
+    let response = client
+        .post(format!("{}/", config.server))
+        .send_form(&LoginForm {
+            nickname: &config.nickname,
+            avatar: config.avatar.0,
+            room: &config.room,
+        })
+        .await
+        .map_err(|e| ClientError::Http(e.to_string()))?;
+
+    if response.status().is_redirection() {
+        return Err(ClientError::RoomNotFound(config.room.clone()));
+    }
+
+    response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .filter_map(|h| h.to_str().ok())
+        .find(|c| c.starts_with("auth-cookie="))
+        .and_then(|c| c.split(';').next())
+        .map(String::from)
+        .ok_or(ClientError::MissingCookie)
+}
+
+impl RoomClient {
+    /// Joins a room and performs the handshake. Returns once the server has welcomed us.
+    pub async fn connect(config: ClientConfig) -> Result<Self, ClientError> {
+        let http = awc::Client::new();
+        let cookie = login(&http, &config).await?;
+
+        let url = format!(
+            "{}/websocket/{}",
+            config.server.replacen("http", "ws", 1),
+            config.room
+        );
+
+        let (_response, framed) = http
+            .ws(url)
+            .header(header::COOKIE, cookie.clone())
+            .protocols(&[config.encoding.protocol()])
+            .connect()
+            .await
+            .map_err(|e| ClientError::Websocket(e.to_string()))?;
+
+        let (mut sink, mut stream) = framed.split();
+        let (outgoing, mut outgoing_rx) = mpsc::unbounded::<Message>();
+        let (events_tx, events) = mpsc::unbounded();
+
+        let shared = Rc::new(RefCell::new(Shared {
+            room: RoomView::default(),
+            player: PlayerState::default(),
+        }));
+
+        let encoding = config.encoding;
+        actix_rt::spawn(async move {
+            while let Some(frame) = outgoing_rx.next().await {
+                if let Err(e) = sink.send(frame).await {
+                    warn!("Failed to send message: {:?}", e);
+                    break;
+                }
+            }
+
+            let _ = sink.close().await;
+        });
+
+        let reader_shared = shared.clone();
+        let pong = outgoing.clone();
+        actix_rt::spawn(async move {
+            while let Some(frame) = stream.next().await {
+                let message = match frame {
+                    // Text frames are always JSON, regardless of the negotiated encoding.
+                    Ok(Frame::Text(txt)) => Encoding::Json.decode_binary::<ToSessionMessage>(&txt),
+                    Ok(Frame::Binary(bytes)) => encoding.decode_binary::<ToSessionMessage>(&bytes),
+                    Ok(Frame::Ping(payload)) => {
+                        let _ = pong.unbounded_send(Message::Pong(payload));
+                        continue;
+                    }
+                    Ok(Frame::Close(_)) => break,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        warn!("Websocket error: {:?}", e);
+                        break;
+                    }
+                };
+
+                let message = match message {
+                    Ok(message) => message,
+                    Err(e) => {
+                        warn!("Failed to parse message from server: {:?}", e);
+                        continue;
+                    }
+                };
+
+                trace!("<- {:?}", message);
+
+                if let ToSessionMessage::Ping = message {
+                    let state = state_message(&reader_shared.borrow().player);
+                    if let Some(frame) = encode(encoding, &state) {
+                        let _ = pong.unbounded_send(frame);
+                    }
+                }
+
+                reader_shared.borrow_mut().room.apply(&message);
+
+                if events_tx.unbounded_send(ClientEvent::Message(message)).is_err() {
+                    break;
+                }
+            }
+
+            let _ = events_tx.unbounded_send(ClientEvent::Closed);
+        });
+
+        let mut client = Self { outgoing, encoding, events, shared, cookie };
+
+        client.send(UserMessage::Hello {
+            name: config.nickname.clone(),
+            avatar: config.avatar,
+            time: Time::now(),
+            version: PROTOCOL_VERSION,
+            features: config.features.clone(),
+        });
+
+        match client.events.next().await {
+            Some(ClientEvent::Message(ToSessionMessage::Welcome { .. })) => {}
+            Some(ClientEvent::Message(ToSessionMessage::Rejected { reason, .. })) => {
+                return Err(ClientError::Rejected(reason));
+            }
+            _ => return Err(ClientError::Closed),
+        }
+
+        Ok(client)
+    }
+
+    /// The id the server handed out to us, known once the `RoomState` has arrived.
+    pub fn user_id(&self) -> Option<UserId> {
+        self.shared.borrow().room.user_id
+    }
+
+    /// The identity cookie we logged in with, for fetching the media of the room.
+    pub fn cookie(&self) -> &str {
+        &self.cookie
+    }
+
+    pub fn room(&self) -> RoomView {
+        self.shared.borrow().room.clone()
+    }
+
+    pub fn player(&self) -> PlayerState {
+        self.shared.borrow().player.clone()
+    }
+
+    /// Sets the player state that is reported on the next ping.
+    pub fn set_player(&self, player: PlayerState) {
+        self.shared.borrow_mut().player = player;
+    }
+
+    /// Sends the current player state right away instead of waiting for a ping.
+    pub fn report_state(&self) {
+        self.send(state_message(&self.shared.borrow().player));
+    }
+
+    pub fn seek(&self, duration: f32) {
+        self.send(UserMessage::Seek { duration, time: Time::now() });
+    }
+
+    pub fn set_state(&self, state: PlayState) {
+        self.send(UserMessage::SetState { state, time: Time::now() });
+    }
+
+    pub fn set_subtitles(&self, subtitle: Option<String>, offset: f32) {
+        self.send(UserMessage::SetSubtitles { subtitle, offset });
+    }
+
+    pub fn send(&self, message: UserMessage) {
+        trace!("-> {:?}", message);
+
+        if let Some(frame) = encode(self.encoding, &message) {
+            if self.outgoing.unbounded_send(frame).is_err() {
+                warn!("Tried to send message on closed connection");
+            }
+        }
+    }
+
+    /// Leaves the room by closing the connection.
+    pub fn close(self) {
+        self.outgoing.close_channel();
+    }
+}
+
+impl Stream for RoomClient {
+    type Item = ClientEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+fn encode(encoding: Encoding, message: &UserMessage) -> Option<Message> {
+    match encoding.encode(message) {
+        Ok(codec::Frame::Text(txt)) => Some(Message::Text(txt)),
+        Ok(codec::Frame::Binary(bytes)) => Some(Message::Binary(Bytes::from(bytes))),
+        Err(e) => {
+            error!("Failed to encode {:?}: {:?}", message, e);
+            None
+        }
+    }
+}
+
+fn state_message(player: &PlayerState) -> UserMessage {
+    UserMessage::State {
+        duration: player.duration,
+        duration_time: player.duration_time,
+        state: player.state,
+        state_time: player.state_time,
+        buffered: player.buffered,
+        time: Time::now(),
+        quality: player.quality,
+        auto_quality: player.auto_quality,
+        audio: player.audio.clone(),
+    }
+}
@@ -0,0 +1,114 @@
+//! Time source for the server. Everything that needs the current time or has to wait goes
+//! through a `Clock`, so tests can swap in a `MockClock` and control time by hand.
+
+use chrono::{DateTime, Utc};
+use futures::{channel::oneshot, future::BoxFuture, FutureExt};
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Returns a future that completes once `duration` has passed on this clock.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The wall clock.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SystemClock;
+
+impl SystemClock {
+    pub fn shared() -> SharedClock {
+        Arc::new(SystemClock)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::delay_for(duration).boxed()
+    }
+}
+
+struct MockState {
+    now: DateTime<Utc>,
+    sleepers: Vec<(DateTime<Utc>, oneshot::Sender<()>)>,
+}
+
+/// A clock that only moves when told to. Sleeping futures complete once the clock has been
+/// advanced past their deadline.
+#[derive(Clone)]
+pub struct MockClock {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockClock {
+    pub fn new(start: DateTime<Utc>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(MockState {
+                now: start,
+                sleepers: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn shared(&self) -> SharedClock {
+        Arc::new(self.clone())
+    }
+
+    /// Moves the clock forward, waking up everything sleeping until then.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.now = state.now + chrono::Duration::from_std(duration).unwrap();
+
+        let now = state.now;
+        let (ready, pending) = state.sleepers.drain(..).partition(|(at, _)| *at <= now);
+        state.sleepers = pending;
+        drop(state);
+
+        for (_, waker) in ready {
+            let _ = waker.send(());
+        }
+    }
+
+    /// How many futures are sleeping on the clock, leaving out those nobody waits for anymore.
+    pub fn sleepers(&self) -> usize {
+        let state = self.state.lock().unwrap();
+
+        state.sleepers.iter().filter(|(_, waker)| !waker.is_canceled()).count()
+    }
+
+    /// Moves the clock to `time`, which must not be in the past.
+    pub fn set(&self, time: DateTime<Utc>) {
+        let now = self.now();
+        if time > now {
+            self.advance((time - now).to_std().unwrap());
+        }
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.state.lock().unwrap().now
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let mut state = self.state.lock().unwrap();
+        let deadline = state.now + chrono::Duration::from_std(duration).unwrap();
+
+        if deadline <= state.now {
+            return futures::future::ready(()).boxed();
+        }
+
+        let (tx, rx) = oneshot::channel();
+        state.sleepers.push((deadline, tx));
+
+        rx.map(|_| ()).boxed()
+    }
+}
@@ -0,0 +1,351 @@
+//! Cluster mode: several server processes sharing a directory of rooms. Every room is owned by
+//! exactly one node, which runs its `Room` actor. The other nodes forward websocket sessions for
+//! the room to the owner, see `WebsocketForwarder`.
+//!
+//! The directory is pluggable through `RoomDirectory`. `MemoryDirectory` shares rooms between
+//! servers in one process, `FileDirectory` between processes on one machine.
+//!
+//! A node shutting down hands its rooms over through the directory. The next node asked for
+//! such a room takes it over, and participants carry on there.
+//!
+//! Nodes talk to each other with a shared secret in the `X-Cluster-Secret` header.
+
+use futures::future::{self, BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::actors::RoomSnapshot;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Carries the cluster's secret on requests between nodes.
+pub const SECRET_HEADER: &str = "X-Cluster-Secret";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NodeInfo {
+    pub id: String,
+    /// Base URL other nodes reach this node at, eg. `http://10.0.0.2:8080`.
+    pub address: String,
+}
+
+impl NodeInfo {
+    pub fn new(id: &str, address: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            address: address.trim_end_matches('/').to_string(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum DirectoryError {
+    #[error("room directory unavailable: {0}")]
+    Io(#[from] io::Error),
+    #[error("broken directory entry for room {0:?}")]
+    Corrupt(String),
+}
+
+/// Why a node didn't get to run a room.
+#[derive(Error, Debug)]
+pub enum ClaimError {
+    #[error("room is owned by node {0:?}")]
+    OwnedElsewhere(String),
+    #[error(transparent)]
+    Directory(#[from] DirectoryError),
+}
+
+/// Keeps track of which node owns which room.
+pub trait RoomDirectory: Send + Sync {
+    /// Makes `node` the owner of `room` unless another node owns it already. Returns the owner.
+    /// A node claiming a room it already owns, eg. after a restart, keeps it.
+    fn claim(&self, room: &str, node: &NodeInfo) -> BoxFuture<'static, Result<NodeInfo, DirectoryError>>;
+
+    fn owner(&self, room: &str) -> BoxFuture<'static, Result<Option<NodeInfo>, DirectoryError>>;
+
+    /// Gives up ownership of `room`, if `node` owns it. With a snapshot the room is handed over,
+    /// for another node to `take_over`.
+    fn release(
+        &self,
+        room: &str,
+        node: &NodeInfo,
+        snapshot: Option<&RoomSnapshot>,
+    ) -> BoxFuture<'static, Result<(), DirectoryError>>;
+
+    /// Takes the snapshot `room` was handed over with, if there is one. Only one node gets it.
+    fn take_over(&self, room: &str, node: &NodeInfo) -> BoxFuture<'static, Result<Option<RoomSnapshot>, DirectoryError>>;
+}
+
+pub type SharedDirectory = Arc<dyn RoomDirectory>;
+
+/// A directory living in memory, shared by every server holding a clone of it.
+#[derive(Clone, Default)]
+pub struct MemoryDirectory {
+    owners: Arc<Mutex<HashMap<String, NodeInfo>>>,
+    handed_over: Arc<Mutex<HashMap<String, RoomSnapshot>>>,
+}
+
+impl MemoryDirectory {
+    pub fn shared(&self) -> SharedDirectory {
+        Arc::new(self.clone())
+    }
+}
+
+impl RoomDirectory for MemoryDirectory {
+    fn claim(&self, room: &str, node: &NodeInfo) -> BoxFuture<'static, Result<NodeInfo, DirectoryError>> {
+        let mut owners = self.owners.lock().unwrap();
+        let owner = owners.entry(room.to_string()).or_insert_with(|| node.clone());
+
+        if owner.id == node.id {
+            *owner = node.clone();
+        }
+
+        future::ready(Ok(owner.clone())).boxed()
+    }
+
+    fn owner(&self, room: &str) -> BoxFuture<'static, Result<Option<NodeInfo>, DirectoryError>> {
+        future::ready(Ok(self.owners.lock().unwrap().get(room).cloned())).boxed()
+    }
+
+    fn release(
+        &self,
+        room: &str,
+        node: &NodeInfo,
+        snapshot: Option<&RoomSnapshot>,
+    ) -> BoxFuture<'static, Result<(), DirectoryError>> {
+        let mut owners = self.owners.lock().unwrap();
+
+        if owners.get(room).map(|owner| owner.id == node.id).unwrap_or(false) {
+            if let Some(snapshot) = snapshot {
+                self.handed_over.lock().unwrap().insert(room.to_string(), snapshot.clone());
+            }
+
+            owners.remove(room);
+        }
+
+        future::ready(Ok(())).boxed()
+    }
+
+    fn take_over(&self, room: &str, _node: &NodeInfo) -> BoxFuture<'static, Result<Option<RoomSnapshot>, DirectoryError>> {
+        future::ready(Ok(self.handed_over.lock().unwrap().remove(room))).boxed()
+    }
+}
+
+/// A directory of one JSON file per room, for running several processes on one machine or on a
+/// shared filesystem. Snapshots of rooms handed over are kept below `handed_over`.
+#[derive(Clone)]
+pub struct FileDirectory {
+    dir: PathBuf,
+}
+
+impl FileDirectory {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn shared(&self) -> SharedDirectory {
+        Arc::new(self.clone())
+    }
+
+    fn path(&self, room: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", room))
+    }
+
+    fn snapshot_path(&self, room: &str) -> PathBuf {
+        self.dir.join("handed_over").join(format!("{}.json", room))
+    }
+
+    fn read(&self, room: &str) -> Result<Option<NodeInfo>, DirectoryError> {
+        match fs::read(self.path(room)) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map(Some)
+                .map_err(|_| DirectoryError::Corrupt(room.to_string())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn claim_sync(&self, room: &str, node: &NodeInfo) -> Result<NodeInfo, DirectoryError> {
+        fs::create_dir_all(&self.dir)?;
+
+        // Write the entry under a name of our own, then link it into place. Linking fails if
+        // another node got there first, and nobody ever sees a half written entry.
+        let tmp = self.dir.join(format!("{}.{}.tmp", room, node.id));
+        fs::write(&tmp, serde_json::to_vec(node).map_err(io::Error::from)?)?;
+        let linked = fs::hard_link(&tmp, self.path(room));
+        fs::remove_file(&tmp)?;
+
+        match linked {
+            Ok(()) => Ok(node.clone()),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let owner = self.read(room)?.ok_or_else(|| DirectoryError::Corrupt(room.to_string()))?;
+
+                if owner.id == node.id && owner != *node {
+                    // Our own claim from before a restart, possibly at another address.
+                    fs::write(self.path(room), serde_json::to_vec(node).map_err(io::Error::from)?)?;
+                    return Ok(node.clone());
+                }
+
+                Ok(owner)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn release_sync(&self, room: &str, node: &NodeInfo, snapshot: Option<&RoomSnapshot>) -> Result<(), DirectoryError> {
+        match self.read(room)? {
+            Some(owner) if owner.id == node.id => {}
+            _ => return Ok(()),
+        }
+
+        // The snapshot is in place before the room is up for grabs, so whoever finds the room
+        // without an owner finds the snapshot as well.
+        if let Some(snapshot) = snapshot {
+            let path = self.snapshot_path(room);
+            let tmp = path.with_extension(format!("{}.tmp", node.id));
+
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(&tmp, serde_json::to_vec(snapshot).map_err(io::Error::from)?)?;
+            fs::rename(tmp, path)?;
+        }
+
+        Ok(fs::remove_file(self.path(room))?)
+    }
+
+    fn take_over_sync(&self, room: &str, node: &NodeInfo) -> Result<Option<RoomSnapshot>, DirectoryError> {
+        // Moving the snapshot out of the way succeeds for one node only.
+        let path = self.snapshot_path(room);
+        let taken = path.with_extension(format!("{}.taken", node.id));
+
+        match fs::rename(&path, &taken) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+
+        let data = fs::read(&taken)?;
+        fs::remove_file(&taken)?;
+
+        serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|_| DirectoryError::Corrupt(room.to_string()))
+    }
+}
+
+impl RoomDirectory for FileDirectory {
+    fn claim(&self, room: &str, node: &NodeInfo) -> BoxFuture<'static, Result<NodeInfo, DirectoryError>> {
+        future::ready(self.claim_sync(room, node)).boxed()
+    }
+
+    fn owner(&self, room: &str) -> BoxFuture<'static, Result<Option<NodeInfo>, DirectoryError>> {
+        future::ready(self.read(room)).boxed()
+    }
+
+    fn release(
+        &self,
+        room: &str,
+        node: &NodeInfo,
+        snapshot: Option<&RoomSnapshot>,
+    ) -> BoxFuture<'static, Result<(), DirectoryError>> {
+        future::ready(self.release_sync(room, node, snapshot)).boxed()
+    }
+
+    fn take_over(&self, room: &str, node: &NodeInfo) -> BoxFuture<'static, Result<Option<RoomSnapshot>, DirectoryError>> {
+        future::ready(self.take_over_sync(room, node)).boxed()
+    }
+}
+
+/// This node and the directory it shares with the rest of the cluster.
+#[derive(Clone)]
+pub struct Cluster {
+    pub node: NodeInfo,
+    pub directory: SharedDirectory,
+    /// Known to every node of the cluster, and nobody else.
+    secret: String,
+    /// How long requests to other nodes may take.
+    pub timeout: Duration,
+}
+
+impl Cluster {
+    pub fn new(node: NodeInfo, directory: SharedDirectory, secret: &str) -> Self {
+        Self {
+            node,
+            directory,
+            secret: secret.to_string(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Cluster mode is enabled by `TMTUSYNC_CLUSTER_DIR`, the directory shared by every node.
+    /// `TMTUSYNC_NODE_ID` and `TMTUSYNC_NODE_ADDRESS` tell the nodes apart, all of them share
+    /// `TMTUSYNC_CLUSTER_SECRET`. `TMTUSYNC_CLUSTER_TIMEOUT` limits requests between nodes, in
+    /// seconds.
+    pub fn from_env() -> Option<Self> {
+        let dir = std::env::var_os("TMTUSYNC_CLUSTER_DIR")?;
+        let id = std::env::var("TMTUSYNC_NODE_ID").expect("TMTUSYNC_NODE_ID is required in cluster mode");
+        let address = std::env::var("TMTUSYNC_NODE_ADDRESS")
+            .expect("TMTUSYNC_NODE_ADDRESS is required in cluster mode");
+        let secret = std::env::var("TMTUSYNC_CLUSTER_SECRET")
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .expect("TMTUSYNC_CLUSTER_SECRET is required in cluster mode");
+
+        let mut cluster = Self::new(NodeInfo::new(&id, &address), FileDirectory::new(dir).shared(), &secret);
+
+        if let Some(timeout) = std::env::var("TMTUSYNC_CLUSTER_TIMEOUT").ok().and_then(|s| s.parse().ok()) {
+            cluster.timeout = Duration::from_secs(timeout);
+        }
+
+        Some(cluster)
+    }
+
+    pub fn is_local(&self, node: &NodeInfo) -> bool {
+        node.id == self.node.id
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+
+    /// Whether a request carrying `secret` comes from a node of the cluster.
+    pub fn is_member(&self, secret: &str) -> bool {
+        // Compared in constant time, to not give away how much of a guess was right.
+        self.secret.len() == secret.len()
+            && self.secret.bytes().zip(secret.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+
+    /// The client for requests to other nodes, shared by everything running on this thread.
+    pub fn client(&self) -> awc::Client {
+        thread_local! {
+            static CLIENT: RefCell<Option<awc::Client>> = RefCell::new(None);
+        }
+
+        CLIENT.with(|client| {
+            client
+                .borrow_mut()
+                .get_or_insert_with(|| awc::Client::builder().timeout(self.timeout).finish())
+                .clone()
+        })
+    }
+
+    /// Makes this node the owner of `room`, unless another node owns it already.
+    pub async fn claim(&self, room: &str) -> Result<(), ClaimError> {
+        let owner = self.directory.claim(room, &self.node).await?;
+
+        if !self.is_local(&owner) {
+            return Err(ClaimError::OwnedElsewhere(owner.id));
+        }
+
+        Ok(())
+    }
+
+    /// The owner of `room`, if that is another node.
+    pub async fn remote_owner(&self, room: &str) -> Result<Option<NodeInfo>, DirectoryError> {
+        Ok(self.directory.owner(room).await?.filter(|owner| !self.is_local(owner)))
+    }
+}
@@ -0,0 +1,78 @@
+//! Wire encodings for the websocket protocol. JSON text frames are the default, binary encodings
+//! are picked through the `Sec-WebSocket-Protocol` header.
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// An encoded message, ready to be put in a websocket frame.
+pub enum Frame {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+/// How messages are serialized on a websocket.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Json
+    }
+}
+
+impl Encoding {
+    /// Every encoding the server supports. The order doesn't matter, the client's order of
+    /// preference in its `Sec-WebSocket-Protocol` header decides, see [`Encoding::negotiate`].
+    pub const ALL: [Encoding; 3] = [Encoding::MessagePack, Encoding::Cbor, Encoding::Json];
+
+    /// The websocket subprotocol name for this encoding.
+    pub fn protocol(self) -> &'static str {
+        match self {
+            Encoding::Json => "tmtusync.json",
+            Encoding::MessagePack => "tmtusync.msgpack",
+            Encoding::Cbor => "tmtusync.cbor",
+        }
+    }
+
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|e| e.protocol() == protocol.trim())
+    }
+
+    /// Picks the encoding from a `Sec-WebSocket-Protocol` header, using the first protocol listed
+    /// by the client that we know about. Falls back to JSON if the header is missing or lists
+    /// nothing we support.
+    pub fn negotiate(header: Option<&str>) -> Self {
+        header
+            .and_then(|h| h.split(',').find_map(Self::from_protocol))
+            .unwrap_or_default()
+    }
+
+    pub fn is_binary(self) -> bool {
+        self != Encoding::Json
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> anyhow::Result<Frame> {
+        Ok(match self {
+            Encoding::Json => Frame::Text(serde_json::to_string(value)?),
+            // Named fields keep the MessagePack layout identical to the JSON one.
+            Encoding::MessagePack => Frame::Binary(rmp_serde::to_vec_named(value)?),
+            Encoding::Cbor => Frame::Binary(serde_cbor::to_vec(value)?),
+        })
+    }
+
+    pub fn decode_text<T: DeserializeOwned>(self, txt: &str) -> anyhow::Result<T> {
+        // Text frames are always JSON, regardless of the negotiated encoding.
+        Ok(serde_json::from_str(txt)?)
+    }
+
+    pub fn decode_binary<T: DeserializeOwned>(self, bytes: &[u8]) -> anyhow::Result<T> {
+        Ok(match self {
+            Encoding::Json => serde_json::from_slice(bytes)?,
+            Encoding::MessagePack => rmp_serde::from_read_ref(bytes)?,
+            Encoding::Cbor => serde_cbor::from_slice(bytes)?,
+        })
+    }
+}
@@ -0,0 +1,317 @@
+//! Reading and generating HLS playlists for the media living on this server.
+
+use crate::protocol::{AudioTrack, Stream};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A segment listed in a media playlist.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    /// In seconds.
+    pub duration: f32,
+    pub uri: String,
+}
+
+/// The segments of a media playlist, from its `#EXTINF` tags and the URIs following them.
+pub fn parse_segments(playlist: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut duration = None;
+
+    for line in playlist.lines().map(str::trim) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            duration = info.split(',').next().and_then(|d| d.trim().parse().ok());
+        } else if !line.is_empty() && !line.starts_with('#') {
+            if let Some(duration) = duration.take() {
+                segments.push(Segment { duration, uri: line.to_string() });
+            }
+        }
+    }
+
+    segments
+}
+
+/// `uri` from a playlist in `dir` as a path from the directory of the stream, `dir` being
+/// relative to that as well. `None` for URIs outside of the stream, absolute ones included.
+pub fn resolve_uri(dir: &str, uri: &str) -> Option<String> {
+    if uri.contains("://") || uri.starts_with('/') {
+        return None;
+    }
+
+    let uri = uri.split(|c| c == '?' || c == '#').next().unwrap_or_default();
+    let mut parts = dir.split('/').filter(|p| !p.is_empty()).collect::<Vec<_>>();
+
+    for part in uri.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            part => parts.push(part),
+        }
+    }
+
+    if parts.is_empty() || parts.iter().any(|p| p.contains('\\')) {
+        None
+    } else {
+        Some(parts.join("/"))
+    }
+}
+
+/// `playlist` with every URI replaced by `map`, be it a segment, a playlist or one in a `URI`
+/// attribute of a tag like `#EXT-X-MEDIA` or `#EXT-X-KEY`.
+pub fn rewrite_uris(playlist: &str, mut map: impl FnMut(&str) -> String) -> String {
+    let mut rewritten = String::with_capacity(playlist.len());
+
+    for line in playlist.lines().map(str::trim) {
+        if line.is_empty() {
+            continue;
+        }
+
+        if !line.starts_with('#') {
+            rewritten.push_str(&map(line));
+        } else {
+            let value = line.find("URI=\"").map(|start| start + 5);
+
+            match value.and_then(|start| line[start..].find('"').map(|len| (start, start + len))) {
+                Some((start, end)) => {
+                    rewritten.push_str(&line[..start]);
+                    rewritten.push_str(&map(&line[start..end]));
+                    rewritten.push_str(&line[end..]);
+                }
+                None => rewritten.push_str(line),
+            }
+        }
+
+        rewritten.push('\n');
+    }
+
+    rewritten
+}
+
+/// The attributes of a tag like `#EXT-X-MEDIA`, with quotes removed from quoted values.
+pub fn parse_attributes(list: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = list.trim();
+
+    while let Some((name, value)) = rest.split_once('=') {
+        let name = name.trim().to_string();
+        let value = value.trim_start();
+
+        let (value, remaining) = match value.strip_prefix('"') {
+            Some(quoted) => match quoted.find('"') {
+                Some(end) => (&quoted[..end], &quoted[end + 1..]),
+                None => (quoted, ""),
+            },
+            None => match value.find(',') {
+                Some(end) => (&value[..end], &value[end..]),
+                None => (value, ""),
+            },
+        };
+
+        attributes.push((name, value.to_string()));
+        rest = remaining.trim_start().trim_start_matches(',');
+    }
+
+    attributes
+}
+
+/// The audio renditions announced with `#EXT-X-MEDIA:TYPE=AUDIO` in a master playlist.
+pub fn parse_audio_tracks(master: &str) -> Vec<AudioTrack> {
+    let mut tracks = Vec::new();
+
+    for line in master.lines().map(str::trim) {
+        let attributes = match line.strip_prefix("#EXT-X-MEDIA:") {
+            Some(list) => parse_attributes(list),
+            None => continue,
+        };
+
+        let attribute = |name: &str| attributes.iter().find(|(n, _)| n == name).map(|(_, v)| v.clone());
+
+        if attribute("TYPE").as_deref() != Some("AUDIO") {
+            continue;
+        }
+
+        if let (Some(group), Some(name)) = (attribute("GROUP-ID"), attribute("NAME")) {
+            tracks.push(AudioTrack {
+                group,
+                name,
+                language: attribute("LANGUAGE"),
+                default: attribute("DEFAULT").as_deref() == Some("YES"),
+                uri: attribute("URI"),
+            });
+        }
+    }
+
+    tracks
+}
+
+/// The attributes of every `#EXT-X-STREAM-INF` in a master playlist, by the URI of the variant
+/// following it.
+pub fn parse_variants(master: &str) -> HashMap<String, Vec<(String, String)>> {
+    let mut variants = HashMap::new();
+    let mut attributes = None;
+
+    for line in master.lines().map(str::trim) {
+        if let Some(list) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            attributes = Some(parse_attributes(list));
+        } else if !line.is_empty() && !line.starts_with('#') {
+            if let Some(attributes) = attributes.take() {
+                variants.insert(line.to_string(), attributes);
+            }
+        }
+    }
+
+    variants
+}
+
+/// The audio tracks of a stream on disk, from the first master playlist among `streams`.
+pub fn discover_audio_tracks(dir: &Path, streams: &[Stream]) -> io::Result<Vec<AudioTrack>> {
+    let master = match streams.iter().find(|s| s.quality == 0) {
+        Some(master) => master,
+        None => return Ok(Vec::new()),
+    };
+
+    match fs::read_to_string(dir.join(&master.playlist)) {
+        Ok(playlist) => Ok(parse_audio_tracks(&playlist)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Peak and average bit rate of a rendition on disk in bit/s, measured from the size of its
+/// segments.
+pub fn measure_bandwidth(playlist: &Path) -> io::Result<(u32, u32)> {
+    let dir = playlist.parent().unwrap_or_else(|| Path::new(""));
+    let segments = parse_segments(&fs::read_to_string(playlist)?);
+
+    let (mut peak, mut bits, mut duration) = (0f64, 0f64, 0f64);
+
+    for segment in segments.iter().filter(|s| !s.uri.contains("://")) {
+        let size = fs::metadata(dir.join(&segment.uri))?.len() as f64 * 8.0;
+
+        if segment.duration > 0.0 {
+            peak = peak.max(size / segment.duration as f64);
+        }
+
+        bits += size;
+        duration += segment.duration as f64;
+    }
+
+    let average = if duration > 0.0 { bits / duration } else { 0.0 };
+
+    Ok((peak.ceil() as u32, average.ceil() as u32))
+}
+
+/// A master playlist listing every rendition of a stream, the highest quality first, so players
+/// can tell which of their levels is which `Stream::quality`. The playlists are read from `dir`
+/// and listed relative to it. Resolution and codecs are taken from the stream's own master
+/// playlist, when it has one listing the rendition. Streams without renditions of their own get
+/// `None`, as do streams with audio tracks in several groups, since which rendition goes with
+/// which group is lost.
+pub fn master_playlist(
+    dir: &Path,
+    streams: &[Stream],
+    audio_tracks: &[AudioTrack],
+) -> io::Result<Option<String>> {
+    let mut renditions = streams.iter().filter(|s| s.quality > 0).collect::<Vec<_>>();
+
+    if renditions.is_empty() {
+        return Ok(None);
+    }
+
+    let group = audio_tracks.first().map(|t| t.group.as_str());
+    if audio_tracks.iter().any(|t| Some(t.group.as_str()) != group) {
+        return Ok(None);
+    }
+
+    renditions.sort_by(|a, b| b.quality.cmp(&a.quality));
+
+    let variants = match streams.iter().find(|s| s.quality == 0) {
+        Some(master) => match fs::read_to_string(dir.join(&master.playlist)) {
+            Ok(master) => parse_variants(&master),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        },
+        None => HashMap::new(),
+    };
+
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+
+    // Separate audio tracks add to the bandwidth of every rendition.
+    let (mut audio_peak, mut audio_average) = (0, 0);
+
+    for track in audio_tracks {
+        let language = track.language.as_ref().map(|l| format!(",LANGUAGE=\"{}\"", l)).unwrap_or_default();
+        let uri = match &track.uri {
+            Some(uri) => {
+                let (peak, average) = measure_bandwidth(&dir.join(uri))?;
+                audio_peak = audio_peak.max(peak);
+                audio_average = audio_average.max(average);
+
+                format!(",URI=\"{}\"", uri)
+            }
+            None => String::new(),
+        };
+
+        playlist.push_str(&format!(
+            "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"{}\",NAME=\"{}\"{},DEFAULT={},AUTOSELECT=YES{}\n",
+            track.group,
+            track.name,
+            language,
+            if track.default { "YES" } else { "NO" },
+            uri,
+        ));
+    }
+
+    let audio = group.map(|g| format!(",AUDIO=\"{}\"", g)).unwrap_or_default();
+
+    for rendition in renditions {
+        let (peak, average) = measure_bandwidth(&dir.join(&rendition.playlist))?;
+
+        let attribute = |name: &str| {
+            variants
+                .get(&rendition.playlist)
+                .and_then(|attributes| attributes.iter().find(|(n, _)| n == name))
+                .map(|(_, value)| value.clone())
+        };
+        let resolution = attribute("RESOLUTION").map(|r| format!(",RESOLUTION={}", r)).unwrap_or_default();
+        let codecs = attribute("CODECS").map(|c| format!(",CODECS=\"{}\"", c)).unwrap_or_default();
+
+        playlist.push_str(&format!(
+            "#EXT-X-STREAM-INF:BANDWIDTH={},AVERAGE-BANDWIDTH={}{}{}{}\n{}\n",
+            (peak + audio_peak).max(1),
+            (average + audio_average).max(1),
+            resolution,
+            codecs,
+            audio,
+            rendition.playlist,
+        ));
+    }
+
+    Ok(Some(playlist))
+}
+
+/// Master playlists made by `master_playlist`, by the directory of the stream. Media doesn't
+/// change once it is in the media directory, and measuring its renditions reads the size of
+/// every segment, so each is only made once.
+#[derive(Clone, Default)]
+pub struct MasterPlaylists(Arc<Mutex<HashMap<PathBuf, Option<String>>>>);
+
+impl MasterPlaylists {
+    /// The master playlist of the stream in `dir`, made on the first call. Failures aren't kept,
+    /// the next call tries again.
+    pub fn get(&self, dir: &Path, streams: &[Stream], audio_tracks: &[AudioTrack]) -> io::Result<Option<String>> {
+        if let Some(playlist) = self.0.lock().unwrap().get(dir) {
+            return Ok(playlist.clone());
+        }
+
+        let playlist = master_playlist(dir, streams, audio_tracks)?;
+        self.0.lock().unwrap().insert(dir.to_owned(), playlist.clone());
+
+        Ok(playlist)
+    }
+}
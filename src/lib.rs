@@ -0,0 +1,40 @@
+pub mod protocol;
+pub mod codec;
+pub mod clock;
+pub mod rate_limit;
+pub mod validation;
+pub mod metrics;
+pub mod audit;
+pub mod recording;
+pub mod shutdown;
+pub mod cluster;
+pub mod library;
+pub mod transcode;
+pub mod hls;
+pub mod subtitles;
+pub mod trickplay;
+pub mod signing;
+pub mod proxy;
+pub mod upload;
+pub mod telemetry;
+pub mod schema;
+pub mod client;
+pub mod server;
+
+pub mod actors {
+    mod room;
+    mod room_repository;
+    mod participant;
+    mod websocket_transport;
+    mod websocket_forwarder;
+    mod transcoder;
+
+    pub use self::{
+        room_repository::*,
+        room::*,
+        participant::*,
+        websocket_transport::*,
+        websocket_forwarder::*,
+        transcoder::*,
+    };
+}
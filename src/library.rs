@@ -0,0 +1,99 @@
+//! The media library: every stream rooms can be created with, kept in a JSON file.
+
+use tracing::{info, warn};
+
+use crate::actors::MediaStream;
+use crate::hls;
+use crate::subtitles;
+use crate::trickplay;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// A library shared by every holder of a clone of it.
+#[derive(Clone)]
+pub struct MediaLibrary {
+    path: PathBuf,
+    media_dir: PathBuf,
+    streams: Arc<Mutex<Vec<MediaStream>>>,
+}
+
+/// Fills in the audio tracks of `stream` from its master playlist, the subtitle sidecars next to
+/// it and its trickplay thumbnails, with the HLS data of the stream below `media_dir`.
+pub fn discover_tracks(media_dir: &Path, stream: &mut MediaStream) {
+    let dir = media_dir.join(&stream.slug);
+
+    match hls::discover_audio_tracks(&dir, &stream.streams) {
+        Ok(tracks) => stream.audio_tracks = tracks,
+        Err(e) => warn!(slug = %stream.slug, error = ?e, "failed to read audio tracks"),
+    }
+
+    match subtitles::discover(&dir) {
+        Ok(tracks) => stream.subtitles = tracks,
+        Err(e) => warn!(slug = %stream.slug, error = ?e, "failed to look for subtitles"),
+    }
+
+    stream.thumbnails = Some(String::from(trickplay::THUMBNAIL_INDEX)).filter(|index| dir.join(index).is_file());
+}
+
+impl MediaLibrary {
+    /// Opens the library kept in `path`, starting out empty if there is none yet. The HLS data
+    /// of every stream lives below `media_dir`.
+    pub fn open(path: impl Into<PathBuf>, media_dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let media_dir = media_dir.into();
+
+        let mut streams: Vec<MediaStream> = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        // Sidecars may have been dropped in or removed while the server was down.
+        for stream in &mut streams {
+            discover_tracks(&media_dir, stream);
+        }
+
+        Ok(Self { path, media_dir, streams: Arc::new(Mutex::new(streams)) })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn streams(&self) -> Vec<MediaStream> {
+        self.streams.lock().unwrap().clone()
+    }
+
+    pub fn find(&self, slug: &str) -> Option<MediaStream> {
+        self.streams.lock().unwrap().iter().find(|s| s.slug == slug).cloned()
+    }
+
+    /// Adds `stream` to the library, replacing a stream with the same slug.
+    pub fn add(&self, mut stream: MediaStream) -> io::Result<()> {
+        discover_tracks(&self.media_dir, &mut stream);
+
+        let mut streams = self.streams.lock().unwrap();
+        info!(slug = %stream.slug, name = %stream.name, "adding media to library");
+
+        match streams.iter_mut().find(|s| s.slug == stream.slug) {
+            Some(existing) => *existing = stream,
+            None => streams.push(stream),
+        }
+
+        self.save(&streams)
+    }
+
+    fn save(&self, streams: &[MediaStream]) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        // Same as the saved rooms, never leave a truncated library behind.
+        let tmp = self.path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(streams)?)?;
+        fs::rename(tmp, &self.path)
+    }
+}
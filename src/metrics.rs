@@ -0,0 +1,104 @@
+//! Prometheus metrics, exported on `/metrics`.
+
+use lazy_static::lazy_static;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+lazy_static! {
+    pub static ref REGISTRY: Registry = Registry::new();
+
+    pub static ref ROOMS: IntGauge =
+        IntGauge::new("tmtusync_rooms", "Number of registered rooms").unwrap();
+
+    pub static ref PARTICIPANTS: IntGaugeVec = IntGaugeVec::new(
+        Opts::new("tmtusync_room_participants", "Number of participants in a room"),
+        &["room"],
+    ).unwrap();
+
+    pub static ref WEBSOCKET_CONNECTS: IntCounter = IntCounter::new(
+        "tmtusync_websocket_connects_total",
+        "Websocket connections opened",
+    ).unwrap();
+
+    pub static ref WEBSOCKET_DISCONNECTS: IntCounter = IntCounter::new(
+        "tmtusync_websocket_disconnects_total",
+        "Websocket connections closed",
+    ).unwrap();
+
+    pub static ref MESSAGES_RECEIVED: IntCounterVec = IntCounterVec::new(
+        Opts::new("tmtusync_messages_received_total", "Messages received from participants"),
+        &["kind"],
+    ).unwrap();
+
+    pub static ref MESSAGES_SENT: IntCounterVec = IntCounterVec::new(
+        Opts::new("tmtusync_messages_sent_total", "Messages sent to participants"),
+        &["kind"],
+    ).unwrap();
+
+    pub static ref RTT: HistogramVec = HistogramVec::new(
+        HistogramOpts::new("tmtusync_participant_rtt_seconds", "Round trip time of participant pings")
+            .buckets(vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]),
+        &["room"],
+    ).unwrap();
+
+    pub static ref DRIFT: HistogramVec = HistogramVec::new(
+        HistogramOpts::new(
+            "tmtusync_participant_drift_seconds",
+            "How far participants are from the room's position",
+        )
+            .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0]),
+        &["room"],
+    ).unwrap();
+
+    pub static ref HLS_REQUESTS: IntCounterVec = IntCounterVec::new(
+        Opts::new("tmtusync_hls_requests_total", "Requests for HLS media"),
+        &["kind"],
+    ).unwrap();
+}
+
+/// Registers every metric. Must be called once before the metrics are served.
+pub fn register() {
+    let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+        Box::new(ROOMS.clone()),
+        Box::new(PARTICIPANTS.clone()),
+        Box::new(WEBSOCKET_CONNECTS.clone()),
+        Box::new(WEBSOCKET_DISCONNECTS.clone()),
+        Box::new(MESSAGES_RECEIVED.clone()),
+        Box::new(MESSAGES_SENT.clone()),
+        Box::new(RTT.clone()),
+        Box::new(DRIFT.clone()),
+        Box::new(HLS_REQUESTS.clone()),
+    ];
+
+    for collector in collectors {
+        if let Err(e) = REGISTRY.register(collector) {
+            tracing::warn!("Failed to register metric: {:?}", e);
+        }
+    }
+}
+
+/// Counts a request for HLS media by the kind of file asked for.
+pub fn record_hls_request(path: &str) {
+    let kind = if path.ends_with(".m3u8") {
+        "playlist"
+    } else if path.ends_with(".ts") || path.ends_with(".m4s") || path.ends_with(".mp4") {
+        "segment"
+    } else {
+        "other"
+    };
+
+    HLS_REQUESTS.with_label_values(&[kind]).inc();
+}
+
+/// Renders all metrics in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        tracing::error!("Failed to encode metrics: {:?}", e);
+    }
+
+    String::from_utf8(buffer).unwrap_or_default()
+}
@@ -1,14 +1,13 @@
-use actix::{Addr, Message};
+use actix::{Message, Recipient};
 
 use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
 use chrono::{DateTime, Utc};
 
 use std::time::{Duration, Instant};
 use std::ops::Deref;
 use std::fmt;
 
-use crate::WebsocketTransport;
-
 pub struct BadgeData {
     pub name: &'static str,
     pub tooltip: &'static str,
@@ -69,8 +68,38 @@ pub mod badges {
     ];
 }
 
+/// The version of the protocol spoken by this server. Bumped whenever `UserMessage` or
+/// `ToSessionMessage` change in a way older clients can't parse.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// The oldest client protocol version the server still accepts.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Optional protocol features which are negotiated during the handshake. A feature is only used
+/// if both the client and the server list it.
+pub mod capabilities {
+    /// The server reports its clock mapping for the participant in `ToSessionMessage::Timing`.
+    pub const TIMING: &str = "timing";
+
+    /// The server suggests lower renditions in `ToSessionMessage::SuggestQuality` when the
+    /// participant's buffer keeps running low.
+    pub const QUALITY: &str = "quality";
+
+    /// All features enabled on this server.
+    pub const SERVER: &[&str] = &[TIMING, QUALITY];
+
+    /// Returns the features listed by the client which are also enabled on the server.
+    pub fn negotiate(requested: &[String]) -> Vec<String> {
+        requested
+            .iter()
+            .filter(|f| SERVER.contains(&f.as_str()))
+            .cloned()
+            .collect()
+    }
+}
+
 /// Identifier for a badge, used to display some status for a user.
-#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Copy, Clone, Eq, PartialEq)]
 pub struct BadgeId(pub u32);
 
 impl fmt::Display for BadgeId {
@@ -80,14 +109,20 @@ impl fmt::Display for BadgeId {
 }
 
 /// Unique identifier for a user. Handed out by the server.
-#[derive(Serialize, Deserialize, Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub struct UserId(pub u32);
 
 /// Milliseconds since UNIX time epoch.
-#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Copy, Clone)]
 pub struct Time(pub i64);
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Time {
+    pub fn now() -> Self {
+        Time(Utc::now().timestamp_millis())
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct ParticipantInfo {
     pub user_id: UserId,
     pub name: String,
@@ -95,16 +130,22 @@ pub struct ParticipantInfo {
     pub badges: Vec<BadgeId>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct ParticipantUpdate {
     pub user_id: UserId,
     pub duration: f32,
     pub buffered: f32,
     pub state: PlayState,
     pub badges: Vec<BadgeId>,
+    /// The `Stream::quality` the participant is playing, if known.
+    pub quality: Option<u32>,
+    /// Whether the participant's player picks the quality on its own.
+    pub auto_quality: bool,
+    /// The `AudioTrack::name` the participant is listening to, if known.
+    pub audio: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct NewParticipant {
     user_id: UserId,
     name: String,
@@ -112,33 +153,92 @@ pub struct NewParticipant {
     badges: Vec<BadgeId>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct ChatMessage {
     from: UserId,
     msg: String,
 }
 
 /// Info about a media stream, containing a sortable quality number and the file name of the HLS
-/// playlist.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// playlist. A quality of 0 marks a master playlist, any other is a single rendition.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Stream {
     pub quality: u32,
     pub playlist: String,
 }
 
+/// A subtitle track of a media stream, served as WebVTT.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct SubtitleTrack {
+    /// The file name of the track next to the stream's HLS data.
+    pub id: String,
+    pub label: String,
+    /// Language code taken from the file name, like `en` in `movie.en.srt`.
+    pub language: Option<String>,
+}
+
+/// An audio rendition of a media stream, announced with `#EXT-X-MEDIA` in its master playlist.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct AudioTrack {
+    /// The `GROUP-ID` the track belongs to.
+    pub group: String,
+    /// Unique within the group, this is what participants pick tracks by.
+    pub name: String,
+    pub language: Option<String>,
+    /// Whether players start out with this track.
+    pub default: bool,
+    /// Playlist of the track relative to the master playlist, `None` when the audio is muxed into
+    /// the video renditions.
+    pub uri: Option<String>,
+}
+
 /// Info about a media stream, containing the directory slug for the data and a list of all
 /// available HLS playlists.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct StreamInfo {
     pub slug: String,
     pub name: String,
     pub streams: Vec<Stream>,
     pub duration: f32,
     pub state: PlayState,
+    pub audio_tracks: Vec<AudioTrack>,
+    pub subtitles: Vec<SubtitleTrack>,
+    /// WebVTT index of thumbnails to show when hovering the timeline, relative to the directory
+    /// of the stream.
+    pub thumbnails: Option<String>,
+    /// The track participants start out with, `None` for no subtitles.
+    pub subtitle: Option<String>,
+    /// Seconds every subtitle cue is shifted by.
+    pub subtitle_offset: f32,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Why the server refused a client's handshake.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub enum RejectReason {
+    /// The client speaks a protocol version outside of what the server supports.
+    UnsupportedVersion,
+    /// The client sent a message before saying `Hello`.
+    HandshakeRequired,
+    /// The client said `Hello` more than once.
+    DuplicateHello,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub enum ToSessionMessage {
+    /// Reply to a successful `Hello`, listing the server's protocol version and the features
+    /// both sides agreed on.
+    Welcome {
+        version: u32,
+        capabilities: Vec<String>,
+    },
+
+    /// Reply to a failed handshake. The connection is closed after this message.
+    Rejected {
+        reason: RejectReason,
+        version: u32,
+        min_version: u32,
+    },
+
     /// Initial payload describing how the room looks like
     RoomState {
         user_id: UserId,
@@ -170,26 +270,124 @@ pub enum ToSessionMessage {
         user: UserId,
         duration: f32,
     },
+    /// Someone changed the room's default subtitle track or its offset.
+    SubtitlesChanged {
+        user: UserId,
+        subtitle: Option<String>,
+        offset: f32,
+    },
 
     Ping,
 
+    /// How the server maps the participant's clock, sent after every answered ping when the
+    /// `timing` capability is enabled. Both values are in milliseconds.
+    Timing {
+        /// Time between the server sending a ping and receiving the reply.
+        rtt: f32,
+        /// Server time minus the client's timestamp on the reply.
+        offset: f32,
+    },
+
     ChatMessage(ChatMessage),
 
-    Error(String),
+    /// The participant is sending messages of this kind too fast. Depending on the penalty the
+    /// message was still passed on, dropped, or the connection is about to be closed.
+    RateLimited {
+        kind: String,
+        penalty: RateLimitPenalty,
+    },
+
+    Error(ProtocolError),
+
+    /// The server is shutting down and has paused the room at `duration`. The connection is
+    /// closed after this message, reconnect to continue where the room left off.
+    Restarting {
+        duration: f32,
+    },
+
+    /// The participant's buffer keeps running low, switching to the rendition with this
+    /// `Stream::quality` might help. Only sent when the `quality` capability is enabled and the
+    /// participant picked its quality by hand.
+    SuggestQuality {
+        quality: u32,
+    },
+}
+
+impl ToSessionMessage {
+    /// The name of the variant, for logging and statistics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ToSessionMessage::Welcome { .. } => "Welcome",
+            ToSessionMessage::Rejected { .. } => "Rejected",
+            ToSessionMessage::RoomState { .. } => "RoomState",
+            ToSessionMessage::RoomUpdate { .. } => "RoomUpdate",
+            ToSessionMessage::NewParticipant { .. } => "NewParticipant",
+            ToSessionMessage::ByeParticipant { .. } => "ByeParticipant",
+            ToSessionMessage::NewStream(_) => "NewStream",
+            ToSessionMessage::SetState { .. } => "SetState",
+            ToSessionMessage::DoSeek { .. } => "DoSeek",
+            ToSessionMessage::SubtitlesChanged { .. } => "SubtitlesChanged",
+            ToSessionMessage::Ping => "Ping",
+            ToSessionMessage::Timing { .. } => "Timing",
+            ToSessionMessage::ChatMessage(_) => "ChatMessage",
+            ToSessionMessage::RateLimited { .. } => "RateLimited",
+            ToSessionMessage::Error(_) => "Error",
+            ToSessionMessage::Restarting { .. } => "Restarting",
+            ToSessionMessage::SuggestQuality { .. } => "SuggestQuality",
+        }
+    }
+}
+
+/// Why a message from the participant was refused.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub enum ProtocolError {
+    /// The message could not be parsed.
+    Malformed { details: String },
+    /// The websocket frame was larger than the server accepts.
+    FrameTooLarge { max: usize },
+    /// A number was NaN, infinite or negative.
+    InvalidNumber { field: String },
+    /// A timestamp was too far off the server's clock to be believable.
+    InvalidTime { field: String },
+    /// The name was empty or too long.
+    InvalidName { max_length: usize },
+}
+
+/// What happens to a participant that exceeds a rate limit.
+#[derive(Serialize, Deserialize, JsonSchema, Eq, PartialEq, Debug, Copy, Clone)]
+pub enum RateLimitPenalty {
+    /// Log and tell the participant, but still handle the message.
+    Warn,
+    /// Drop the message.
+    Ignore,
+    /// Close the connection.
+    Disconnect,
 }
 
 /// The state the media player can be in.
-#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Copy, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Eq, PartialEq, Debug, Copy, Clone)]
 pub enum PlayState {
     Play,
     Pause,
 }
 
 /// A message sent by the user to the server.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub enum UserMessage {
     /// First message sent by a user, indicating their name.
-    Hello { name: String, avatar: BadgeId, time: Time },
+    Hello {
+        name: String,
+        avatar: BadgeId,
+        time: Time,
+
+        /// The protocol version spoken by the client. Clients predating the handshake don't send
+        /// this and end up as version 0.
+        #[serde(default)]
+        version: u32,
+        /// Optional features the client would like to use.
+        #[serde(default)]
+        features: Vec<String>,
+    },
 
     /// User has left the room.
     Goodbye,
@@ -213,6 +411,16 @@ pub enum UserMessage {
 
         /// Time when the state was sent by the user.
         time: Time,
+
+        /// The `Stream::quality` of the rendition being played, if known.
+        #[serde(default)]
+        quality: Option<u32>,
+        /// Whether the player picks the rendition on its own.
+        #[serde(default)]
+        auto_quality: bool,
+        /// The `AudioTrack::name` of the track being played, if known.
+        #[serde(default)]
+        audio: Option<String>,
     },
 
     /// A user request to seek in the current media.
@@ -230,6 +438,28 @@ pub enum UserMessage {
         /// The time when the message was sent by the user.
         time: Time,
     },
+
+    /// A user request to set the room's default subtitle track and the offset of every track.
+    SetSubtitles {
+        /// A `SubtitleTrack::id` of the current media, `None` for no subtitles.
+        subtitle: Option<String>,
+        /// Seconds to shift every cue by, negative to show them earlier.
+        offset: f32,
+    },
+}
+
+impl UserMessage {
+    /// The name of the variant, for logging and statistics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            UserMessage::Hello { .. } => "Hello",
+            UserMessage::Goodbye => "Goodbye",
+            UserMessage::State { .. } => "State",
+            UserMessage::Seek { .. } => "Seek",
+            UserMessage::SetState { .. } => "SetState",
+            UserMessage::SetSubtitles { .. } => "SetSubtitles",
+        }
+    }
 }
 
 pub struct ClientMessage {
@@ -239,8 +469,8 @@ pub struct ClientMessage {
     /// User cookie.
     pub cookie: String,
 
-    /// The actor address that we can send replies to.
-    pub addr: Addr<WebsocketTransport>,
+    /// Where replies to the participant go, usually its websocket transport.
+    pub addr: Recipient<ToSessionMessage>,
 
     /// The actual message.
     pub message: UserMessage,
@@ -0,0 +1,438 @@
+//! Playing HLS hosted elsewhere through this server. Playlists of remote streams are fetched and
+//! rewritten so the URIs on the stream's host point back at the proxy, everything else is passed
+//! through as it comes in. Responses are cached for a while and requests for the same URL share
+//! one fetch, so a room full of participants fetches each of them once, and players never talk
+//! to the remote host, which might not allow them to with CORS. Hosts on private addresses are
+//! refused, the proxy is no way into the server's own network.
+
+use actix_web::{http::header, web::{self, Bytes, BytesMut}};
+use chrono::{DateTime, Duration, Utc};
+use futures::{channel::{mpsc, oneshot}, Stream, StreamExt};
+use thiserror::Error;
+use tracing::{debug, warn};
+use url::{Host, Url};
+
+use crate::clock::SharedClock;
+use crate::hls;
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+#[derive(Error, Debug, Clone)]
+pub enum ProxyError {
+    #[error("invalid url: {0}")]
+    InvalidUrl(String),
+    #[error("{0} is on a private address")]
+    PrivateAddress(String),
+    #[error("request failed: {0}")]
+    Request(String),
+    #[error("remote responded with {0}")]
+    Status(u16),
+    #[error("response larger than {0} bytes")]
+    TooLarge(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// How long playlists are cached, short enough to follow live streams.
+    pub playlist_ttl: Duration,
+    /// How long everything else is cached.
+    pub segment_ttl: Duration,
+    /// Bytes of responses cached at most.
+    pub max_cache_size: usize,
+    /// Largest response passed through, in bytes.
+    pub max_response_size: usize,
+    /// How long the remote host may take to respond, and to send each part of the body.
+    pub timeout: std::time::Duration,
+    /// Hosts besides the one a stream comes from that its playlists may point the proxy at, like
+    /// the CDN its segments are on.
+    pub allowed_hosts: Vec<String>,
+    /// Whether hosts on loopback, link-local or private addresses may be fetched from. They may
+    /// not by default, so rooms can't reach into the network the server is in.
+    pub allow_private: bool,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            playlist_ttl: Duration::seconds(2),
+            segment_ttl: Duration::minutes(10),
+            max_cache_size: 512 << 20,
+            max_response_size: 64 << 20,
+            timeout: std::time::Duration::from_secs(20),
+            allowed_hosts: Vec::new(),
+            allow_private: false,
+        }
+    }
+}
+
+impl ProxyConfig {
+    /// The default configuration, with the cache size overridden by `TMTUSYNC_PROXY_CACHE_SIZE`
+    /// in bytes, the allowed hosts by the comma separated `TMTUSYNC_PROXY_ALLOWED_HOSTS` and
+    /// private addresses allowed with `TMTUSYNC_PROXY_ALLOW_PRIVATE=1`.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+
+        if let Some(bytes) = std::env::var("TMTUSYNC_PROXY_CACHE_SIZE").ok().and_then(|b| b.parse().ok()) {
+            config.max_cache_size = bytes;
+        }
+        if let Ok(hosts) = std::env::var("TMTUSYNC_PROXY_ALLOWED_HOSTS") {
+            config.allowed_hosts = hosts.split(',').map(str::trim).filter(|h| !h.is_empty()).map(String::from).collect();
+        }
+        if let Ok(allow) = std::env::var("TMTUSYNC_PROXY_ALLOW_PRIVATE") {
+            match allow.as_str() {
+                "1" | "true" => config.allow_private = true,
+                "0" | "false" => config.allow_private = false,
+                _ => warn!("Ignoring invalid TMTUSYNC_PROXY_ALLOW_PRIVATE={:?}, expected 1 or 0", allow),
+            }
+        }
+
+        config
+    }
+}
+
+/// What the remote host responded with, the body passed on as it comes in.
+pub struct ProxyResponse {
+    pub content_type: Option<String>,
+    pub body: ProxyBody,
+}
+
+impl ProxyResponse {
+    /// Whether the response to `url` is a playlist, going by its content type or extension.
+    pub fn is_playlist(&self, url: &Url) -> bool {
+        self.content_type.as_deref().map_or(false, |t| t.to_ascii_lowercase().contains("mpegurl"))
+            || url.path().to_ascii_lowercase().ends_with(".m3u8")
+    }
+}
+
+/// The body of a response, ending with an error if the remote host fails to send all of it.
+pub struct ProxyBody(mpsc::UnboundedReceiver<Result<Bytes, ProxyError>>);
+
+impl ProxyBody {
+    fn complete(body: Bytes) -> Self {
+        let (sender, receiver) = mpsc::unbounded();
+        let _ = sender.unbounded_send(Ok(body));
+
+        Self(receiver)
+    }
+
+    /// The whole body, for the responses that have to be looked at, like playlists.
+    pub async fn bytes(mut self) -> Result<Bytes, ProxyError> {
+        let mut body = BytesMut::new();
+        while let Some(chunk) = self.0.next().await {
+            body.extend_from_slice(&chunk?);
+        }
+
+        Ok(body.freeze())
+    }
+}
+
+impl Stream for ProxyBody {
+    type Item = Result<Bytes, ProxyError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0).poll_next(cx)
+    }
+}
+
+struct CacheEntry {
+    content_type: Option<String>,
+    body: Bytes,
+    fetched: DateTime<Utc>,
+    expires: DateTime<Utc>,
+}
+
+/// A response still coming in. Everyone asking for it meanwhile gets what arrived so far and
+/// then the rest as it arrives, so each URL is only fetched once at a time.
+#[derive(Default)]
+struct InFlight {
+    /// The content type, once the remote host responded.
+    head: Option<Result<Option<String>, ProxyError>>,
+    chunks: Vec<Bytes>,
+    size: usize,
+    waiting: Vec<oneshot::Sender<Result<Option<String>, ProxyError>>>,
+    bodies: Vec<mpsc::UnboundedSender<Result<Bytes, ProxyError>>>,
+}
+
+impl InFlight {
+    fn subscribe(&mut self) -> (oneshot::Receiver<Result<Option<String>, ProxyError>>, ProxyBody) {
+        let (head, head_receiver) = oneshot::channel();
+        match &self.head {
+            Some(result) => { let _ = head.send(result.clone()); }
+            None => self.waiting.push(head),
+        }
+
+        let (body, body_receiver) = mpsc::unbounded();
+        for chunk in &self.chunks {
+            let _ = body.unbounded_send(Ok(chunk.clone()));
+        }
+        self.bodies.push(body);
+
+        (head_receiver, ProxyBody(body_receiver))
+    }
+
+    fn respond(&mut self, result: Result<Option<String>, ProxyError>) {
+        for head in self.waiting.drain(..) {
+            let _ = head.send(result.clone());
+        }
+        self.head = Some(result);
+    }
+
+    fn push(&mut self, chunk: Bytes) {
+        self.bodies.retain(|body| body.unbounded_send(Ok(chunk.clone())).is_ok());
+        self.size += chunk.len();
+        self.chunks.push(chunk);
+    }
+
+    fn fail(mut self, error: ProxyError) {
+        if self.head.is_none() {
+            self.respond(Err(error));
+        } else {
+            for body in self.bodies {
+                let _ = body.unbounded_send(Err(error.clone()));
+            }
+        }
+    }
+}
+
+/// A proxy shared by every holder of a clone of it.
+#[derive(Clone)]
+pub struct HlsProxy {
+    config: ProxyConfig,
+    clock: SharedClock,
+    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
+    in_flight: Arc<Mutex<HashMap<String, InFlight>>>,
+}
+
+impl HlsProxy {
+    pub fn new(config: ProxyConfig, clock: SharedClock) -> Self {
+        Self {
+            config,
+            clock,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Whether playlists of a stream from `source` may send the proxy to `url`: only within the
+    /// origin of the stream, or to one of the allowed hosts.
+    pub fn allows(&self, source: &Url, url: &Url) -> bool {
+        url.origin() == source.origin()
+            || url.host_str().map_or(false, |host| {
+                self.config.allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host))
+            })
+    }
+
+    /// Fetches `url`, from the cache if it was fetched recently enough. Requests for a URL that
+    /// is being fetched already share that fetch.
+    pub async fn fetch(&self, url: &str) -> Result<ProxyResponse, ProxyError> {
+        let (head, body) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+
+            // Looked up while holding the lock, so a fetch finishing in between can't be missed.
+            if let Some((content_type, body)) = self.cached(url) {
+                return Ok(ProxyResponse { content_type, body: ProxyBody::complete(body) });
+            }
+
+            match in_flight.get_mut(url) {
+                Some(fetch) => fetch.subscribe(),
+                None => {
+                    let mut fetch = InFlight::default();
+                    let subscription = fetch.subscribe();
+                    in_flight.insert(url.to_string(), fetch);
+                    actix_rt::spawn(self.clone().fetch_remote(url.to_string()));
+
+                    subscription
+                }
+            }
+        };
+
+        let content_type = head.await.unwrap_or_else(|_| Err(ProxyError::Request(String::from("fetch canceled"))))?;
+
+        Ok(ProxyResponse { content_type, body })
+    }
+
+    fn cached(&self, url: &str) -> Option<(Option<String>, Bytes)> {
+        let cache = self.cache.lock().unwrap();
+
+        cache.get(url).filter(|e| e.expires > self.clock.now()).map(|e| (e.content_type.clone(), e.body.clone()))
+    }
+
+    /// Fetches `url` for everyone waiting on it, caching it once it is complete.
+    async fn fetch_remote(self, url: String) {
+        let result = self.stream_remote(&url).await;
+
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let fetch = match in_flight.remove(&url) {
+            Some(fetch) => fetch,
+            None => return,
+        };
+
+        match result {
+            Ok(()) => {
+                let mut body = BytesMut::with_capacity(fetch.size);
+                for chunk in &fetch.chunks {
+                    body.extend_from_slice(chunk);
+                }
+
+                let content_type = fetch.head.and_then(Result::ok).flatten();
+                self.store(&url, content_type, body.freeze());
+            }
+            Err(e) => {
+                debug!(%url, error = %e, "failed to fetch remote media");
+                fetch.fail(e);
+            }
+        }
+    }
+
+    async fn stream_remote(&self, url: &str) -> Result<(), ProxyError> {
+        let parsed = Url::parse(url).map_err(|_| ProxyError::InvalidUrl(url.to_string()))?;
+        if !matches!(parsed.scheme(), "http" | "https") {
+            return Err(ProxyError::InvalidUrl(url.to_string()));
+        }
+
+        let address = self.resolve(&parsed).await?;
+
+        debug!(%url, %address, "fetching remote media");
+
+        // Connecting to the address that was checked, the host could resolve to another one by now.
+        let client = awc::Client::builder().timeout(self.config.timeout).finish();
+        let mut response = client
+            .get(url)
+            .address(address)
+            .send()
+            .await
+            .map_err(|e| ProxyError::Request(e.to_string()))?;
+
+        if !response.status().is_success() {
+            return Err(ProxyError::Status(response.status().as_u16()));
+        }
+
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok())
+            .map(String::from);
+        self.update(url, |fetch| fetch.respond(Ok(content_type)));
+
+        let mut size = 0;
+        loop {
+            let chunk = match tokio::time::timeout(self.config.timeout, response.next()).await {
+                Ok(Some(chunk)) => chunk.map_err(|e| ProxyError::Request(e.to_string()))?,
+                Ok(None) => return Ok(()),
+                Err(_) => return Err(ProxyError::Request(String::from("timed out"))),
+            };
+
+            size += chunk.len();
+            if size > self.config.max_response_size {
+                return Err(ProxyError::TooLarge(self.config.max_response_size));
+            }
+
+            self.update(url, |fetch| fetch.push(chunk));
+        }
+    }
+
+    fn update(&self, url: &str, f: impl FnOnce(&mut InFlight)) {
+        if let Some(fetch) = self.in_flight.lock().unwrap().get_mut(url) {
+            f(fetch);
+        }
+    }
+
+    /// The address to fetch `url` from, refusing private ones unless they are allowed.
+    async fn resolve(&self, url: &Url) -> Result<SocketAddr, ProxyError> {
+        let port = url.port_or_known_default().ok_or_else(|| ProxyError::InvalidUrl(url.to_string()))?;
+        let addresses = match url.host() {
+            Some(Host::Ipv4(ip)) => vec![SocketAddr::new(ip.into(), port)],
+            Some(Host::Ipv6(ip)) => vec![SocketAddr::new(ip.into(), port)],
+            Some(Host::Domain(domain)) => {
+                let domain = domain.to_string();
+                web::block(move || (domain.as_str(), port).to_socket_addrs().map(|a| a.collect::<Vec<_>>()))
+                    .await
+                    .map_err(|e| ProxyError::Request(e.to_string()))?
+            }
+            None => return Err(ProxyError::InvalidUrl(url.to_string())),
+        };
+
+        // Every address counts, a host is refused if any of them is private.
+        if !self.config.allow_private && addresses.iter().any(|a| is_private(a.ip())) {
+            return Err(ProxyError::PrivateAddress(url.host_str().unwrap_or_default().to_string()));
+        }
+
+        addresses.into_iter().next().ok_or_else(|| ProxyError::Request(format!("{} has no address", url)))
+    }
+
+    fn store(&self, url: &str, content_type: Option<String>, body: Bytes) {
+        let size = body.len();
+        if size > self.config.max_cache_size {
+            return;
+        }
+
+        let now = self.clock.now();
+        let is_playlist = content_type.as_deref().map_or(false, |t| t.to_ascii_lowercase().contains("mpegurl"))
+            || body.starts_with(b"#EXTM3U");
+        let ttl = if is_playlist { self.config.playlist_ttl } else { self.config.segment_ttl };
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, e| e.expires > now);
+
+        // The oldest responses go first once the cache is full.
+        let mut cached = cache.values().map(|e| e.body.len()).sum::<usize>();
+        while cached + size > self.config.max_cache_size {
+            let oldest = match cache.iter().min_by_key(|(_, e)| e.fetched) {
+                Some((url, _)) => url.clone(),
+                None => break,
+            };
+
+            if let Some(entry) = cache.remove(&oldest) {
+                cached -= entry.body.len();
+            }
+        }
+
+        cache.insert(url.to_string(), CacheEntry { content_type, body, fetched: now, expires: now + ttl });
+    }
+}
+
+/// Whether `ip` is in the server's own or a private network rather than on the internet:
+/// loopback, link-local (where cloud metadata services live), private and shared ranges.
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4() {
+                return is_private_v4(ip);
+            }
+
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || first & 0xfe00 == 0xfc00 // unique local
+                || first & 0xffc0 == 0xfe80 // link-local
+        }
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || a == 0
+        || (a == 100 && b & 0xc0 == 64) // shared address space
+}
+
+/// `playlist`, fetched from `base`, with every URI made absolute and passed to `map`. URIs that
+/// aren't HTTP, like `data:` ones, are left alone.
+pub fn rewrite_playlist(playlist: &str, base: &Url, mut map: impl FnMut(&Url) -> String) -> String {
+    hls::rewrite_uris(playlist, |uri| match base.join(uri) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => map(&url),
+        _ => uri.to_string(),
+    })
+}
@@ -0,0 +1,48 @@
+//! Token buckets used to limit how fast a participant may send messages.
+
+use chrono::{DateTime, Utc};
+
+/// Allows bursts of up to `burst` messages, refilled at `per_second` messages per second.
+#[derive(Debug, Copy, Clone)]
+pub struct RateLimit {
+    pub burst: f32,
+    pub per_second: f32,
+}
+
+impl RateLimit {
+    pub const fn new(burst: f32, per_second: f32) -> Self {
+        Self { burst, per_second }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f32,
+    updated: DateTime<Utc>,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit, now: DateTime<Utc>) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            updated: now,
+        }
+    }
+
+    /// Takes a token if there is one. Returns `false` if the limit has been exceeded.
+    pub fn try_take(&mut self, now: DateTime<Utc>) -> bool {
+        let elapsed = (now - self.updated).num_milliseconds().max(0) as f32 / 1000.0;
+
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,347 @@
+//! Opt-in recordings of everything entering and leaving a room, and a replay that feeds a
+//! recording back into a fresh room under a `MockClock` to reproduce desync reports exactly.
+//!
+//! A recording is a file of JSON lines, starting with `RecordedEvent::Start`.
+
+use actix::{Actor, Context, Handler, Recipient};
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{error, warn};
+
+use crate::actors::{GetRoomMeta, GetUserId, MediaSource, MediaStream, Room, StreamMetadata};
+use crate::clock::{Clock, MockClock};
+use crate::protocol::{AudioTrack, ClientMessage, ServerTime, Stream, SubtitleTrack, Time, ToSessionMessage, UserId, UserMessage};
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// The parts of a `MediaStream` needed to recreate the room.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordedStream {
+    pub slug: String,
+    pub name: String,
+    pub streams: Vec<Stream>,
+    pub length: Option<f32>,
+    #[serde(default)]
+    pub audio_tracks: Vec<AudioTrack>,
+    #[serde(default)]
+    pub subtitles: Vec<SubtitleTrack>,
+    #[serde(default)]
+    pub thumbnails: Option<String>,
+    #[serde(default)]
+    pub source: MediaSource,
+}
+
+impl From<&MediaStream> for RecordedStream {
+    fn from(stream: &MediaStream) -> Self {
+        Self {
+            slug: stream.slug.clone(),
+            name: stream.name.clone(),
+            streams: stream.streams.clone(),
+            length: stream.length,
+            audio_tracks: stream.audio_tracks.clone(),
+            subtitles: stream.subtitles.clone(),
+            thumbnails: stream.thumbnails.clone(),
+            source: stream.source.clone(),
+        }
+    }
+}
+
+impl From<RecordedStream> for MediaStream {
+    fn from(stream: RecordedStream) -> Self {
+        Self {
+            meta: StreamMetadata {
+                title: stream.name.clone(),
+                duration: String::new(),
+                imdb: None,
+            },
+            slug: stream.slug,
+            name: stream.name,
+            streams: stream.streams,
+            length: stream.length,
+            audio_tracks: stream.audio_tracks,
+            subtitles: stream.subtitles,
+            thumbnails: stream.thumbnails,
+            source: stream.source,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RecordedEvent {
+    /// The first line of every recording.
+    Start { room: String, time: Time, stream: Option<RecordedStream> },
+    /// The room handed out a new user id.
+    Identify { cookie: String, user: UserId },
+    /// A message from a participant, with the time it was received at.
+    Incoming { time: Time, user: UserId, cookie: String, message: UserMessage },
+    /// A message the room sent to a participant.
+    Outgoing { time: Time, user: UserId, message: ToSessionMessage },
+    /// A message the participant's transport answered on its own, eg. the handshake or a rate
+    /// limit. Replays leave these out, since a room alone doesn't send them.
+    Transport { time: Time, user: UserId, message: ToSessionMessage },
+}
+
+/// Appends the events of one room to a recording.
+pub struct Recorder {
+    path: PathBuf,
+    file: File,
+}
+
+impl Recorder {
+    /// Starts a new recording of `room` in `dir`, named after the room and the start time.
+    pub fn create(dir: &Path, room: &str, stream: Option<&MediaStream>, time: Time) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let path = dir.join(format!("{}-{}.jsonl", room, time.0));
+        let recorder = Self { file: File::create(&path)?, path };
+
+        recorder.write(&RecordedEvent::Start {
+            room: room.to_string(),
+            time,
+            stream: stream.map(RecordedStream::from),
+        });
+
+        Ok(recorder)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn identify(&self, cookie: &str, user: UserId) {
+        self.write(&RecordedEvent::Identify { cookie: cookie.to_string(), user });
+    }
+
+    pub fn incoming(&self, message: &ClientMessage) {
+        self.write(&RecordedEvent::Incoming {
+            time: Time(message.server_time.timestamp_millis()),
+            user: message.from,
+            cookie: message.cookie.clone(),
+            message: message.message.clone(),
+        });
+    }
+
+    pub fn outgoing(&self, user: UserId, message: &ToSessionMessage, time: Time) {
+        self.write(&RecordedEvent::Outgoing { time, user, message: message.clone() });
+    }
+
+    pub fn transport(&self, user: UserId, message: &ToSessionMessage, time: Time) {
+        self.write(&RecordedEvent::Transport { time, user, message: message.clone() });
+    }
+
+    fn write(&self, event: &RecordedEvent) {
+        let result = serde_json::to_vec(event)
+            .map_err(io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                (&self.file).write_all(&line)
+            });
+
+        if let Err(e) = result {
+            error!(path = %self.path.display(), error = ?e, "failed to write recording");
+        }
+    }
+}
+
+/// Reads a recording written by `Recorder`.
+pub fn load(path: &Path) -> io::Result<Vec<RecordedEvent>> {
+    let reader = BufReader::new(File::open(path)?);
+
+    reader
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// A message the replayed room sent differently than the recorded one.
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    pub user: UserId,
+    /// Position of the message among all messages sent to `user`.
+    pub index: usize,
+    pub expected: Option<ToSessionMessage>,
+    pub actual: Option<ToSessionMessage>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReplayReport {
+    /// Number of recorded outgoing messages compared.
+    pub messages: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl ReplayReport {
+    pub fn is_clean(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+type Outputs = Rc<RefCell<HashMap<UserId, Vec<ToSessionMessage>>>>;
+
+/// Stands in for a participant's transport, collecting everything the room sends it.
+struct Collector {
+    user: UserId,
+    outputs: Outputs,
+}
+
+impl Actor for Collector {
+    type Context = Context<Self>;
+}
+
+impl Handler<ToSessionMessage> for Collector {
+    type Result = anyhow::Result<()>;
+
+    fn handle(&mut self, msg: ToSessionMessage, _ctx: &mut Self::Context) -> Self::Result {
+        self.outputs.borrow_mut().entry(self.user).or_default().push(msg);
+        Ok(())
+    }
+}
+
+/// How long to wait for the room to catch up before comparing anyway.
+const SETTLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Lets timers woken by the clock fire and waits until the room has handled what they sent.
+/// Every participant has a ping loop, which goes back to sleep once the room has handled its
+/// ping.
+async fn settle(room: &actix::Addr<Room>, clock: &MockClock, participants: usize) {
+    let deadline = Instant::now() + SETTLE_TIMEOUT;
+
+    while clock.sleepers() < participants {
+        if Instant::now() > deadline {
+            warn!(sleeping = clock.sleepers(), participants, "ping loops didn't go back to sleep");
+            break;
+        }
+
+        tokio::time::delay_for(Duration::from_millis(1)).await;
+    }
+
+    let _ = room.send(GetRoomMeta).await;
+}
+
+/// Waits until the participants' transports have received as many messages as `expected`.
+async fn collected(outputs: &Outputs, expected: usize) {
+    let deadline = Instant::now() + SETTLE_TIMEOUT;
+    let received = || outputs.borrow().values().map(Vec::len).sum::<usize>();
+
+    while received() < expected && Instant::now() <= deadline {
+        tokio::time::delay_for(Duration::from_millis(1)).await;
+    }
+}
+
+/// Feeds a recording into a fresh room and compares what it sends with what was recorded. Has
+/// to run on an actix system.
+pub async fn replay(events: Vec<RecordedEvent>) -> anyhow::Result<ReplayReport> {
+    let mut events = events.into_iter();
+
+    let (name, start, stream) = match events.next() {
+        Some(RecordedEvent::Start { room, time, stream }) => (room, time, stream),
+        _ => anyhow::bail!("recording doesn't begin with a start event"),
+    };
+
+    let clock = MockClock::new(Utc.timestamp_millis(start.0));
+    let room = Room::with_clock(name, stream.map(MediaStream::from), clock.shared()).start();
+
+    let outputs = Outputs::default();
+    let mut expected: HashMap<UserId, Vec<ToSessionMessage>> = HashMap::new();
+    let mut transports: HashMap<UserId, Recipient<ToSessionMessage>> = HashMap::new();
+    let mut participants = HashSet::new();
+
+    for event in events {
+        match event {
+            RecordedEvent::Start { .. } => anyhow::bail!("recording has more than one start event"),
+            RecordedEvent::Identify { cookie, user } => {
+                let replayed = room.send(GetUserId(cookie)).await?;
+
+                if replayed != Some(user) {
+                    warn!(user = user.0, ?replayed, "user id differs from the recording");
+                }
+            }
+            RecordedEvent::Incoming { time, user, cookie, message } => {
+                // Participants' ping loops are spawned on join, let them catch up even if the
+                // clock stays put.
+                clock.set(Utc.timestamp_millis(time.0));
+                settle(&room, &clock, participants.len()).await;
+
+                // Every hello is a new connection, so it gets a new transport as well.
+                match message {
+                    UserMessage::Hello { .. } => {
+                        transports.remove(&user);
+                        participants.insert(user);
+                    }
+                    UserMessage::Goodbye => {
+                        participants.remove(&user);
+                    }
+                    _ => {}
+                }
+
+                let addr = transports
+                    .entry(user)
+                    .or_insert_with(|| Collector { user, outputs: outputs.clone() }.start().recipient())
+                    .clone();
+
+                room.send(ClientMessage {
+                    from: user,
+                    cookie,
+                    addr,
+                    message,
+                    server_time: ServerTime(Utc.timestamp_millis(time.0)),
+                }).await??;
+            }
+            RecordedEvent::Outgoing { time, user, message } => {
+                // Pings are sent by timers, moving the clock along lets them fire when they did.
+                let time = Utc.timestamp_millis(time.0);
+                if time > clock.now() {
+                    clock.set(time);
+                    settle(&room, &clock, participants.len()).await;
+                }
+
+                expected.entry(user).or_default().push(message);
+            }
+            RecordedEvent::Transport { .. } => {}
+        }
+    }
+
+    settle(&room, &clock, participants.len()).await;
+    collected(&outputs, expected.values().map(Vec::len).sum()).await;
+
+    let actual = outputs.borrow();
+    let mut report = ReplayReport::default();
+
+    let mut users = expected.keys().chain(actual.keys()).copied().collect::<Vec<_>>();
+    users.sort_by_key(|u| u.0);
+    users.dedup();
+
+    for user in users {
+        let expected = expected.get(&user).map(Vec::as_slice).unwrap_or_default();
+        let actual = actual.get(&user).map(Vec::as_slice).unwrap_or_default();
+        report.messages += expected.len();
+
+        for index in 0..expected.len().max(actual.len()) {
+            let (expected, actual) = (expected.get(index), actual.get(index));
+
+            // Compare the wire representation, the protocol types don't implement `PartialEq`.
+            let same = match (expected, actual) {
+                (Some(e), Some(a)) => serde_json::to_value(e)? == serde_json::to_value(a)?,
+                _ => false,
+            };
+
+            if !same {
+                report.mismatches.push(Mismatch {
+                    user,
+                    index,
+                    expected: expected.cloned(),
+                    actual: actual.cloned(),
+                });
+            }
+        }
+    }
+
+    Ok(report)
+}
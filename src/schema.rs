@@ -0,0 +1,132 @@
+//! Machine readable descriptions of the websocket protocol, generated from the types in
+//! `protocol.rs` so they always match the serde representation actually sent over the wire.
+//! Both are also checked in under `protocol/`, `tests/protocol.rs` keeps them up to date.
+
+use schemars::gen::SchemaSettings;
+use serde_json::{json, Map, Value};
+
+use crate::protocol::{ToSessionMessage, UserMessage};
+
+use std::fmt::Write;
+
+/// Returns a JSON Schema (draft 7) document with a definition for every protocol type.
+pub fn json_schema() -> Value {
+    let mut gen = SchemaSettings::draft07().into_generator();
+
+    gen.subschema_for::<UserMessage>();
+    gen.subschema_for::<ToSessionMessage>();
+
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "tmtusync protocol",
+        "definitions": gen.take_definitions(),
+    })
+}
+
+/// Returns TypeScript declarations for every protocol type.
+pub fn typescript() -> String {
+    let schema = json_schema();
+    let mut out = String::from("// Generated from protocol.rs, do not edit.\n");
+
+    if let Some(definitions) = schema["definitions"].as_object() {
+        for (name, def) in definitions {
+            out.push('\n');
+
+            if let Some(description) = def["description"].as_str() {
+                write_doc_comment(&mut out, description, "");
+            }
+
+            match def["properties"].as_object() {
+                Some(properties) if is_plain_object(def) => {
+                    writeln!(out, "export interface {} {{", name).unwrap();
+                    write_properties(&mut out, properties, def, "    ");
+                    out.push_str("}\n");
+                }
+                _ => {
+                    writeln!(out, "export type {} = {};", name, ts_type(def, "")).unwrap();
+                }
+            }
+        }
+    }
+
+    out
+}
+
+fn is_plain_object(schema: &Value) -> bool {
+    schema["type"] == "object" && schema.get("oneOf").is_none() && schema.get("anyOf").is_none()
+}
+
+fn write_doc_comment(out: &mut String, description: &str, indent: &str) {
+    writeln!(out, "{}/**", indent).unwrap();
+    for line in description.lines() {
+        writeln!(out, "{} * {}", indent, line).unwrap();
+    }
+    writeln!(out, "{} */", indent).unwrap();
+}
+
+fn write_properties(out: &mut String, properties: &Map<String, Value>, schema: &Value, indent: &str) {
+    let required = schema["required"]
+        .as_array()
+        .map(|r| r.iter().filter_map(Value::as_str).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    for (name, property) in properties {
+        if let Some(description) = property["description"].as_str() {
+            write_doc_comment(out, description, indent);
+        }
+
+        let optional = if required.contains(&name.as_str()) { "" } else { "?" };
+        writeln!(out, "{}{}{}: {};", indent, name, optional, ts_type(property, indent)).unwrap();
+    }
+}
+
+/// Translates a (sub)schema into a TypeScript type expression.
+fn ts_type(schema: &Value, indent: &str) -> String {
+    if let Some(reference) = schema["$ref"].as_str() {
+        return reference.rsplit('/').next().unwrap_or(reference).to_string();
+    }
+
+    if let Some(variants) = schema["oneOf"].as_array().or_else(|| schema["anyOf"].as_array()) {
+        return variants
+            .iter()
+            .map(|v| ts_type(v, indent))
+            .collect::<Vec<_>>()
+            .join(" | ");
+    }
+
+    if let Some(values) = schema["enum"].as_array() {
+        return values
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(" | ");
+    }
+
+    match &schema["type"] {
+        Value::Array(types) => types
+            .iter()
+            .map(|t| ts_type(&json!({ "type": t, "items": schema["items"], "properties": schema["properties"], "required": schema["required"] }), indent))
+            .collect::<Vec<_>>()
+            .join(" | "),
+        Value::String(t) => match t.as_str() {
+            "string" => "string".into(),
+            "integer" | "number" => "number".into(),
+            "boolean" => "boolean".into(),
+            "null" => "null".into(),
+            "array" => format!("({})[]", ts_type(&schema["items"], indent)),
+            "object" => match schema["properties"].as_object() {
+                Some(properties) => {
+                    let inner = format!("{}    ", indent);
+                    let mut out = String::from("{\n");
+                    write_properties(&mut out, properties, schema, &inner);
+                    out.push_str(indent);
+                    out.push('}');
+                    out
+                }
+                None => "Record<string, unknown>".into(),
+            },
+            _ => "unknown".into(),
+        },
+        _ => "unknown".into(),
+    }
+}
@@ -0,0 +1,1508 @@
+//! The HTTP side of the server: pages, the websocket endpoint and the app configuration shared
+//! by the binary and the integration tests.
+
+use actix::{Addr, Actor};
+use actix_web::{get, post, put, delete, web, error::BlockingError, http::{header, StatusCode}, HttpRequest, HttpResponse, Responder};
+use actix_identity::{Identity, CookieIdentityPolicy, IdentityService};
+use actix_web_actors::ws;
+use actix_files::NamedFile;
+
+use askama_actix::{TemplateIntoResponse};
+
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+use url::Url;
+
+use crate::audit::{self, AuditError, AuditLog};
+use crate::cluster::{self, ClaimError, Cluster, NodeInfo};
+use crate::codec::Encoding;
+use crate::hls;
+use crate::transcode::MASTER_PLAYLIST;
+use crate::subtitles::{self, SubtitleError};
+use crate::library::MediaLibrary;
+use crate::proxy::{self, HlsProxy, ProxyError};
+use crate::signing::UrlSigner;
+use crate::upload::{UploadError, UploadInfo, UploadStore};
+use crate::recording::Recorder;
+use crate::protocol::Time;
+use crate::clock::SharedClock;
+use crate::metrics;
+
+use crate::protocol::{
+    BadgeData,
+    BadgeId,
+    badges,
+    badges::BADGE_DATA,
+};
+
+use crate::actors::{
+    Room,
+    MediaSource,
+    MediaStream,
+    GetUserId,
+    RoomMetadata,
+    RoomRepository,
+    GetRoomMeta,
+    GetStream,
+    GetSubtitleOffset,
+    IsMember,
+    IsHostOrAdmin,
+    GetAuditLog,
+    GrantAdmin,
+    RegisterRoom,
+    RoomSnapshot,
+    FindRoom,
+    WebsocketTransport,
+    WebsocketForwarder,
+    TransportConfig,
+    Transcoder,
+    Transcode,
+    GetJob,
+    ListJobs,
+    Job,
+    JobId,
+};
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Deserialize, Debug)]
+pub struct LoginData {
+    pub nickname: String,
+    pub avatar: u32,
+    pub room: String,
+}
+
+#[derive(askama::Template)]
+#[template(path = "hello.html")]
+struct HelloTemplate<'a> {
+    name: &'a str,
+}
+
+#[derive(askama::Template)]
+#[template(path = "room.html")]
+struct RoomTemplate<'a> {
+    meta: RoomMetadata,
+    nickname: &'a str,
+    avatar: BadgeId,
+    badges: &'a [BadgeId],
+    code: &'a str,
+    badge_data: &'a [BadgeData],
+}
+
+#[derive(askama::Template)]
+#[template(path = "create_room.html")]
+struct CreateRoomTemplate {
+    files: Vec<MediaStream>,
+}
+
+async fn find_room(room_repository: &Addr<RoomRepository>, code: String) -> Option<Addr<Room>> {
+    room_repository.send(FindRoom(code)).await.unwrap()
+}
+
+/// The node owning the room, if running in a cluster and the room lives elsewhere.
+async fn remote_owner(data: &AppData, code: &str) -> Option<NodeInfo> {
+    let cluster = data.cluster.as_ref()?;
+
+    match cluster.remote_owner(code).await {
+        Ok(owner) => owner,
+        Err(e) => {
+            error!(room = %code, error = ?e, "failed to look up room owner");
+            None
+        }
+    }
+}
+
+/// The room if it lives on this node. In a cluster, a room handed over by a node that shut down
+/// is taken over first.
+async fn find_local_room(data: &AppData, code: &str) -> Option<Addr<Room>> {
+    if let Some(room) = find_room(&data.room_repo, code.to_string()).await {
+        return Some(room);
+    }
+
+    let cluster = data.cluster.as_ref()?;
+    let snapshot = match cluster.directory.take_over(code, &cluster.node).await {
+        Ok(snapshot) => snapshot?,
+        Err(e) => {
+            error!(room = %code, error = ?e, "failed to take over room");
+            return None;
+        }
+    };
+
+    info!(room = %code, "taking over room");
+    if let Err(e) = restore_room(data, snapshot).await {
+        error!(room = %code, error = ?e, "failed to take over room");
+        return None;
+    }
+
+    find_room(&data.room_repo, code.to_string()).await
+}
+
+/// Asks the owning node for the metadata of a room living elsewhere in the cluster.
+async fn remote_room_meta(data: &AppData, code: &str) -> Option<RoomMetadata> {
+    let cluster = data.cluster.as_ref()?;
+    let owner = remote_owner(data, code).await?;
+    let url = format!("{}/cluster/rooms/{}", owner.address, code);
+
+    let request = cluster.client().get(url).header(cluster::SECRET_HEADER, cluster.secret());
+    let mut response = request.send().await.map_err(|e| {
+        error!(room = %code, owner = %owner.id, error = ?e, "failed to reach room owner");
+    }).ok()?;
+
+    if !response.status().is_success() {
+        return None;
+    }
+
+    response.json().await.map_err(|e| {
+        error!(room = %code, owner = %owner.id, error = ?e, "invalid room metadata from owner");
+    }).ok()
+}
+
+fn negotiate_encoding(req: &HttpRequest) -> Encoding {
+    Encoding::negotiate(
+        req.headers()
+            .get(header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|h| h.to_str().ok()),
+    )
+}
+
+async fn forward_websocket_session(
+    req: HttpRequest,
+    stream: web::Payload,
+    code: String,
+    owner: NodeInfo,
+    data: web::Data<AppData>,
+) -> HttpResponse {
+    let cookie = req.headers().get(header::COOKIE).and_then(|h| h.to_str().ok());
+    let max_frame_size = data.transport.validation.max_frame_size;
+    let encoding = negotiate_encoding(&req);
+    let client = match &data.cluster {
+        Some(cluster) => cluster.client(),
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    let forwarder = match WebsocketForwarder::connect(&client, &owner, &code, cookie, encoding, max_frame_size).await {
+        Ok(forwarder) => forwarder,
+        Err(e) => {
+            error!(owner = %owner.id, error = ?e, "failed to forward session to owner");
+            return HttpResponse::BadGateway().finish();
+        }
+    };
+
+    let codec = ws::Codec::new().max_size(max_frame_size);
+    let protocols = Encoding::ALL.iter().map(|e| e.protocol()).collect::<Vec<_>>();
+
+    match ws::handshake_with_protocols(&req, &protocols) {
+        Ok(mut response) => {
+            response.streaming(ws::WebsocketContext::with_codec(forwarder, stream, codec))
+        }
+        Err(e) => HttpResponse::from_error(e.into()),
+    }
+}
+
+#[get("/websocket/{name}")]
+async fn room_websocket_session(
+    req: HttpRequest,
+    identity: Identity,
+    stream: web::Payload,
+    path: web::Path<(String,)>,
+    data: web::Data<AppData>,
+) -> impl Responder {
+    let code = path.into_inner().0;
+    let span = info_span!("websocket", room = %code, user = tracing::field::Empty);
+
+    start_websocket_session(req, identity, stream, code, data)
+        .instrument(span)
+        .await
+}
+
+async fn start_websocket_session(
+    req: HttpRequest,
+    identity: Identity,
+    stream: web::Payload,
+    code: String,
+    data: web::Data<AppData>,
+) -> HttpResponse {
+    let room = find_local_room(&data, &code).await;
+    let cookie = identity.identity();
+
+    if room.is_none() {
+        if let Some(owner) = remote_owner(&data, &code).await {
+            return forward_websocket_session(req, stream, code, owner, data).await;
+        }
+    }
+
+    if let (Some(room), Some(cookie)) = (room, cookie) {
+        if let Some(id) = room.send(GetUserId(cookie.clone())).await.unwrap() {
+            Span::current().record("user", &id.0);
+            info!("starting websocket session");
+
+            let encoding = negotiate_encoding(&req);
+            let codec = ws::Codec::new().max_size(data.transport.validation.max_frame_size);
+            let transport = WebsocketTransport::new(
+                cookie,
+                id,
+                room,
+                code,
+                encoding,
+                data.clock.clone(),
+                data.transport.clone(),
+            ).await;
+            let protocols = Encoding::ALL.iter().map(|e| e.protocol()).collect::<Vec<_>>();
+
+            match ws::handshake_with_protocols(&req, &protocols) {
+                Ok(mut response) => {
+                    response.streaming(ws::WebsocketContext::with_codec(transport, stream, codec))
+                }
+                Err(e) => HttpResponse::from_error(e.into()),
+            }
+        } else {
+            error!("already logged in");
+
+            HttpResponse::NotFound().body("already logged in")
+        }
+    } else {
+        HttpResponse::NotFound().body("nope")
+    }
+}
+
+/// Metadata of a room owned by this node, for the other nodes of the cluster.
+#[get("/cluster/rooms/{code}")]
+async fn cluster_room_meta(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    data: web::Data<AppData>,
+) -> HttpResponse {
+    let cluster = match &data.cluster {
+        Some(cluster) => cluster,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    let secret = req.headers().get(cluster::SECRET_HEADER).and_then(|h| h.to_str().ok());
+    if !secret.map(|secret| cluster.is_member(secret)).unwrap_or(false) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let meta = match find_room(&data.room_repo, path.into_inner().0).await {
+        Some(room) => room.send(GetRoomMeta).await.unwrap(),
+        None => None,
+    };
+
+    match meta {
+        Some(meta) => HttpResponse::Ok().json(meta),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Proof that the requester may fetch a file of a room's media without being part of the room.
+#[derive(Deserialize, Debug)]
+pub struct SignedQuery {
+    /// Unix time in seconds.
+    pub expires: Option<i64>,
+    pub signature: Option<String>,
+}
+
+/// Headers passed on both ways when forwarding a request for a room to another node.
+const FORWARDED_REQUEST_HEADERS: &[header::HeaderName] = &[header::COOKIE, header::RANGE, header::IF_NONE_MATCH];
+const FORWARDED_RESPONSE_HEADERS: &[header::HeaderName] = &[
+    header::CONTENT_TYPE,
+    header::CACHE_CONTROL,
+    header::LOCATION,
+    header::CONTENT_RANGE,
+    header::ACCEPT_RANGES,
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::CONTENT_DISPOSITION,
+];
+
+/// Passes a request for the media or audit log of a room living on another node of the cluster
+/// on to that node, streaming its answer back. `None` if the room doesn't live elsewhere. The
+/// owner checks the identity and signatures, which is why every node has to sign with the same
+/// key.
+async fn forward_to_owner(req: &HttpRequest, data: &AppData, code: &str) -> Option<HttpResponse> {
+    let cluster = data.cluster.as_ref()?;
+    let owner = remote_owner(data, code).await?;
+
+    let mut request = cluster.client().get(format!("{}{}", owner.address.trim_end_matches('/'), req.uri()));
+    for name in FORWARDED_REQUEST_HEADERS {
+        if let Some(value) = req.headers().get(name) {
+            request = request.header(name.clone(), value.clone());
+        }
+    }
+
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            error!(room = %code, owner = %owner.id, error = ?e, "failed to forward request");
+            return Some(HttpResponse::BadGateway().finish());
+        }
+    };
+
+    let mut forwarded = HttpResponse::build(response.status());
+    for name in FORWARDED_RESPONSE_HEADERS {
+        if let Some(value) = response.headers().get(name) {
+            forwarded.header(name.clone(), value.clone());
+        }
+    }
+
+    Some(forwarded.streaming(response))
+}
+
+/// The room `code` if the requester may fetch `path` of its media: participants may fetch
+/// everything, anyone else needs a URL signed for that path. Requests for rooms on other nodes
+/// are answered by those, the response being the error then.
+async fn media_room(
+    req: &HttpRequest,
+    data: &AppData,
+    identity: &Identity,
+    code: &str,
+    path: &str,
+    query: &SignedQuery,
+) -> Result<Addr<Room>, HttpResponse> {
+    let room = match find_local_room(data, code).await {
+        Some(room) => room,
+        None => match forward_to_owner(req, data, code).await {
+            Some(response) => return Err(response),
+            None => return Err(HttpResponse::NotFound().finish()),
+        },
+    };
+
+    if let (Some(expires), Some(signature)) = (query.expires, &query.signature) {
+        if data.media_signer.verify(code, path, expires, signature, data.clock.now()) {
+            return Ok(room);
+        }
+    }
+
+    let member = match identity.identity() {
+        Some(cookie) => room.send(IsMember(cookie)).await.unwrap(),
+        None => false,
+    };
+
+    if member {
+        Ok(room)
+    } else {
+        Err(HttpResponse::Forbidden().finish())
+    }
+}
+
+/// `playlist`, found in `dir` of the room's stream, with every URI within the stream signed and
+/// put below `prefix`.
+fn sign_playlist(data: &AppData, code: &str, dir: &str, prefix: &str, playlist: &str) -> String {
+    let now = data.clock.now();
+
+    hls::rewrite_uris(playlist, |uri| match hls::resolve_uri(dir, uri) {
+        Some(path) => format!(
+            "{}{}{}{}",
+            prefix,
+            uri,
+            if uri.contains('?') { '&' } else { '?' },
+            data.media_signer.query(code, &path, now),
+        ),
+        None => uri.to_string(),
+    })
+}
+
+/// Every rendition of the room's stream in one master playlist, so players can switch between
+/// them. Streams the playlist can't be generated for are redirected to their own.
+#[get("/room/{code}/master.m3u8")]
+async fn room_master_playlist(
+    req: HttpRequest,
+    identity: Identity,
+    path: web::Path<(String,)>,
+    query: web::Query<SignedQuery>,
+    data: web::Data<AppData>,
+) -> HttpResponse {
+    let code = path.into_inner().0;
+    let stream = match media_room(&req, &data, &identity, &code, MASTER_PLAYLIST, &query).await {
+        Ok(room) => room.send(GetStream).await.unwrap(),
+        Err(response) => return response,
+    };
+
+    let stream = match stream {
+        Some(stream) => stream,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    if let MediaSource::Remote { url } = &stream.source {
+        return proxy_remote_media(&data, &code, url, url).await;
+    }
+
+    let (playlists, dir) = (data.master_playlists.clone(), data.media_dir.join(&stream.slug));
+    let (streams, audio_tracks) = (stream.streams.clone(), stream.audio_tracks.clone());
+    let playlist = web::block(move || playlists.get(&dir, &streams, &audio_tracks)).await.map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => io::Error::new(io::ErrorKind::Other, "generating playlist canceled"),
+    });
+
+    match playlist {
+        Ok(Some(playlist)) => HttpResponse::Ok()
+            .content_type("application/vnd.apple.mpegurl")
+            .body(sign_playlist(&data, &code, "", "media/", &playlist)),
+        Ok(None) => match stream.streams.first() {
+            Some(first) => HttpResponse::Found()
+                .header(
+                    header::LOCATION,
+                    format!(
+                        "/room/{}/media/{}?{}",
+                        code,
+                        first.playlist,
+                        data.media_signer.query(&code, &first.playlist, data.clock.now()),
+                    ),
+                )
+                .finish(),
+            None => HttpResponse::NotFound().finish(),
+        },
+        Err(e) => {
+            error!(room = %code, error = ?e, "failed to generate master playlist");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// A file of the room's stream, for participants and signed URLs. Playlists have every URI in
+/// them signed, so players can follow them without the identity cookie.
+#[get("/room/{code}/media/{path:.*}")]
+async fn room_media(
+    req: HttpRequest,
+    identity: Identity,
+    path: web::Path<(String, String)>,
+    query: web::Query<SignedQuery>,
+    data: web::Data<AppData>,
+) -> HttpResponse {
+    let (code, path) = path.into_inner();
+
+    // Nothing outside of the directory of the stream.
+    let file = match hls::resolve_uri("", &path) {
+        Some(file) => file,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    let stream = match media_room(&req, &data, &identity, &code, &file, &query).await {
+        Ok(room) => room.send(GetStream).await.unwrap(),
+        Err(response) => return response,
+    };
+
+    let full_path = match stream {
+        Some(stream) => data.media_dir.join(&stream.slug).join(&file),
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    if !full_path.is_file() {
+        return HttpResponse::NotFound().finish();
+    }
+
+    metrics::record_hls_request(&file);
+
+    if file.ends_with(".m3u8") {
+        let dir = file.rsplitn(2, '/').nth(1).unwrap_or("");
+
+        return match fs::read_to_string(&full_path) {
+            Ok(playlist) => HttpResponse::Ok()
+                .content_type("application/vnd.apple.mpegurl")
+                .body(sign_playlist(&data, &code, dir, "", &playlist)),
+            Err(e) => {
+                error!(room = %code, %file, error = ?e, "failed to read playlist");
+                HttpResponse::InternalServerError().finish()
+            }
+        };
+    }
+
+    match NamedFile::open(&full_path) {
+        Ok(file) => file.into_response(&req).unwrap_or_else(HttpResponse::from_error),
+        Err(e) => {
+            error!(room = %code, %file, error = ?e, "failed to open media");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Where the proxy serves `url` of the remote stream of room `code`. Signed, so the proxy only
+/// ever fetches URLs found in the playlists of the stream.
+fn remote_media_url(data: &AppData, code: &str, url: &str, now: DateTime<Utc>) -> String {
+    format!(
+        "/room/{}/remote?url={}&{}",
+        code,
+        url::form_urlencoded::byte_serialize(url.as_bytes()).collect::<String>(),
+        data.media_signer.query(code, url, now),
+    )
+}
+
+/// `url` of the remote stream of room `code`, coming from `source`, through the proxy. Playlists
+/// have every URI the proxy may fetch pointing back at it, and the rest made absolute.
+async fn proxy_remote_media(data: &AppData, code: &str, source: &str, url: &str) -> HttpResponse {
+    let (proxy, source, base) = match (&data.proxy, Url::parse(source), Url::parse(url)) {
+        (Some(proxy), Ok(source), Ok(base)) if proxy.allows(&source, &base) => (proxy, source, base),
+        _ => return HttpResponse::NotFound().finish(),
+    };
+
+    let response = match proxy.fetch(url).await {
+        Ok(response) => response,
+        Err(e @ ProxyError::PrivateAddress(_)) => {
+            warn!(room = %code, %url, error = %e, "refused to fetch remote media");
+            return HttpResponse::Forbidden().finish();
+        }
+        Err(e) => {
+            warn!(room = %code, %url, error = %e, "failed to fetch remote media");
+            return HttpResponse::BadGateway().finish();
+        }
+    };
+
+    if !response.is_playlist(&base) {
+        return HttpResponse::Ok()
+            .content_type(response.content_type.as_deref().unwrap_or("application/octet-stream"))
+            .streaming(response.body.map_err(actix_web::error::ErrorBadGateway));
+    }
+
+    let playlist = match response.body.bytes().await {
+        Ok(playlist) => playlist,
+        Err(e) => {
+            warn!(room = %code, %url, error = %e, "failed to fetch remote playlist");
+            return HttpResponse::BadGateway().finish();
+        }
+    };
+
+    let now = data.clock.now();
+    let playlist = proxy::rewrite_playlist(&String::from_utf8_lossy(&playlist), &base, |uri| {
+        if proxy.allows(&source, uri) {
+            remote_media_url(data, code, uri.as_str(), now)
+        } else {
+            debug!(room = %code, %uri, "not proxying uri off the stream's host");
+            uri.to_string()
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("application/vnd.apple.mpegurl")
+        .body(playlist)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RemoteQuery {
+    pub url: String,
+    /// Unix time in seconds.
+    pub expires: i64,
+    pub signature: String,
+}
+
+/// A playlist or segment of the room's remote stream. Only URLs signed by `remote_media_url` are
+/// fetched, the proxy isn't open to just any URL.
+#[get("/room/{code}/remote")]
+async fn room_remote_media(
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    query: web::Query<RemoteQuery>,
+    data: web::Data<AppData>,
+) -> HttpResponse {
+    let code = path.into_inner().0;
+
+    if !data.media_signer.verify(&code, &query.url, query.expires, &query.signature, data.clock.now()) {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let stream = match find_local_room(&data, &code).await {
+        Some(room) => room.send(GetStream).await.unwrap(),
+        None => match forward_to_owner(&req, &data, &code).await {
+            Some(response) => return response,
+            None => None,
+        },
+    };
+
+    match stream.map(|s| s.source) {
+        Some(MediaSource::Remote { url: source }) => {
+            let remote_path = Url::parse(&query.url).map(|u| u.path().to_string()).unwrap_or_default();
+            metrics::record_hls_request(&remote_path);
+
+            proxy_remote_media(&data, &code, &source, &query.url).await
+        }
+        _ => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// A subtitle track of the room's stream as WebVTT, shifted by the room's subtitle offset.
+#[get("/room/{code}/subtitles/{track}")]
+async fn room_subtitles(
+    req: HttpRequest,
+    identity: Identity,
+    path: web::Path<(String, String)>,
+    query: web::Query<SignedQuery>,
+    data: web::Data<AppData>,
+) -> HttpResponse {
+    let (code, track) = path.into_inner();
+
+    let (stream, offset) = match media_room(&req, &data, &identity, &code, &track, &query).await {
+        Ok(room) => (room.send(GetStream).await.unwrap(), room.send(GetSubtitleOffset).await.unwrap()),
+        Err(response) => return response,
+    };
+
+    let stream = match stream {
+        Some(stream) => stream,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    match subtitles::load(&data.media_dir.join(&stream.slug), &track, offset) {
+        // The room's offset is applied to the cues, so they change with it.
+        Ok(vtt) => HttpResponse::Ok()
+            .content_type("text/vtt; charset=utf-8")
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(vtt),
+        Err(SubtitleError::NotFound) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!(room = %code, %track, error = ?e, "failed to read subtitles");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[get("/metrics")]
+async fn metrics_endpoint() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::render())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuditQuery {
+    /// `json` or `csv`, defaults to `json`.
+    pub format: Option<String>,
+}
+
+#[get("/room/{code}/audit")]
+async fn room_audit_log(
+    req: HttpRequest,
+    identity: Identity,
+    path: web::Path<(String,)>,
+    query: web::Query<AuditQuery>,
+    data: web::Data<AppData>,
+) -> HttpResponse {
+    let code = path.into_inner().0;
+    let room = match find_local_room(&data, &code).await {
+        Some(room) => room,
+        None => return match forward_to_owner(&req, &data, &code).await {
+            Some(response) => response,
+            None => HttpResponse::NotFound().finish(),
+        },
+    };
+
+    let cookie = match identity.identity() {
+        Some(cookie) => cookie,
+        None => return HttpResponse::Forbidden().finish(),
+    };
+
+    let events = match room.send(GetAuditLog(cookie)).await.unwrap() {
+        Ok(log) => log.read().await,
+        Err(e) => Err(e),
+    };
+
+    let events = match events {
+        Ok(events) => events,
+        Err(AuditError::Forbidden) => return HttpResponse::Forbidden().finish(),
+        Err(AuditError::Disabled) => return HttpResponse::NotFound().finish(),
+        Err(e) => {
+            error!(room = %code, error = ?e, "failed to read audit log");
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match query.format.as_deref() {
+        Some("csv") => HttpResponse::Ok()
+            .content_type("text/csv")
+            .header(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}-audit.csv\"", code),
+            )
+            .body(audit::to_csv(&events)),
+        Some("json") | None => HttpResponse::Ok().json(events),
+        Some(_) => HttpResponse::BadRequest().body("format must be json or csv"),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UploadQuery {
+    /// What the media is called in the library.
+    pub name: String,
+}
+
+/// Where a resumable upload is at, and where a chunk starts.
+const UPLOAD_OFFSET: &str = "Upload-Offset";
+/// `sha256` and the hex digest of a chunk.
+const UPLOAD_CHECKSUM: &str = "Upload-Checksum";
+
+fn upload_error_response(e: UploadError) -> HttpResponse {
+    match e {
+        UploadError::NotFound => HttpResponse::NotFound().finish(),
+        UploadError::TooLarge(_) | UploadError::PastEnd => HttpResponse::PayloadTooLarge().body(e.to_string()),
+        UploadError::QuotaExceeded(_) => HttpResponse::build(StatusCode::INSUFFICIENT_STORAGE).body(e.to_string()),
+        UploadError::WrongOffset(offset) => HttpResponse::Conflict()
+            .header(UPLOAD_OFFSET, offset.to_string())
+            .body(e.to_string()),
+        UploadError::Busy => HttpResponse::build(StatusCode::LOCKED).body(e.to_string()),
+        UploadError::BadChecksum(_) | UploadError::Incomplete => HttpResponse::BadRequest().body(e.to_string()),
+        UploadError::ChecksumMismatch => HttpResponse::UnprocessableEntity().body(e.to_string()),
+        UploadError::Io(e) => {
+            error!(error = ?e, "failed to store upload");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Runs file work on the blocking thread pool, keeping it off the thread serving requests.
+async fn blocking<T, F>(f: F) -> Result<T, UploadError>
+where
+    F: FnOnce() -> Result<T, UploadError> + Send + 'static,
+    T: Send + 'static,
+{
+    web::block(f).await.map_err(|e| match e {
+        BlockingError::Error(e) => e,
+        BlockingError::Canceled => io::Error::new(io::ErrorKind::Other, "file operation canceled").into(),
+    })
+}
+
+/// A nickname or room code as part of an identity, see `login`, with the dashes separating the
+/// parts escaped.
+fn escape_identity_part(part: &str) -> String {
+    part.replace('%', "%25").replace('-', "%2D")
+}
+
+/// The code of the room an identity logged in to.
+fn identity_room(cookie: &str) -> Option<String> {
+    let room = cookie.rsplitn(3, '-').nth(1)?;
+
+    Some(room.replace("%2D", "-").replace("%25", "%"))
+}
+
+/// Whether the identity belongs to the host or an admin of the room it logged in to, see
+/// `login`. Only they get to add media and follow its transcoding.
+async fn may_manage_media(data: &AppData, identity: &Identity) -> bool {
+    let cookie = match identity.identity() {
+        Some(cookie) => cookie,
+        None => return false,
+    };
+
+    let room = match identity_room(&cookie) {
+        Some(code) => find_room(&data.room_repo, code).await,
+        None => None,
+    };
+
+    match room {
+        Some(room) => room.send(IsHostOrAdmin(cookie)).await.unwrap_or(false),
+        None => false,
+    }
+}
+
+/// Tells apart uploads arriving within the same millisecond.
+static UPLOADS: AtomicU64 = AtomicU64::new(0);
+
+/// Writes a request body to a new file in the upload directory.
+async fn receive_upload(body: &mut web::Payload, uploads: &UploadStore, now: i64) -> Result<PathBuf, UploadError> {
+    let path = uploads.dir().join(format!("{}-{}.upload", now, UPLOADS.fetch_add(1, Ordering::Relaxed)));
+    let max_size = uploads.limits().max_size;
+
+    // The file counts towards the quota while it waits for transcoding.
+    let store = uploads.clone();
+    let available = blocking(move || store.available()).await?;
+
+    let created = path.clone();
+    let mut file = blocking(move || Ok(File::create(created)?)).await?;
+    let mut size = 0;
+
+    while let Some(chunk) = body.next().await {
+        let written = match chunk {
+            Ok(chunk) => {
+                size += chunk.len() as u64;
+
+                if size > max_size {
+                    Err(UploadError::TooLarge(max_size))
+                } else if size > available {
+                    Err(UploadError::QuotaExceeded(available))
+                } else {
+                    blocking(move || {
+                        file.write_all(&chunk)?;
+                        Ok(file)
+                    }).await
+                }
+            }
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string()).into()),
+        };
+
+        file = match written {
+            Ok(file) => file,
+            Err(e) => {
+                let _ = blocking(move || Ok(fs::remove_file(path)?)).await;
+                return Err(e);
+            }
+        };
+    }
+
+    Ok(path)
+}
+
+/// Takes a media file as the request body and queues it for transcoding into HLS. Responds with
+/// the job, which can be followed on `/media/jobs/{id}`. Large files are better sent as a
+/// resumable upload, see `create_upload`.
+#[post("/media")]
+async fn upload_media(
+    mut body: web::Payload,
+    query: web::Query<UploadQuery>,
+    identity: Identity,
+    data: web::Data<AppData>,
+) -> HttpResponse {
+    let (transcoder, uploads) = match (&data.transcoder, &data.uploads) {
+        (Some(transcoder), Some(uploads)) => (transcoder, uploads),
+        _ => return HttpResponse::NotFound().finish(),
+    };
+
+    if !may_manage_media(&data, &identity).await {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let name = query.name.trim();
+    if name.is_empty() {
+        return HttpResponse::BadRequest().body("name must not be empty");
+    }
+
+    let input = match receive_upload(&mut body, uploads, data.clock.now().timestamp_millis()).await {
+        Ok(input) => input,
+        Err(e) => return upload_error_response(e),
+    };
+
+    let job = transcoder.send(Transcode { input, name: name.to_string() }).await.unwrap();
+
+    HttpResponse::Accepted().json(job)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewUpload {
+    /// What the media is called in the library.
+    pub name: String,
+    /// In bytes.
+    pub size: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct ChunkResponse {
+    upload: UploadInfo,
+    /// The transcoding job, once the upload is complete.
+    job: Option<Job>,
+}
+
+/// Hands a completed upload over to transcoding. Without a transcoder it stays in the store.
+async fn ingest_upload(
+    data: &AppData,
+    uploads: &UploadStore,
+    upload: &UploadInfo,
+) -> Result<Option<Job>, UploadError> {
+    let transcoder = match &data.transcoder {
+        Some(transcoder) => transcoder,
+        None => return Ok(None),
+    };
+
+    let (store, id) = (uploads.clone(), upload.id.clone());
+    let input = blocking(move || store.take(&id)).await?;
+    info!(upload = %upload.id, name = %upload.name, "upload complete");
+
+    Ok(Some(transcoder.send(Transcode { input, name: upload.name.clone() }).await.unwrap()))
+}
+
+/// Hands over uploads that were completed, but not transcoded, before a restart.
+pub async fn ingest_completed_uploads(data: &AppData) {
+    let uploads = match &data.uploads {
+        Some(uploads) => uploads,
+        None => return,
+    };
+
+    let completed = match uploads.list() {
+        Ok(list) => list.into_iter().filter(UploadInfo::is_complete),
+        Err(e) => {
+            error!(error = ?e, "failed to list uploads");
+            return;
+        }
+    };
+
+    for upload in completed {
+        if let Err(e) = ingest_upload(data, uploads, &upload).await {
+            error!(upload = %upload.id, error = ?e, "failed to hand over upload");
+        }
+    }
+}
+
+/// Announces a resumable upload. The file is then sent in chunks, see `put_upload_chunk`.
+#[post("/uploads")]
+async fn create_upload(
+    params: web::Json<NewUpload>,
+    identity: Identity,
+    data: web::Data<AppData>,
+) -> HttpResponse {
+    let uploads = match &data.uploads {
+        Some(uploads) => uploads,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    if !may_manage_media(&data, &identity).await {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let name = params.name.trim().to_string();
+    if name.is_empty() {
+        return HttpResponse::BadRequest().body("name must not be empty");
+    }
+
+    let (store, size) = (uploads.clone(), params.size);
+    match blocking(move || store.create(&name, size)).await {
+        Ok(upload) => HttpResponse::Created()
+            .header(header::LOCATION, format!("/uploads/{}", upload.id))
+            .header(UPLOAD_OFFSET, "0")
+            .json(upload),
+        Err(e) => upload_error_response(e),
+    }
+}
+
+/// Where an upload is at, to resume it from there.
+#[get("/uploads/{id}")]
+async fn get_upload(
+    path: web::Path<(String,)>,
+    identity: Identity,
+    data: web::Data<AppData>,
+) -> HttpResponse {
+    let uploads = match &data.uploads {
+        Some(uploads) => uploads.clone(),
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    if !may_manage_media(&data, &identity).await {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let id = path.into_inner().0;
+    match blocking(move || uploads.get(&id)).await {
+        Ok(upload) => HttpResponse::Ok()
+            .header(UPLOAD_OFFSET, upload.offset.to_string())
+            .json(upload),
+        Err(e) => upload_error_response(e),
+    }
+}
+
+/// Writes the request body into an upload, starting at the `Upload-Offset` header. An
+/// `Upload-Checksum` header is checked against the chunk, a chunk not matching it is dropped.
+/// The last chunk hands the file over for transcoding.
+#[put("/uploads/{id}")]
+async fn put_upload_chunk(
+    req: HttpRequest,
+    mut body: web::Payload,
+    path: web::Path<(String,)>,
+    identity: Identity,
+    data: web::Data<AppData>,
+) -> HttpResponse {
+    let uploads = match &data.uploads {
+        Some(uploads) => uploads,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    if !may_manage_media(&data, &identity).await {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let id = path.into_inner().0;
+    let offset = match req.headers().get(UPLOAD_OFFSET).and_then(|h| h.to_str().ok()).and_then(|h| h.parse().ok()) {
+        Some(offset) => offset,
+        None => return HttpResponse::BadRequest().body("missing Upload-Offset header"),
+    };
+    let checksum = req.headers().get(UPLOAD_CHECKSUM).and_then(|h| h.to_str().ok()).map(String::from);
+
+    let (store, appended) = (uploads.clone(), id.clone());
+    let mut writer = match blocking(move || store.append(&appended, offset, checksum.as_deref())).await {
+        Ok(writer) => writer,
+        Err(e) => return upload_error_response(e),
+    };
+
+    let mut interrupted = false;
+
+    while let Some(chunk) = body.next().await {
+        match chunk {
+            Ok(chunk) => {
+                let written = blocking(move || match writer.write(&chunk) {
+                    Ok(()) => Ok(writer),
+                    Err(e) => {
+                        let _ = writer.discard();
+                        Err(e)
+                    }
+                }).await;
+
+                writer = match written {
+                    Ok(writer) => writer,
+                    Err(e) => return upload_error_response(e),
+                };
+            }
+            Err(e) => {
+                warn!(upload = %id, error = ?e, "upload interrupted");
+                interrupted = true;
+                break;
+            }
+        }
+    }
+
+    let written = blocking(move || if interrupted { writer.interrupted() } else { writer.finish() }).await;
+    let upload = match written {
+        Ok(upload) => upload,
+        Err(e) => return upload_error_response(e),
+    };
+
+    let job = if upload.is_complete() {
+        match ingest_upload(&data, uploads, &upload).await {
+            Ok(job) => job,
+            Err(e) => return upload_error_response(e),
+        }
+    } else {
+        None
+    };
+
+    HttpResponse::Ok()
+        .header(UPLOAD_OFFSET, upload.offset.to_string())
+        .json(ChunkResponse { upload, job })
+}
+
+#[delete("/uploads/{id}")]
+async fn delete_upload(
+    path: web::Path<(String,)>,
+    identity: Identity,
+    data: web::Data<AppData>,
+) -> HttpResponse {
+    let uploads = match &data.uploads {
+        Some(uploads) => uploads.clone(),
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    if !may_manage_media(&data, &identity).await {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let id = path.into_inner().0;
+    match blocking(move || uploads.remove(&id)).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => upload_error_response(e),
+    }
+}
+
+#[get("/media/jobs")]
+async fn list_transcode_jobs(identity: Identity, data: web::Data<AppData>) -> HttpResponse {
+    let transcoder = match &data.transcoder {
+        Some(transcoder) => transcoder,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    if !may_manage_media(&data, &identity).await {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    HttpResponse::Ok().json(transcoder.send(ListJobs).await.unwrap())
+}
+
+#[get("/media/jobs/{id}")]
+async fn get_transcode_job(
+    path: web::Path<(u64,)>,
+    identity: Identity,
+    data: web::Data<AppData>,
+) -> HttpResponse {
+    let transcoder = match &data.transcoder {
+        Some(transcoder) => transcoder,
+        None => return HttpResponse::NotFound().finish(),
+    };
+
+    if !may_manage_media(&data, &identity).await {
+        return HttpResponse::Forbidden().finish();
+    }
+
+    let job = transcoder.send(GetJob(JobId(path.into_inner().0))).await.unwrap();
+
+    match job {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+#[get("/protocol/schema.json")]
+async fn protocol_schema() -> HttpResponse {
+    HttpResponse::Ok().json(crate::schema::json_schema())
+}
+
+#[get("/protocol/protocol.d.ts")]
+async fn protocol_typescript() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/typescript")
+        .body(crate::schema::typescript())
+}
+
+#[get("/create")]
+async fn create_room_page(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let files = data.library.as_ref().map(MediaLibrary::streams).unwrap_or_default();
+
+    CreateRoomTemplate { files }.into_response()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewRoom {
+    /// Also what others join the room with.
+    pub room: String,
+    pub nickname: String,
+    /// Slug of the library entry to play.
+    pub media: String,
+}
+
+/// Starts a room playing media from the library and logs its creator in to it. Only an admin,
+/// logging in from where admins do, gets to create rooms.
+#[post("/create")]
+async fn create_room(
+    req: HttpRequest,
+    params: web::Form<NewRoom>,
+    identity: Identity,
+    data: web::Data<AppData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let library = match &data.library {
+        Some(library) => library,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let from_admin_address = req
+        .connection_info()
+        .realip_remote_addr()
+        .map(|addr| addr.starts_with(&data.admin.address))
+        .unwrap_or(false);
+    if params.nickname != data.admin.nickname || !from_admin_address {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    let code = params.room.trim().to_string();
+    if code.is_empty() {
+        return Ok(HttpResponse::BadRequest().body("room must not be empty"));
+    }
+
+    let stream = match library.find(&params.media) {
+        Some(stream) => stream,
+        None => return Ok(HttpResponse::BadRequest().body("no such media")),
+    };
+
+    if find_local_room(&data, &code).await.is_some() {
+        return Ok(HttpResponse::Conflict().body("room already exists"));
+    }
+
+    match register_room(&data, stream, code.clone()).await {
+        Ok(()) => info!(room = %code, media = %params.media, "room created"),
+        Err(ClaimError::OwnedElsewhere(_)) => return Ok(HttpResponse::Conflict().body("room already exists")),
+        Err(e) => {
+            error!(room = %code, error = ?e, "failed to create room");
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+    }
+
+    let params = params.into_inner();
+    let login_data = LoginData { nickname: params.nickname, avatar: 0, room: code };
+
+    login(req, web::Form(login_data), identity, data).await
+}
+
+/*#[get("/room/{name}")]
+async fn room_page(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let room = find_room(&data.room_repo, &req).await;
+
+    if let Some(room) = room {
+        let name = room.send(GetRoomName).await.unwrap();
+
+        RoomTemplate { name: &name }.into_response()
+    } else {
+        Ok(HttpResponse::NotFound().body("nope"))
+    }
+}*/
+
+#[post("/")]
+async fn index_auth(
+    req: HttpRequest,
+    params: web::Form<LoginData>,
+    identity: Identity,
+    data: web::Data<AppData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let span = info_span!("login", room = %params.room, nickname = %params.nickname);
+
+    login(req, params, identity, data).instrument(span).await
+}
+
+async fn login(
+    req: HttpRequest,
+    params: web::Form<LoginData>,
+    identity: Identity,
+    data: web::Data<AppData>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let room = find_local_room(&data, &params.room).await;
+    let meta = match &room {
+        Some(room) => room.send(GetRoomMeta).await.unwrap(),
+        None => remote_room_meta(&data, &params.room).await,
+    };
+
+    // The random part keeps anyone logging in with the same nickname from taking over the
+    // participant, and with it the host's or an admin's rights.
+    let login = format!("{}-{}", escape_identity_part(&params.nickname), escape_identity_part(&params.room));
+    let new_cookie = match identity.identity() {
+        Some(cookie) if cookie.rsplitn(2, '-').nth(1) == Some(login.as_str()) => {
+            info!(%login, "reusing cookie");
+            cookie
+        }
+        previous => {
+            if previous.is_some() {
+                info!(%login, "invalidating cookie");
+            } else {
+                info!(%login, "remembering new cookie");
+            }
+
+            let cookie = format!("{}-{:032x}", login, rand::random::<u128>());
+            identity.remember(cookie.clone());
+            cookie
+        }
+    };
+
+    if let Some(meta) = meta {
+        if params.nickname == data.admin.nickname {
+            if let Some(addr) = req.connection_info().realip_remote_addr() {
+                debug!(addr, "admin login attempt");
+                if !addr.starts_with(&data.admin.address) {
+                    return
+                        Ok(HttpResponse::NotFound().body("fuck off"))
+                }
+            }
+
+            // Rooms living on other nodes only know about admins that logged in there.
+            if let Some(room) = &room {
+                room.do_send(GrantAdmin(new_cookie.clone()));
+            }
+        }
+
+        let badges = if params.nickname == data.admin.nickname {
+            &[badges::RUBY][..]
+        } else {
+            &[][..]
+        };
+        RoomTemplate {
+            meta,
+            nickname: &params.nickname,
+            avatar: if params.nickname == data.admin.nickname {
+                badges::USER_GRAY
+            } else {
+                BadgeId(params.avatar)
+            },
+            code: &params.room,
+            badges: &badges,
+            badge_data: &BADGE_DATA[..]
+        }.into_response()
+    } else {
+        Ok(HttpResponse::Found()
+            .header(header::LOCATION, "/#retry")
+            .finish())
+    }
+}
+
+#[get("/")]
+async fn index(
+    _req: HttpRequest,
+    identity: Identity,
+) -> Result<NamedFile, actix_web::Error> {
+    let path: PathBuf = "./static/index.html".parse().unwrap();
+    Ok(NamedFile::open(path)?)
+}
+
+/// Who gets to log in as an admin.
+#[derive(Debug, Clone)]
+pub struct AdminConfig {
+    pub nickname: String,
+    /// Prefix of the addresses admins may log in from.
+    pub address: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self {
+        Self {
+            nickname: String::from("tmtu"),
+            address: String::from("192.168.1.1"),
+        }
+    }
+}
+
+impl AdminConfig {
+    /// The default configuration, overridden by `TMTUSYNC_ADMIN_NICKNAME` and
+    /// `TMTUSYNC_ADMIN_ADDRESS`.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+
+        if let Ok(nickname) = std::env::var("TMTUSYNC_ADMIN_NICKNAME") {
+            config.nickname = nickname;
+        }
+        if let Ok(address) = std::env::var("TMTUSYNC_ADMIN_ADDRESS") {
+            config.address = address;
+        }
+
+        config
+    }
+}
+
+#[derive(Clone)]
+pub struct AppData {
+    pub room_repo: Addr<RoomRepository>,
+    pub clock: SharedClock,
+    pub transport: TransportConfig,
+    pub admin: AdminConfig,
+    /// Where rooms keep their audit logs, `None` to not keep any.
+    pub audit_dir: Option<PathBuf>,
+    /// Where to record room sessions for replaying them, `None` to not record.
+    pub record_dir: Option<PathBuf>,
+    /// The cluster this node is part of, `None` when running on its own.
+    pub cluster: Option<Cluster>,
+    /// The media rooms can be created with, `None` without a library.
+    pub library: Option<MediaLibrary>,
+    /// Turns uploads into HLS, `None` to not accept uploads.
+    pub transcoder: Option<Addr<Transcoder>>,
+    /// Uploads on their way to the transcoder, `None` to not accept uploads.
+    pub uploads: Option<UploadStore>,
+    /// Where the HLS data is kept, served to the rooms playing it below `/room/{code}/media`.
+    pub media_dir: PathBuf,
+    /// The master playlists made for the streams in `media_dir` so far.
+    pub master_playlists: hls::MasterPlaylists,
+    /// Signs the URIs in the playlists handed out to participants.
+    pub media_signer: UrlSigner,
+    /// Plays streams hosted elsewhere, `None` to not play any.
+    pub proxy: Option<HlsProxy>,
+}
+
+/// Served to anyone below `/static`.
+const STATIC_DIR: &str = "static";
+
+/// Refuses to serve media from within the static files, where anyone could fetch it. Media used
+/// to live in `static/data`; it goes into the media directory now, `data/media` unless set with
+/// `TMTUSYNC_MEDIA_DIR`, and has to be moved there.
+pub fn check_media_dir(media_dir: &Path) -> io::Result<()> {
+    let legacy = Path::new(STATIC_DIR).join("data");
+    if legacy.exists() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!(
+            "{} would be served to anyone, move the media in it to {}",
+            legacy.display(),
+            media_dir.display(),
+        )));
+    }
+
+    // Either may not exist yet.
+    let absolute = |path: &Path| {
+        fs::canonicalize(path)
+            .or_else(|_| std::env::current_dir().map(|dir| dir.join(path)))
+            .unwrap_or_else(|_| path.to_owned())
+    };
+
+    let (static_dir, media_dir) = (absolute(Path::new(STATIC_DIR)), absolute(media_dir));
+    if media_dir.starts_with(&static_dir) {
+        return Err(io::Error::new(io::ErrorKind::Other, format!(
+            "the media directory {} would be served to anyone, move it out of {}",
+            media_dir.display(),
+            static_dir.display(),
+        )));
+    }
+
+    Ok(())
+}
+
+/// Starts a room playing `stream`. In a cluster the room only starts if this node gets to own
+/// it.
+pub async fn register_room(data: &AppData, stream: MediaStream, code: String) -> Result<(), ClaimError> {
+    let room = Room::with_clock(code.clone(), Some(stream), data.clock.clone());
+
+    start_room(data, room, code).await
+}
+
+/// Brings back a room saved during the last shutdown, or handed over by another node.
+pub async fn restore_room(data: &AppData, snapshot: RoomSnapshot) -> Result<(), ClaimError> {
+    let code = snapshot.name.clone();
+    info!(room = %code, duration = snapshot.duration, "restoring room");
+
+    let room = Room::from_snapshot(snapshot, data.clock.clone());
+
+    start_room(data, room, code).await
+}
+
+async fn start_room(data: &AppData, mut room: Room, code: String) -> Result<(), ClaimError> {
+    if let Some(cluster) = &data.cluster {
+        cluster.claim(&code).await?;
+
+        // Whatever this node handed over before is stale now that it runs the room again.
+        if let Ok(Some(_)) = cluster.directory.take_over(&code, &cluster.node).await {
+            info!(room = %code, "dropped stale handed over room");
+        }
+    }
+
+    if let Some(dir) = &data.audit_dir {
+        match AuditLog::open(dir, &code) {
+            Ok(log) => room = room.with_audit_log(log),
+            Err(e) => error!(room = %code, error = ?e, "failed to open audit log"),
+        }
+    }
+
+    if let Some(dir) = &data.record_dir {
+        let now = Time(data.clock.now().timestamp_millis());
+
+        match Recorder::create(dir, &code, room.stream(), now) {
+            Ok(recorder) => {
+                info!(room = %code, path = %recorder.path().display(), "recording room");
+                room = room.with_recorder(recorder);
+            }
+            Err(e) => error!(room = %code, error = ?e, "failed to start recording"),
+        }
+    }
+
+    let room = room.start();
+
+    data.room_repo.send(RegisterRoom(code, room)).await.unwrap();
+
+    Ok(())
+}
+
+/// The identity service handing out the `auth-cookie` used to tell participants apart.
+pub fn identity_service() -> IdentityService<CookieIdentityPolicy> {
+    IdentityService::new(
+        CookieIdentityPolicy::new(&[0; 32])
+            .name("auth-cookie")
+            .secure(false))
+}
+
+/// Registers the app data and every route. The identity service has to be wrapped around the app
+/// separately, see `identity_service`.
+pub fn configure(data: AppData) -> impl FnOnce(&mut web::ServiceConfig) {
+    move |cfg| {
+        cfg
+            .data(data)
+            .service(room_websocket_session)
+            .service(protocol_schema)
+            .service(protocol_typescript)
+            //.service(room_page)
+            .service(create_room_page)
+            .service(create_room)
+            .service(index)
+            .service(index_auth)
+            .service(metrics_endpoint)
+            .service(cluster_room_meta)
+            .service(room_audit_log)
+            .service(room_master_playlist)
+            .service(room_media)
+            .service(room_remote_media)
+            .service(room_subtitles)
+            .service(upload_media)
+            .service(create_upload)
+            .service(get_upload)
+            .service(put_upload_chunk)
+            .service(delete_upload)
+            .service(list_transcode_jobs)
+            .service(get_transcode_job)
+            .service(actix_files::Files::new("/static", STATIC_DIR));
+    }
+}
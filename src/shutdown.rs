@@ -0,0 +1,91 @@
+//! Graceful shutdown. On SIGTERM or SIGINT every room is paused and saved, participants are told
+//! the server is restarting, and open connections get some time to drain. Saved rooms are
+//! restored on the next start, so participants can reconnect and carry on.
+
+use actix::Addr;
+use actix_web::dev::Server;
+use tracing::{error, info};
+
+use crate::actors::{RoomRepository, RoomSnapshot, ShutdownRooms};
+use crate::cluster::Cluster;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const ROOMS_FILE: &str = "rooms.json";
+
+/// Writes the rooms to `dir`, replacing what was saved before.
+pub fn save(dir: &Path, rooms: &[RoomSnapshot]) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    // Write to a temporary file first so a crash halfway doesn't leave a truncated file behind.
+    let tmp = dir.join(format!("{}.tmp", ROOMS_FILE));
+    fs::write(&tmp, serde_json::to_vec_pretty(rooms)?)?;
+    fs::rename(tmp, dir.join(ROOMS_FILE))
+}
+
+/// Reads the rooms saved by the last shutdown and removes them, so a crash later on doesn't
+/// restore stale state.
+pub fn take(dir: &Path) -> io::Result<Vec<RoomSnapshot>> {
+    let path = dir.join(ROOMS_FILE);
+
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let rooms = serde_json::from_slice(&data)?;
+    fs::remove_file(path)?;
+
+    Ok(rooms)
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to listen for SIGTERM");
+
+    tokio::select! {
+        _ = terminate.recv() => info!("received SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+    info!("received ctrl-c");
+}
+
+/// Waits for a shutdown signal, then saves the rooms to `dir` and stops `server`. The server
+/// should be built with `disable_signals`, and its `shutdown_timeout` bounds how long open
+/// connections may take to drain. In a cluster the rooms are handed over, for other nodes to
+/// take over once participants turn up there.
+pub async fn on_signal(
+    server: Server,
+    room_repo: Addr<RoomRepository>,
+    dir: PathBuf,
+    cluster: Option<Cluster>,
+) {
+    wait_for_signal().await;
+
+    let rooms = room_repo.send(ShutdownRooms).await.unwrap_or_default();
+
+    match save(&dir, &rooms) {
+        Ok(()) => info!(rooms = rooms.len(), path = %dir.display(), "saved rooms"),
+        Err(e) => error!(error = ?e, path = %dir.display(), "failed to save rooms"),
+    }
+
+    if let Some(cluster) = cluster {
+        for room in &rooms {
+            if let Err(e) = cluster.directory.release(&room.name, &cluster.node, Some(room)).await {
+                error!(room = %room.name, error = ?e, "failed to release room");
+            }
+        }
+    }
+
+    server.stop(true).await;
+}
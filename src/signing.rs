@@ -0,0 +1,68 @@
+//! Signed, expiring URLs for the media of a room. Participants fetch media with their identity
+//! cookie, the URIs in the playlists they get are signed as well, so players that don't send the
+//! cookie along, like cast devices, can still fetch the segments until the URLs expire.
+
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+#[derive(Clone)]
+pub struct UrlSigner {
+    key: Vec<u8>,
+    /// How long a URL stays valid after signing it.
+    ttl: Duration,
+}
+
+impl UrlSigner {
+    pub fn new(key: impl Into<Vec<u8>>, ttl: Duration) -> Self {
+        Self { key: key.into(), ttl }
+    }
+
+    /// A signer with a random key, signed URLs don't survive a restart.
+    pub fn random(ttl: Duration) -> Self {
+        Self::new(rand::random::<[u8; 32]>().to_vec(), ttl)
+    }
+
+    /// A random key with URLs valid for 6 hours, overridden by `TMTUSYNC_MEDIA_KEY` and by
+    /// `TMTUSYNC_MEDIA_URL_TTL` in seconds. A fixed key is needed for URLs to survive restarts.
+    pub fn from_env() -> Self {
+        let ttl = std::env::var("TMTUSYNC_MEDIA_URL_TTL")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .map(Duration::seconds)
+            .unwrap_or_else(|| Duration::hours(6));
+
+        match std::env::var("TMTUSYNC_MEDIA_KEY") {
+            Ok(key) if !key.is_empty() => Self::new(key.into_bytes(), ttl),
+            _ => Self::random(ttl),
+        }
+    }
+
+    fn signature(&self, room: &str, path: &str, expires: i64) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC takes keys of any length");
+        mac.update(format!("{}\n{}\n{}", room, path, expires).as_bytes());
+
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+
+    /// The query string signing `path` of the media of `room`, relative to the directory of the
+    /// stream, valid until the TTL has passed after `now`.
+    pub fn query(&self, room: &str, path: &str, now: DateTime<Utc>) -> String {
+        let expires = (now + self.ttl).timestamp();
+
+        format!("expires={}&signature={}", expires, self.signature(room, path, expires))
+    }
+
+    /// Whether `signature` was handed out for `path` of `room` and hasn't expired at `now`.
+    pub fn verify(&self, room: &str, path: &str, expires: i64, signature: &str, now: DateTime<Utc>) -> bool {
+        if expires < now.timestamp() {
+            return false;
+        }
+
+        let expected = self.signature(room, path, expires);
+
+        // Compared in constant time, to not give away how much of a guess was right.
+        expected.len() == signature.len()
+            && expected.bytes().zip(signature.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
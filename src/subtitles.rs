@@ -0,0 +1,357 @@
+//! Subtitle sidecars kept next to the HLS data of a stream. SubRip, WebVTT and ASS files are
+//! picked up and served to players as WebVTT, shifted by the room's subtitle offset.
+
+use thiserror::Error;
+
+use crate::protocol::SubtitleTrack;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Error, Debug)]
+pub enum SubtitleError {
+    #[error("no such subtitle track")]
+    NotFound,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubtitleFormat {
+    Srt,
+    Vtt,
+    Ass,
+}
+
+impl SubtitleFormat {
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+
+        match extension.as_str() {
+            "srt" => Some(SubtitleFormat::Srt),
+            "vtt" => Some(SubtitleFormat::Vtt),
+            "ass" | "ssa" => Some(SubtitleFormat::Ass),
+            _ => None,
+        }
+    }
+}
+
+/// The language code at the end of a file stem like `movie.en` or `pt-BR`.
+fn language(stem: &str) -> Option<String> {
+    let code = stem.rsplit('.').next()?;
+    let primary = code.split('-').next()?;
+
+    if (2..=3).contains(&primary.len()) && primary.chars().all(|c| c.is_ascii_alphabetic()) {
+        Some(code.to_string())
+    } else {
+        None
+    }
+}
+
+/// Every subtitle sidecar in `dir`, sorted by file name. Tracks are identified by their file
+/// name, a directory that doesn't exist has none.
+pub fn discover(dir: &Path) -> io::Result<Vec<SubtitleTrack>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut tracks = Vec::new();
+
+    for entry in entries {
+        let path = entry?.path();
+
+        if !path.is_file() || SubtitleFormat::from_path(&path).is_none() {
+            continue;
+        }
+
+        let (id, stem) = match (path.file_name().and_then(|n| n.to_str()), path.file_stem().and_then(|s| s.to_str())) {
+            (Some(id), Some(stem)) => (id, stem),
+            _ => continue,
+        };
+
+        tracks.push(SubtitleTrack {
+            id: id.to_string(),
+            label: stem.to_string(),
+            language: language(stem),
+        });
+    }
+
+    tracks.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(tracks)
+}
+
+/// Reads the track `id` from `dir` and converts it to WebVTT, shifting every cue by `offset`
+/// seconds.
+pub fn load(dir: &Path, id: &str, offset: f32) -> Result<String, SubtitleError> {
+    // Only tracks that were discovered, anything else could point outside the directory.
+    if !discover(dir)?.iter().any(|t| t.id == id) {
+        return Err(SubtitleError::NotFound);
+    }
+
+    let path = dir.join(id);
+    let format = SubtitleFormat::from_path(&path).ok_or(SubtitleError::NotFound)?;
+    let data = fs::read(&path)?;
+
+    Ok(to_webvtt(format, &String::from_utf8_lossy(&data), offset))
+}
+
+/// A single cue, with times in seconds.
+#[derive(Debug, Clone)]
+struct Cue {
+    id: Option<String>,
+    start: f64,
+    end: f64,
+    /// WebVTT cue settings following the timing.
+    settings: String,
+    text: String,
+}
+
+/// Converts subtitles in `format` to WebVTT, shifting every cue by `offset` seconds. Cues
+/// ending up before the start are dropped.
+pub fn to_webvtt(format: SubtitleFormat, text: &str, offset: f32) -> String {
+    let text = text.trim_start_matches('\u{feff}').replace("\r\n", "\n").replace('\r', "\n");
+
+    let (header, mut cues) = match format {
+        SubtitleFormat::Srt => (Vec::new(), parse_srt(&text)),
+        SubtitleFormat::Vtt => parse_vtt(&text),
+        SubtitleFormat::Ass => (Vec::new(), parse_ass(&text)),
+    };
+
+    cues.sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap_or(std::cmp::Ordering::Equal));
+
+    let mut out = String::from("WEBVTT\n\n");
+
+    for block in header {
+        out.push_str(&block);
+        out.push_str("\n\n");
+    }
+
+    for cue in cues {
+        let start = (cue.start + offset as f64).max(0.0);
+        let end = cue.end + offset as f64;
+
+        if end <= start {
+            continue;
+        }
+
+        if let Some(id) = &cue.id {
+            out.push_str(id);
+            out.push('\n');
+        }
+
+        out.push_str(&format!("{} --> {}", format_timestamp(start), format_timestamp(end)));
+        if !cue.settings.is_empty() {
+            out.push(' ');
+            out.push_str(&cue.settings);
+        }
+
+        out.push('\n');
+        out.push_str(&cue.text);
+        out.push_str("\n\n");
+    }
+
+    out
+}
+
+/// Parses `hh:mm:ss.ttt`, `mm:ss.ttt` or SubRip's `hh:mm:ss,ttt` into seconds.
+fn parse_timestamp(timestamp: &str) -> Option<f64> {
+    let timestamp = timestamp.trim().replace(',', ".");
+    let mut parts = timestamp.rsplit(':');
+
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    let minutes: f64 = parts.next()?.parse().ok()?;
+    let hours: f64 = match parts.next() {
+        Some(hours) => hours.parse().ok()?,
+        None => 0.0,
+    };
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+pub(crate) fn format_timestamp(seconds: f64) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000,
+    )
+}
+
+/// Parses a `start --> end settings` line.
+fn parse_timing(line: &str) -> Option<(f64, f64, &str)> {
+    let (start, rest) = line.split_once("-->")?;
+    let rest = rest.trim();
+    let (end, settings) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+
+    Some((parse_timestamp(start)?, parse_timestamp(end)?, settings.trim()))
+}
+
+fn blocks(text: &str) -> impl Iterator<Item = Vec<&str>> {
+    text.split("\n\n")
+        .map(|block| block.lines().filter(|l| !l.trim().is_empty()).collect::<Vec<_>>())
+        .filter(|lines| !lines.is_empty())
+}
+
+/// Removes what WebVTT doesn't understand from SubRip text: `<font>` tags and ASS style
+/// overrides like `{\an8}`. `<b>`, `<i>` and `<u>` mean the same in both.
+fn clean_srt_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(idx) = rest.find(|c| c == '<' || c == '{') {
+        out.push_str(&rest[..idx]);
+        rest = &rest[idx..];
+
+        let starts_with = |tag: &str| rest.get(..tag.len()).map_or(false, |s| s.eq_ignore_ascii_case(tag));
+        let font = starts_with("<font") || starts_with("</font");
+        let close = if rest.starts_with("{\\") { Some('}') } else if font { Some('>') } else { None };
+
+        match close.and_then(|c| rest.find(c)) {
+            Some(end) => rest = &rest[end + 1..],
+            None => {
+                out.push_str(&rest[..1]);
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn parse_srt(text: &str) -> Vec<Cue> {
+    let mut cues = Vec::new();
+
+    for lines in blocks(text) {
+        // The counter in front of the timing is optional in the wild.
+        let timing = match lines.iter().position(|l| l.contains("-->")) {
+            Some(idx) if idx <= 1 => idx,
+            _ => continue,
+        };
+
+        if let Some((start, end, _)) = parse_timing(lines[timing]) {
+            cues.push(Cue {
+                id: None,
+                start,
+                end,
+                settings: String::new(),
+                text: clean_srt_text(&lines[timing + 1..].join("\n")).replace("-->", "->"),
+            });
+        }
+    }
+
+    cues
+}
+
+/// Returns the `STYLE` and `REGION` blocks to keep and every cue.
+fn parse_vtt(text: &str) -> (Vec<String>, Vec<Cue>) {
+    let mut header = Vec::new();
+    let mut cues = Vec::new();
+
+    for lines in blocks(text) {
+        let first = lines[0].trim();
+
+        if first.starts_with("WEBVTT") || first.starts_with("NOTE") {
+            continue;
+        }
+        if first.starts_with("STYLE") || first.starts_with("REGION") {
+            header.push(lines.join("\n"));
+            continue;
+        }
+
+        let (id, timing) = if first.contains("-->") {
+            (None, 0)
+        } else if lines.len() > 1 && lines[1].contains("-->") {
+            (Some(first.to_string()), 1)
+        } else {
+            continue;
+        };
+
+        if let Some((start, end, settings)) = parse_timing(lines[timing]) {
+            cues.push(Cue {
+                id,
+                start,
+                end,
+                settings: settings.to_string(),
+                text: lines[timing + 1..].join("\n"),
+            });
+        }
+    }
+
+    (header, cues)
+}
+
+/// Plain text of an ASS dialogue line, with override blocks removed and line breaks kept.
+fn clean_ass_text(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut in_override = false;
+
+    for c in text.chars() {
+        match c {
+            '{' => in_override = true,
+            '}' if in_override => in_override = false,
+            _ if in_override => {}
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            c => out.push(c),
+        }
+    }
+
+    out.replace("\\N", "\n").replace("\\n", "\n").replace("\\h", "\u{a0}")
+}
+
+fn parse_ass(text: &str) -> Vec<Cue> {
+    let mut cues = Vec::new();
+    let mut in_events = false;
+    let mut format: Vec<String> = Vec::new();
+
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_events = line.eq_ignore_ascii_case("[events]");
+            continue;
+        }
+
+        if !in_events {
+            continue;
+        }
+
+        if let Some(fields) = line.strip_prefix("Format:") {
+            format = fields.split(',').map(|f| f.trim().to_ascii_lowercase()).collect();
+        } else if let Some(fields) = line.strip_prefix("Dialogue:") {
+            if format.is_empty() {
+                continue;
+            }
+
+            // The text is the last field and may contain commas itself.
+            let fields = fields.trim_start().splitn(format.len(), ',').collect::<Vec<_>>();
+            let field = |name: &str| format.iter().position(|f| f == name).and_then(|i| fields.get(i));
+
+            let (start, end, text) = match (field("start"), field("end"), field("text")) {
+                (Some(start), Some(end), Some(text)) => (start, end, text),
+                _ => continue,
+            };
+
+            if let (Some(start), Some(end)) = (parse_timestamp(start), parse_timestamp(end)) {
+                let text = clean_ass_text(text);
+
+                if !text.trim().is_empty() {
+                    cues.push(Cue { id: None, start, end, settings: String::new(), text });
+                }
+            }
+        }
+    }
+
+    cues
+}
@@ -0,0 +1,13 @@
+//! Log output setup. Logs are filtered through `RUST_LOG` like before, and written as JSON
+//! instead of plain text when `TMTUSYNC_LOG_FORMAT=json` is set.
+
+use tracing_subscriber::EnvFilter;
+
+pub fn init() {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::from_default_env());
+
+    match std::env::var("TMTUSYNC_LOG_FORMAT").as_deref() {
+        Ok("json") => builder.json().init(),
+        _ => builder.init(),
+    }
+}
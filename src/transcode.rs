@@ -0,0 +1,604 @@
+//! Turning uploaded media into HLS with a local ffmpeg. Every input becomes a ladder of
+//! renditions, each with its own playlist, plus a master playlist listing all of them. Inputs
+//! with several audio streams get one audio-only rendition per stream in an `EXT-X-MEDIA`
+//! group, the video renditions are left without audio then.
+//!
+//! The output for a slug is written to `{media_dir}/{slug}`, next to the streams prepared by
+//! hand, so it is served the same way. Trickplay thumbnails are generated into it afterwards.
+
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+use crate::actors::{MediaSource, MediaStream, StreamMetadata};
+use crate::protocol::Stream;
+use crate::trickplay::{self, TrickplayConfig};
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+pub const MASTER_PLAYLIST: &str = "master.m3u8";
+/// The `GROUP-ID` of the audio tracks of inputs with several audio streams.
+pub const AUDIO_GROUP: &str = "audio";
+/// H.264 Main profile at level 4.0, as ffmpeg is told to encode, enough for 1080p.
+const VIDEO_CODEC: &str = "avc1.4d4028";
+/// AAC-LC.
+const AUDIO_CODEC: &str = "mp4a.40.2";
+
+/// What a transcoding run is busy with.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Encoding the renditions.
+    Transcoding,
+    /// Taking the trickplay thumbnails, once the renditions are done.
+    Thumbnails,
+}
+
+#[derive(Error, Debug)]
+pub enum TranscodeError {
+    #[error("failed to run ffmpeg: {0}")]
+    Io(#[from] io::Error),
+    #[error("unreadable input: {0}")]
+    Probe(String),
+    #[error("input has no video")]
+    NoVideo,
+    #[error("ffmpeg failed: {0}")]
+    Ffmpeg(String),
+}
+
+/// One step of the quality ladder.
+#[derive(Debug, Clone)]
+pub struct Rendition {
+    pub height: u32,
+    /// In kbit/s.
+    pub video_bitrate: u32,
+    /// In kbit/s.
+    pub audio_bitrate: u32,
+}
+
+impl Rendition {
+    pub fn new(height: u32, video_bitrate: u32, audio_bitrate: u32) -> Self {
+        Self { height, video_bitrate, audio_bitrate }
+    }
+
+    /// Also the directory the rendition is written to.
+    pub fn name(&self) -> String {
+        format!("{}p", self.height)
+    }
+
+    pub fn playlist(&self) -> String {
+        format!("{}/index.m3u8", self.name())
+    }
+
+    fn max_video_bitrate(&self) -> u32 {
+        self.video_bitrate * 107 / 100
+    }
+
+    /// Peak bit rate in bit/s, as announced in the master playlist.
+    fn bandwidth(&self) -> u32 {
+        (self.max_video_bitrate() + self.audio_bitrate) * 1000
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TranscodeConfig {
+    pub ffmpeg: PathBuf,
+    pub ffprobe: PathBuf,
+    /// Where the HLS output goes, served to the rooms playing it. Kept out of `static/`, which
+    /// is served to anyone.
+    pub media_dir: PathBuf,
+    /// Renditions taller than the input are skipped, there's no point in upscaling.
+    pub renditions: Vec<Rendition>,
+    /// Target length of a segment in seconds.
+    pub segment_duration: u32,
+    pub trickplay: TrickplayConfig,
+}
+
+impl Default for TranscodeConfig {
+    fn default() -> Self {
+        Self {
+            ffmpeg: PathBuf::from("ffmpeg"),
+            ffprobe: PathBuf::from("ffprobe"),
+            media_dir: PathBuf::from("data/media"),
+            renditions: vec![
+                Rendition::new(1080, 5000, 192),
+                Rendition::new(720, 2800, 128),
+                Rendition::new(480, 1400, 128),
+                Rendition::new(360, 800, 96),
+            ],
+            segment_duration: 6,
+            trickplay: TrickplayConfig::default(),
+        }
+    }
+}
+
+impl TranscodeConfig {
+    /// The default configuration, with the tools and output directory overridden by
+    /// `TMTUSYNC_FFMPEG`, `TMTUSYNC_FFPROBE` and `TMTUSYNC_MEDIA_DIR`, and the seconds between
+    /// two thumbnails by `TMTUSYNC_THUMBNAIL_INTERVAL`.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+
+        if let Some(path) = std::env::var_os("TMTUSYNC_FFMPEG") {
+            config.ffmpeg = path.into();
+        }
+        if let Some(path) = std::env::var_os("TMTUSYNC_FFPROBE") {
+            config.ffprobe = path.into();
+        }
+        if let Some(path) = std::env::var_os("TMTUSYNC_MEDIA_DIR") {
+            config.media_dir = path.into();
+        }
+        if let Some(interval) = std::env::var("TMTUSYNC_THUMBNAIL_INTERVAL").ok().and_then(|i| i.parse().ok()).filter(|i| *i > 0) {
+            config.trickplay.interval = interval;
+        }
+
+        config
+    }
+}
+
+/// An audio stream of an input.
+#[derive(Debug, Clone, Default)]
+pub struct ProbeAudio {
+    /// As tagged in the input, usually an ISO 639-2 code like `eng`.
+    pub language: Option<String>,
+    pub title: Option<String>,
+}
+
+/// What ffprobe found out about an input.
+#[derive(Debug, Clone)]
+pub struct Probe {
+    /// In seconds.
+    pub duration: f32,
+    pub width: u32,
+    pub height: u32,
+    pub audio: Vec<ProbeAudio>,
+}
+
+impl Probe {
+    /// Whether the audio streams go into renditions of their own.
+    pub fn has_separate_audio(&self) -> bool {
+        self.audio.len() > 1
+    }
+}
+
+#[derive(Deserialize)]
+struct ProbeOutput {
+    #[serde(default)]
+    streams: Vec<ProbeStream>,
+    format: Option<ProbeFormat>,
+}
+
+#[derive(Deserialize)]
+struct ProbeStream {
+    codec_type: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    #[serde(default)]
+    tags: ProbeTags,
+}
+
+#[derive(Deserialize, Default)]
+struct ProbeTags {
+    language: Option<String>,
+    title: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ProbeFormat {
+    duration: Option<String>,
+}
+
+pub async fn probe(config: &TranscodeConfig, input: &Path) -> Result<Probe, TranscodeError> {
+    let output = Command::new(&config.ffprobe)
+        .args(&["-v", "error", "-print_format", "json", "-show_format", "-show_streams"])
+        .arg(input)
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(TranscodeError::Probe(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+
+    let probed: ProbeOutput = serde_json::from_slice(&output.stdout)
+        .map_err(|e| TranscodeError::Probe(e.to_string()))?;
+
+    let (width, height) = probed.streams
+        .iter()
+        .filter(|s| s.codec_type == "video")
+        .find_map(|s| match (s.width, s.height) {
+            (Some(width), Some(height)) if width > 0 && height > 0 => Some((width, height)),
+            _ => None,
+        })
+        .ok_or(TranscodeError::NoVideo)?;
+
+    let duration = probed.format
+        .and_then(|f| f.duration)
+        .and_then(|d| d.parse().ok())
+        .ok_or_else(|| TranscodeError::Probe(String::from("unknown duration")))?;
+
+    Ok(Probe {
+        duration,
+        width,
+        height,
+        audio: probed.streams
+            .iter()
+            .filter(|s| s.codec_type == "audio")
+            .map(|s| ProbeAudio {
+                language: s.tags.language.clone().filter(|l| l != "und"),
+                title: s.tags.title.clone(),
+            })
+            .collect(),
+    })
+}
+
+/// The renditions to produce for an input, never upscaling. Inputs smaller than the whole ladder
+/// get its lowest step at their own height.
+pub fn select_renditions(config: &TranscodeConfig, probe: &Probe) -> Vec<Rendition> {
+    let mut selected = config.renditions
+        .iter()
+        .filter(|r| r.height <= probe.height)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    if selected.is_empty() {
+        if let Some(lowest) = config.renditions.iter().min_by_key(|r| r.height) {
+            // x264 wants even dimensions.
+            selected.push(Rendition { height: probe.height & !1, ..lowest.clone() });
+        }
+    }
+
+    selected
+}
+
+/// Width of a rendition keeping the input's aspect ratio, rounded to even like `scale=-2:h`.
+fn rendition_width(probe: &Probe, rendition: &Rendition) -> u32 {
+    let width = (probe.width as f32 * rendition.height as f32 / probe.height as f32).round() as u32;
+
+    width + width % 2
+}
+
+/// Also the directory the audio track is written to.
+fn audio_track_dir(index: usize) -> String {
+    format!("audio{}", index)
+}
+
+fn audio_track_playlist(index: usize) -> String {
+    format!("{}/index.m3u8", audio_track_dir(index))
+}
+
+/// The `NAME` of every audio track, from its title or language. Names are unique, since players
+/// pick tracks by them.
+pub fn audio_track_names(audio: &[ProbeAudio]) -> Vec<String> {
+    let names = audio
+        .iter()
+        .enumerate()
+        .map(|(i, a)| a.title.clone().or_else(|| a.language.clone()).unwrap_or_else(|| format!("Track {}", i + 1)))
+        .collect::<Vec<_>>();
+
+    names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| if names.iter().filter(|n| *n == name).count() > 1 {
+            format!("{} ({})", name, i + 1)
+        } else {
+            name.clone()
+        })
+        .collect()
+}
+
+/// Arguments for a single ffmpeg run writing every rendition, and every audio track of inputs
+/// with several, into its own directory below `dir`. Progress is written to stdout.
+pub fn ffmpeg_args(
+    config: &TranscodeConfig,
+    input: &Path,
+    dir: &Path,
+    renditions: &[Rendition],
+    audio: &[ProbeAudio],
+) -> Vec<String> {
+    // Muxed into every rendition when there is a single audio stream.
+    let has_audio = audio.len() == 1;
+    let separate_audio = audio.len() > 1;
+
+    let mut args = ["-hide_banner", "-nostats", "-loglevel", "error", "-progress", "pipe:1", "-y", "-i"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+    args.push(input.display().to_string());
+
+    for _ in renditions {
+        args.extend(vec![String::from("-map"), String::from("0:v:0")]);
+
+        if has_audio {
+            args.extend(vec![String::from("-map"), String::from("0:a:0")]);
+        }
+    }
+
+    if separate_audio {
+        for i in 0..audio.len() {
+            args.extend(vec![String::from("-map"), format!("0:a:{}", i)]);
+        }
+    }
+
+    for (i, rendition) in renditions.iter().enumerate() {
+        args.extend(vec![
+            format!("-filter:v:{}", i),
+            format!("scale=-2:{}", rendition.height),
+            format!("-b:v:{}", i),
+            format!("{}k", rendition.video_bitrate),
+            format!("-maxrate:v:{}", i),
+            format!("{}k", rendition.max_video_bitrate()),
+            format!("-bufsize:v:{}", i),
+            format!("{}k", rendition.video_bitrate * 3 / 2),
+        ]);
+
+        if has_audio {
+            args.extend(vec![format!("-b:a:{}", i), format!("{}k", rendition.audio_bitrate)]);
+        }
+    }
+
+    if separate_audio {
+        let bitrate = renditions.iter().map(|r| r.audio_bitrate).max().unwrap_or(128);
+
+        for i in 0..audio.len() {
+            args.extend(vec![format!("-b:a:{}", i), format!("{}k", bitrate)]);
+        }
+    }
+
+    // Keyframes on every segment boundary, so players can switch renditions between any two
+    // segments.
+    args.extend(vec![
+        String::from("-c:v"),
+        String::from("libx264"),
+        String::from("-profile:v"),
+        String::from("main"),
+        String::from("-level:v"),
+        String::from("4.0"),
+        String::from("-preset"),
+        String::from("veryfast"),
+        String::from("-pix_fmt"),
+        String::from("yuv420p"),
+        String::from("-sc_threshold"),
+        String::from("0"),
+        String::from("-force_key_frames"),
+        format!("expr:gte(t,n_forced*{})", config.segment_duration),
+    ]);
+
+    if has_audio || separate_audio {
+        args.extend(vec![
+            String::from("-c:a"),
+            String::from("aac"),
+            String::from("-ac"),
+            String::from("2"),
+        ]);
+    }
+
+    let stream_map = renditions
+        .iter()
+        .enumerate()
+        .map(|(i, rendition)| if has_audio {
+            format!("v:{},a:{},name:{}", i, i, rendition.name())
+        } else {
+            format!("v:{},name:{}", i, rendition.name())
+        })
+        .chain((0..audio.len()).filter(|_| separate_audio).map(|i| format!("a:{},name:{}", i, audio_track_dir(i))))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    args.extend(vec![
+        String::from("-f"),
+        String::from("hls"),
+        String::from("-hls_time"),
+        config.segment_duration.to_string(),
+        String::from("-hls_playlist_type"),
+        String::from("vod"),
+        String::from("-hls_segment_filename"),
+        dir.join("%v").join("segment%05d.ts").display().to_string(),
+        String::from("-var_stream_map"),
+        stream_map,
+        dir.join("%v").join("index.m3u8").display().to_string(),
+    ]);
+
+    args
+}
+
+/// The master playlist pointing at every rendition, best first, and at the audio tracks of
+/// inputs with several.
+pub fn master_playlist(probe: &Probe, renditions: &[Rendition]) -> String {
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+    let mut audio = String::new();
+
+    if probe.has_separate_audio() {
+        for (i, (track, name)) in probe.audio.iter().zip(audio_track_names(&probe.audio)).enumerate() {
+            let language = track.language.as_ref().map(|l| format!(",LANGUAGE=\"{}\"", l)).unwrap_or_default();
+
+            playlist.push_str(&format!(
+                "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"{}\",NAME=\"{}\"{},DEFAULT={},AUTOSELECT=YES,URI=\"{}\"\n",
+                AUDIO_GROUP,
+                name.replace('"', "'"),
+                language,
+                if i == 0 { "YES" } else { "NO" },
+                audio_track_playlist(i),
+            ));
+        }
+
+        audio = format!(",AUDIO=\"{}\"", AUDIO_GROUP);
+    }
+
+    // Separate audio tracks count as part of every rendition as well.
+    let codecs = if probe.audio.is_empty() {
+        String::from(VIDEO_CODEC)
+    } else {
+        format!("{},{}", VIDEO_CODEC, AUDIO_CODEC)
+    };
+
+    for rendition in renditions {
+        playlist.push_str(&format!(
+            "#EXT-X-STREAM-INF:BANDWIDTH={},RESOLUTION={}x{},CODECS=\"{}\"{}\n{}\n",
+            rendition.bandwidth(),
+            rendition_width(probe, rendition),
+            rendition.height,
+            codecs,
+            audio,
+            rendition.playlist(),
+        ));
+    }
+
+    playlist
+}
+
+/// Seconds of output written so far, from a line of ffmpeg's `-progress` output.
+fn parse_progress(line: &str) -> Option<f32> {
+    let micros = line.strip_prefix("out_time_us=")?.trim().parse::<i64>().ok()?;
+
+    Some(micros.max(0) as f32 / 1_000_000.0)
+}
+
+/// Transcodes `input` into `dir`, calling `progress` with the stage it is at and the share of
+/// the input done in it so far. Returns what ffprobe found out about the input and the streams
+/// written, the master playlist first.
+pub async fn transcode(
+    config: &TranscodeConfig,
+    input: &Path,
+    dir: &Path,
+    mut progress: impl FnMut(Stage, f32),
+) -> Result<(Probe, Vec<Stream>), TranscodeError> {
+    let probe = probe(config, input).await?;
+    let renditions = select_renditions(config, &probe);
+
+    info!(
+        duration = probe.duration,
+        height = probe.height,
+        renditions = renditions.len(),
+        audio_tracks = probe.audio.len(),
+        "transcoding"
+    );
+
+    for rendition in &renditions {
+        fs::create_dir_all(dir.join(rendition.name()))?;
+    }
+    if probe.has_separate_audio() {
+        for i in 0..probe.audio.len() {
+            fs::create_dir_all(dir.join(audio_track_dir(i)))?;
+        }
+    }
+
+    let args = ffmpeg_args(config, input, dir, &renditions, &probe.audio);
+    debug!(?args, "starting ffmpeg");
+
+    let mut child = Command::new(&config.ffmpeg)
+        .args(&args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let stdout = child.stdout.take().expect("stdout is piped");
+    let read_progress = async {
+        let mut lines = BufReader::new(stdout).lines();
+
+        while let Some(Ok(line)) = lines.next().await {
+            if let Some(done) = parse_progress(&line) {
+                progress(Stage::Transcoding, share(done, probe.duration));
+            }
+        }
+    };
+
+    let (_, output) = futures::join!(read_progress, child.wait_with_output());
+    let output = output?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+
+        return Err(TranscodeError::Ffmpeg(if stderr.is_empty() { output.status.to_string() } else { stderr }));
+    }
+
+    fs::write(dir.join(MASTER_PLAYLIST), master_playlist(&probe, &renditions))?;
+
+    // The media plays just fine without previews on the timeline.
+    progress(Stage::Thumbnails, 0.0);
+    if let Err(e) = trickplay::generate(config, input, dir, &probe, |done| progress(Stage::Thumbnails, done)).await {
+        warn!(error = %e, "failed to generate thumbnails");
+    }
+
+    let streams = std::iter::once(Stream { quality: 0, playlist: String::from(MASTER_PLAYLIST) })
+        .chain(renditions.iter().map(|r| Stream { quality: r.height, playlist: r.playlist() }))
+        .collect();
+
+    Ok((probe, streams))
+}
+
+/// How much of `total` is `done`, between 0 and 1. Nothing is done of an unknown or empty
+/// total, rather than reporting NaN or infinity.
+pub fn share(done: f32, total: f32) -> f32 {
+    if !total.is_finite() || total <= 0.0 {
+        return 0.0;
+    }
+
+    (done / total).max(0.0).min(1.0)
+}
+
+/// Formats a length in seconds the way `StreamMetadata::duration` is written, eg. `1h 15m 2s`.
+pub fn format_duration(secs: f32) -> String {
+    let secs = secs.max(0.0).round() as u64;
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+    match (hours, minutes) {
+        (0, 0) => format!("{}s", secs),
+        (0, _) => format!("{}m {}s", minutes, secs),
+        _ => format!("{}h {}m {}s", hours, minutes, secs),
+    }
+}
+
+/// The library entry for media transcoded into `slug`.
+pub fn media_stream(slug: &str, name: &str, probe: &Probe, streams: Vec<Stream>) -> MediaStream {
+    MediaStream {
+        slug: slug.to_string(),
+        name: name.to_string(),
+        streams,
+        meta: StreamMetadata {
+            title: name.to_string(),
+            duration: format_duration(probe.duration),
+            imdb: None,
+        },
+        length: Some(probe.duration),
+        audio_tracks: Vec::new(),
+        subtitles: Vec::new(),
+        thumbnails: None,
+        source: MediaSource::Local,
+    }
+}
+
+/// A slug for `name` that doesn't have a directory in `media_dir` yet.
+pub fn unused_slug(media_dir: &Path, name: &str) -> String {
+    let mut base = String::new();
+
+    for c in name.chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() {
+            base.push(c);
+        } else if !base.is_empty() && !base.ends_with('-') {
+            base.push('-');
+        }
+    }
+
+    let base = match base.trim_end_matches('-') {
+        "" => "media",
+        base => base,
+    };
+
+    let mut slug = base.to_string();
+    let mut n = 1;
+
+    while media_dir.join(&slug).exists() {
+        n += 1;
+        slug = format!("{}-{}", base, n);
+    }
+
+    slug
+}
@@ -0,0 +1,203 @@
+//! Trickplay thumbnails: sprite sheets of small frames taken at a fixed interval, plus a WebVTT
+//! index mapping every frame to its region of a sheet. Players show them when hovering the
+//! timeline. They are written next to the HLS data during ingestion, or from the HLS data itself
+//! for media that came without them.
+
+use futures::StreamExt;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::Command;
+use tracing::debug;
+
+use crate::protocol::Stream;
+use crate::subtitles::format_timestamp;
+use crate::transcode::{self, Probe, TranscodeConfig, TranscodeError};
+
+use std::fs;
+use std::path::Path;
+use std::process::Stdio;
+
+/// Where the sheets go, below the directory of the stream.
+pub const TRICKPLAY_DIR: &str = "trickplay";
+/// The index, relative to the directory of the stream.
+pub const THUMBNAIL_INDEX: &str = "trickplay/thumbnails.vtt";
+
+#[derive(Debug, Clone)]
+pub struct TrickplayConfig {
+    /// Seconds between two thumbnails.
+    pub interval: u32,
+    /// Width of a thumbnail in pixels, the height follows the aspect ratio of the input.
+    pub width: u32,
+    /// Thumbnails per row of a sheet.
+    pub columns: u32,
+    /// Rows per sheet.
+    pub rows: u32,
+}
+
+impl Default for TrickplayConfig {
+    fn default() -> Self {
+        Self {
+            interval: 10,
+            width: 160,
+            columns: 10,
+            rows: 10,
+        }
+    }
+}
+
+/// Size of a thumbnail of an input of `probe`'s size, rounded to even like `scale=w:-2`.
+pub fn thumbnail_size(config: &TrickplayConfig, probe: &Probe) -> (u32, u32) {
+    let height = (config.width as f32 * probe.height as f32 / probe.width as f32).round() as u32;
+
+    (config.width, (height + height % 2).max(2))
+}
+
+fn sheet_name(sheet: u32) -> String {
+    format!("sprite{}.jpg", sheet)
+}
+
+/// Arguments for an ffmpeg run writing the sheets of `input` into `dir`. Every frame going into
+/// a sheet is logged by `showinfo`, see `parse_frame_time`.
+pub fn ffmpeg_args(config: &TrickplayConfig, input: &Path, dir: &Path, size: (u32, u32)) -> Vec<String> {
+    let filter = format!(
+        "fps=1/{},scale={}:{},showinfo,tile={}x{}",
+        config.interval, size.0, size.1, config.columns, config.rows,
+    );
+
+    let mut args = ["-hide_banner", "-nostats", "-loglevel", "info", "-y", "-i"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect::<Vec<_>>();
+
+    args.push(input.display().to_string());
+    args.extend(vec![
+        String::from("-an"),
+        String::from("-sn"),
+        String::from("-vf"),
+        filter,
+        String::from("-q:v"),
+        String::from("5"),
+        String::from("-start_number"),
+        String::from("0"),
+        dir.join(TRICKPLAY_DIR).join("sprite%d.jpg").display().to_string(),
+    ]);
+
+    args
+}
+
+/// The time in seconds of a frame logged by `showinfo`, from a line of ffmpeg's log output.
+pub fn parse_frame_time(line: &str) -> Option<f64> {
+    if !line.contains("Parsed_showinfo") || !line.contains(" n:") {
+        return None;
+    }
+
+    line.split("pts_time:").nth(1)?.split_whitespace().next()?.parse().ok()
+}
+
+/// The WebVTT index of the thumbnails taken at `times` of `duration` seconds of media, each cue
+/// pointing at its region of a sheet with a `#xywh` fragment. A thumbnail is shown until the
+/// next one, the last one until the end.
+pub fn thumbnail_index(config: &TrickplayConfig, times: &[f64], duration: f32, size: (u32, u32)) -> String {
+    let per_sheet = (config.columns * config.rows).max(1);
+
+    let mut index = String::from("WEBVTT\n\n");
+
+    for (i, &start) in times.iter().enumerate() {
+        let start = start.max(0.0);
+        let end = match times.get(i + 1) {
+            Some(&next) => next.max(start),
+            None => (duration as f64).max(start + 1.0),
+        };
+        let i = i as u32;
+        let position = i % per_sheet;
+        let (x, y) = (position % config.columns * size.0, position / config.columns * size.1);
+
+        index.push_str(&format!(
+            "{} --> {}\n{}#xywh={},{},{},{}\n\n",
+            format_timestamp(start),
+            format_timestamp(end),
+            sheet_name(i / per_sheet),
+            x,
+            y,
+            size.0,
+            size.1,
+        ));
+    }
+
+    index
+}
+
+/// Writes the sheets and the index of `input` into `dir`, calling `progress` with the share of
+/// the input done so far. The index lists the frames ffmpeg took, however many that were.
+pub async fn generate(
+    config: &TranscodeConfig,
+    input: &Path,
+    dir: &Path,
+    probe: &Probe,
+    mut progress: impl FnMut(f32),
+) -> Result<(), TranscodeError> {
+    let size = thumbnail_size(&config.trickplay, probe);
+    fs::create_dir_all(dir.join(TRICKPLAY_DIR))?;
+
+    let args = ffmpeg_args(&config.trickplay, input, dir, size);
+    debug!(?args, "generating thumbnails");
+
+    let mut child = Command::new(&config.ffmpeg)
+        .args(&args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let (mut times, mut log) = (Vec::new(), Vec::new());
+    let read_log = async {
+        let mut lines = BufReader::new(stderr).lines();
+
+        while let Some(Ok(line)) = lines.next().await {
+            match parse_frame_time(&line) {
+                Some(time) => {
+                    progress(transcode::share(time as f32, probe.duration));
+                    times.push(time);
+                }
+                None => log.push(line),
+            }
+        }
+    };
+
+    let (_, status) = futures::join!(read_log, child);
+    let status = status?;
+
+    if !status.success() {
+        // Everything but errors is about the input and output, the errors come last.
+        let errors = log.iter().rev().take(5).rev().cloned().collect::<Vec<_>>().join("\n");
+
+        return Err(TranscodeError::Ffmpeg(if errors.is_empty() { status.to_string() } else { errors }));
+    }
+
+    if times.is_empty() {
+        return Err(TranscodeError::Ffmpeg(String::from("no frames for thumbnails")));
+    }
+
+    fs::write(dir.join(THUMBNAIL_INDEX), thumbnail_index(&config.trickplay, &times, probe.duration, size))?;
+
+    Ok(())
+}
+
+/// Writes the sheets and the index of media already in `dir`, taking the frames from its best
+/// rendition. For media added before thumbnails were a thing, or without them for other reasons.
+pub async fn backfill(
+    config: &TranscodeConfig,
+    dir: &Path,
+    streams: &[Stream],
+    progress: impl FnMut(f32),
+) -> Result<(), TranscodeError> {
+    let best = streams
+        .iter()
+        .max_by_key(|s| s.quality)
+        .ok_or_else(|| TranscodeError::Probe(String::from("no playlist to take thumbnails from")))?;
+
+    let input = dir.join(&best.playlist);
+    let probe = transcode::probe(config, &input).await?;
+
+    generate(config, &input, dir, &probe, progress).await
+}
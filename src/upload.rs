@@ -0,0 +1,372 @@
+//! Resumable uploads. A client announces an upload with its name and size, then sends the file
+//! in chunks, each starting at the offset the upload has reached and optionally carrying a
+//! checksum. An upload cut short by a dropped connection or a server restart carries on from the
+//! last byte stored.
+//!
+//! Every upload lives in the upload directory as `{id}.json`, what was announced, and
+//! `{id}.part`, the bytes received so far. Completed uploads are handed to transcoding as
+//! `{id}.upload`, next to the files sent in one go that are waiting for it too.
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::info;
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Error, Debug)]
+pub enum UploadError {
+    #[error("no such upload")]
+    NotFound,
+    #[error("uploads may be at most {0} bytes")]
+    TooLarge(u64),
+    #[error("only {0} bytes of upload space left")]
+    QuotaExceeded(u64),
+    #[error("upload is at offset {0}")]
+    WrongOffset(u64),
+    #[error("another chunk is being written")]
+    Busy,
+    #[error("chunk goes past the announced size")]
+    PastEnd,
+    #[error("unsupported checksum {0:?}, expected `sha256 <hex>`")]
+    BadChecksum(String),
+    #[error("checksum mismatch")]
+    ChecksumMismatch,
+    #[error("upload isn't complete")]
+    Incomplete,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadInfo {
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+    /// Bytes received so far, where the next chunk has to start.
+    pub offset: u64,
+}
+
+impl UploadInfo {
+    pub fn is_complete(&self) -> bool {
+        self.offset == self.size
+    }
+}
+
+/// What a client announced, kept in `{id}.json`.
+#[derive(Serialize, Deserialize)]
+struct Announcement {
+    name: String,
+    size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadLimits {
+    /// In bytes.
+    pub max_size: u64,
+    /// Space all unfinished uploads and files waiting for transcoding may take up together, in
+    /// bytes. Uploads reserve their whole size when announced.
+    pub quota: u64,
+}
+
+impl Default for UploadLimits {
+    fn default() -> Self {
+        Self {
+            max_size: 16 << 30,
+            quota: 64 << 30,
+        }
+    }
+}
+
+impl UploadLimits {
+    /// The default limits, overridden by `TMTUSYNC_MAX_UPLOAD_SIZE` and `TMTUSYNC_UPLOAD_QUOTA`
+    /// in bytes.
+    pub fn from_env() -> Self {
+        let mut limits = Self::default();
+
+        if let Some(bytes) = std::env::var("TMTUSYNC_MAX_UPLOAD_SIZE").ok().and_then(|b| b.parse().ok()) {
+            limits.max_size = bytes;
+        }
+        if let Some(bytes) = std::env::var("TMTUSYNC_UPLOAD_QUOTA").ok().and_then(|b| b.parse().ok()) {
+            limits.quota = bytes;
+        }
+
+        limits
+    }
+}
+
+/// The uploads kept in a directory, shared by every holder of a clone.
+#[derive(Clone)]
+pub struct UploadStore {
+    dir: PathBuf,
+    limits: UploadLimits,
+    /// Uploads a chunk is being written to. Also held while announcing, so the quota can't be
+    /// overrun by announcing several uploads at once.
+    busy: Arc<Mutex<HashSet<String>>>,
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Parses an `Upload-Checksum` header, `sha256` followed by the hex digest of the chunk.
+fn parse_checksum(checksum: &str) -> Result<String, UploadError> {
+    match checksum.trim().split_once(' ') {
+        Some(("sha256", digest)) if digest.len() == 64 && is_hex(digest) => Ok(digest.to_lowercase()),
+        _ => Err(UploadError::BadChecksum(checksum.to_string())),
+    }
+}
+
+impl UploadStore {
+    pub fn new(dir: impl Into<PathBuf>, limits: UploadLimits) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        Ok(Self { dir, limits, busy: Default::default() })
+    }
+
+    /// Where uploads are kept, also until they are transcoded.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn limits(&self) -> &UploadLimits {
+        &self.limits
+    }
+
+    fn announcement_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.part", id))
+    }
+
+    pub fn get(&self, id: &str) -> Result<UploadInfo, UploadError> {
+        // Ids are made up of hex digits, anything else could point outside the directory.
+        if !is_hex(id) {
+            return Err(UploadError::NotFound);
+        }
+
+        let announcement: Announcement = match fs::read(self.announcement_path(id)) {
+            Ok(data) => serde_json::from_slice(&data).map_err(io::Error::from)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(UploadError::NotFound),
+            Err(e) => return Err(e.into()),
+        };
+
+        let offset = match fs::metadata(self.data_path(id)) {
+            Ok(meta) => meta.len().min(announcement.size),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(UploadInfo {
+            id: id.to_string(),
+            name: announcement.name,
+            size: announcement.size,
+            offset,
+        })
+    }
+
+    /// Every unfinished upload, including the ones left over from before a restart.
+    pub fn list(&self) -> Result<Vec<UploadInfo>, UploadError> {
+        let mut uploads = Vec::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+
+            if path.extension().map(|e| e == "json").unwrap_or(false) {
+                if let Some(id) = path.file_stem().and_then(|s| s.to_str()) {
+                    match self.get(id) {
+                        Ok(upload) => uploads.push(upload),
+                        Err(UploadError::NotFound) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+        }
+
+        Ok(uploads)
+    }
+
+    /// Space taken up by unfinished uploads, their whole announced size, and by files waiting
+    /// for transcoding.
+    fn used(&self) -> Result<u64, UploadError> {
+        let mut used = self.list()?.iter().map(|u| u.size).sum::<u64>();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+
+            if entry.path().extension().map(|e| e == "upload").unwrap_or(false) {
+                used += entry.metadata()?.len();
+            }
+        }
+
+        Ok(used)
+    }
+
+    /// Space left for uploads within the quota.
+    pub fn available(&self) -> Result<u64, UploadError> {
+        Ok(self.limits.quota.saturating_sub(self.used()?))
+    }
+
+    /// Announces an upload of `size` bytes, reserving the space for it.
+    pub fn create(&self, name: &str, size: u64) -> Result<UploadInfo, UploadError> {
+        if size > self.limits.max_size {
+            return Err(UploadError::TooLarge(self.limits.max_size));
+        }
+
+        let _busy = self.busy.lock().unwrap();
+
+        let available = self.available()?;
+        if size > available {
+            return Err(UploadError::QuotaExceeded(available));
+        }
+
+        let id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        File::create(self.data_path(&id))?;
+
+        let announcement = Announcement { name: name.to_string(), size };
+        let tmp = self.dir.join(format!("{}.json.tmp", id));
+        fs::write(&tmp, serde_json::to_vec(&announcement).map_err(io::Error::from)?)?;
+        fs::rename(tmp, self.announcement_path(&id))?;
+
+        info!(upload = %id, %name, size, "upload announced");
+
+        Ok(UploadInfo { id, name: name.to_string(), size, offset: 0 })
+    }
+
+    /// Starts writing a chunk at `offset`, which has to be where the upload is at. `checksum` is
+    /// checked once the chunk is finished.
+    pub fn append(&self, id: &str, offset: u64, checksum: Option<&str>) -> Result<ChunkWriter, UploadError> {
+        let checksum = checksum.map(parse_checksum).transpose()?;
+        let upload = self.get(id)?;
+
+        if !self.busy.lock().unwrap().insert(id.to_string()) {
+            return Err(UploadError::Busy);
+        }
+
+        // From here on, dropping the writer frees the upload again.
+        let mut writer = ChunkWriter {
+            store: self.clone(),
+            upload,
+            file: None,
+            written: 0,
+            checksum: checksum.map(|digest| (Sha256::new(), digest)),
+        };
+
+        if writer.upload.offset != offset {
+            return Err(UploadError::WrongOffset(writer.upload.offset));
+        }
+
+        writer.file = Some(OpenOptions::new().append(true).open(self.data_path(id))?);
+
+        Ok(writer)
+    }
+
+    /// Removes a completed upload from the store, returning the file for transcoding. The caller
+    /// takes care of removing the file.
+    pub fn take(&self, id: &str) -> Result<PathBuf, UploadError> {
+        let upload = self.get(id)?;
+
+        if !upload.is_complete() {
+            return Err(UploadError::Incomplete);
+        }
+
+        let path = self.dir.join(format!("{}.upload", id));
+        fs::rename(self.data_path(id), &path)?;
+        fs::remove_file(self.announcement_path(id))?;
+
+        Ok(path)
+    }
+
+    pub fn remove(&self, id: &str) -> Result<(), UploadError> {
+        self.get(id)?;
+
+        if self.busy.lock().unwrap().contains(id) {
+            return Err(UploadError::Busy);
+        }
+
+        fs::remove_file(self.announcement_path(id))?;
+        match fs::remove_file(self.data_path(id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Writes one chunk of an upload. Only one chunk can be written to an upload at a time.
+pub struct ChunkWriter {
+    store: UploadStore,
+    /// The upload as it was before the chunk.
+    upload: UploadInfo,
+    file: Option<File>,
+    written: u64,
+    /// Running digest of the chunk, and the one it should end up with.
+    checksum: Option<(Sha256, String)>,
+}
+
+impl ChunkWriter {
+    pub fn write(&mut self, data: &[u8]) -> Result<(), UploadError> {
+        if self.upload.offset + self.written + data.len() as u64 > self.upload.size {
+            return Err(UploadError::PastEnd);
+        }
+
+        if let Some(file) = &mut self.file {
+            file.write_all(data)?;
+        }
+        if let Some((digest, _)) = &mut self.checksum {
+            digest.update(data);
+        }
+
+        self.written += data.len() as u64;
+
+        Ok(())
+    }
+
+    /// Ends the chunk, checking its checksum. A chunk not matching its checksum is dropped.
+    pub fn finish(mut self) -> Result<UploadInfo, UploadError> {
+        if let Some((digest, expected)) = self.checksum.take() {
+            if format!("{:x}", digest.finalize()) != expected {
+                self.discard()?;
+                return Err(UploadError::ChecksumMismatch);
+            }
+        }
+
+        if let Some(file) = &self.file {
+            file.sync_data()?;
+        }
+
+        Ok(UploadInfo { offset: self.upload.offset + self.written, ..self.upload.clone() })
+    }
+
+    /// Ends a chunk that was cut short. What arrived is kept, unless the chunk came with a
+    /// checksum, which can't be verified now.
+    pub fn interrupted(self) -> Result<UploadInfo, UploadError> {
+        if self.checksum.is_some() {
+            return self.discard();
+        }
+
+        self.finish()
+    }
+
+    /// Drops everything written with this chunk.
+    pub fn discard(&self) -> Result<UploadInfo, UploadError> {
+        if let Some(file) = &self.file {
+            file.set_len(self.upload.offset)?;
+        }
+
+        Ok(self.upload.clone())
+    }
+}
+
+impl Drop for ChunkWriter {
+    fn drop(&mut self) {
+        self.store.busy.lock().unwrap().remove(&self.upload.id);
+    }
+}
@@ -0,0 +1,93 @@
+//! Checks on incoming `UserMessage`s before they reach a room, so rooms only ever see sane
+//! numbers and timestamps.
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::protocol::{ProtocolError, Time, UserMessage};
+
+/// Bounds enforced on everything a participant sends.
+#[derive(Debug, Clone)]
+pub struct ValidationLimits {
+    /// Largest websocket frame accepted, in bytes.
+    pub max_frame_size: usize,
+    /// How far a client timestamp may be from the server's clock.
+    pub max_clock_skew: Duration,
+    /// Longest allowed participant name, in characters.
+    pub max_name_length: usize,
+    /// Largest subtitle offset either way, in seconds.
+    pub max_subtitle_offset: f32,
+}
+
+impl Default for ValidationLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 16 * 1024,
+            max_clock_skew: Duration::hours(24),
+            max_name_length: 64,
+            max_subtitle_offset: 600.0,
+        }
+    }
+}
+
+fn check_number(field: &str, value: f32) -> Result<(), ProtocolError> {
+    if value.is_finite() && value >= 0.0 {
+        Ok(())
+    } else {
+        Err(ProtocolError::InvalidNumber { field: field.to_string() })
+    }
+}
+
+fn check_time(
+    field: &str,
+    time: Time,
+    now: DateTime<Utc>,
+    limits: &ValidationLimits,
+) -> Result<(), ProtocolError> {
+    let skew = limits.max_clock_skew.num_milliseconds();
+    let now = now.timestamp_millis();
+
+    if time.0.checked_sub(now).and_then(i64::checked_abs).map_or(false, |d| d <= skew) {
+        Ok(())
+    } else {
+        Err(ProtocolError::InvalidTime { field: field.to_string() })
+    }
+}
+
+/// Checks that every value in the message is usable. Positions past the end of the media are
+/// left for the room to clamp, since only it knows what is playing.
+pub fn validate(
+    message: &UserMessage,
+    now: DateTime<Utc>,
+    limits: &ValidationLimits,
+) -> Result<(), ProtocolError> {
+    match message {
+        UserMessage::Hello { name, time, .. } => {
+            let length = name.trim().chars().count();
+            if length == 0 || length > limits.max_name_length {
+                return Err(ProtocolError::InvalidName { max_length: limits.max_name_length });
+            }
+
+            check_time("time", *time, now, limits)
+        }
+        UserMessage::Goodbye => Ok(()),
+        UserMessage::State { duration, duration_time, state_time, buffered, time, .. } => {
+            check_number("duration", *duration)?;
+            check_number("buffered", *buffered)?;
+            check_time("duration_time", *duration_time, now, limits)?;
+            check_time("state_time", *state_time, now, limits)?;
+            check_time("time", *time, now, limits)
+        }
+        UserMessage::Seek { duration, time } => {
+            check_number("duration", *duration)?;
+            check_time("time", *time, now, limits)
+        }
+        UserMessage::SetState { time, .. } => check_time("time", *time, now, limits),
+        UserMessage::SetSubtitles { offset, .. } => {
+            if offset.is_finite() && offset.abs() <= limits.max_subtitle_offset {
+                Ok(())
+            } else {
+                Err(ProtocolError::InvalidNumber { field: String::from("offset") })
+            }
+        }
+    }
+}
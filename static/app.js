@@ -1,3 +1,10 @@
+// Must match `PROTOCOL_VERSION` in protocol.rs.
+var PROTOCOL_VERSION = 2;
+// Optional protocol features this client understands.
+var PROTOCOL_FEATURES = ["quality"];
+// How long to wait before reconnecting after the server restarted, in milliseconds.
+var RECONNECT_DELAY = 2000;
+
 function createBadge(id) {
     var i = document.createElement('i');
     i.classList.add("sprite");
@@ -30,6 +37,51 @@ function secondsToTime(seconds) {
     // return h.toString().padStart(2, "0") + ":" + m.toString().padStart(2, "0") + ":" + s.toString().padStart(2, "0");
 }
 
+function qualityToText(quality, auto) {
+    var text = quality != null ? quality + "p" : "";
+
+    return auto ? "Auto" + (text ? " (" + text + ")" : "") : text;
+}
+
+// Seconds from a WebVTT timestamp like "01:02:03.450" or "02:03.450".
+function parseTimestamp(timestamp) {
+    return timestamp.trim().split(":").reduce((total, part) => total * 60 + parseFloat(part), 0);
+}
+
+// The cues of a WebVTT thumbnail index, each pointing at a region of a sprite sheet like
+// "sprite0.jpg#xywh=0,0,160,90". Sheets are relative to the index at `base`.
+function parseThumbnails(text, base) {
+    var thumbnails = [];
+
+    text.replace(/\r\n/g, "\n").split(/\n\n+/).forEach((block) => {
+        var lines = block.trim().split("\n");
+        var timing = lines.findIndex((line) => line.includes("-->"));
+        if (timing < 0 || timing + 1 >= lines.length) {
+            return;
+        }
+
+        var times = lines[timing].split("-->");
+        var url = new URL(lines[timing + 1].trim(), base);
+        var xywh = url.hash.match(/^#xywh=(\d+),(\d+),(\d+),(\d+)$/);
+        if (xywh == null) {
+            return;
+        }
+        url.hash = "";
+
+        thumbnails.push({
+            start: parseTimestamp(times[0]),
+            end: parseTimestamp(times[1].trim().split(/\s+/)[0]),
+            url: url.href,
+            x: parseInt(xywh[1]),
+            y: parseInt(xywh[2]),
+            width: parseInt(xywh[3]),
+            height: parseInt(xywh[4]),
+        });
+    });
+
+    return thumbnails;
+}
+
 function bufferedFromPosition(video, pos) {
     var bufferedRanges = video.buffered;
 
@@ -148,6 +200,8 @@ Participant = function(userlist_table, user_id, name, is_me, avatar, badges) {
     this.name_col = document.createElement('td');
     this.time_col = document.createElement('td');
     this.buffered_col = document.createElement('td');
+    this.quality_col = document.createElement('td');
+    this.audio_col = document.createElement('td');
     this.state_col = document.createElement('td');
     this.badge_col = document.createElement('td');
 
@@ -171,6 +225,8 @@ Participant = function(userlist_table, user_id, name, is_me, avatar, badges) {
     this.user_row.appendChild(this.name_col);
     this.user_row.appendChild(this.time_col);
     this.user_row.appendChild(this.buffered_col);
+    this.user_row.appendChild(this.quality_col);
+    this.user_row.appendChild(this.audio_col);
     this.user_row.appendChild(this.state_col);
     this.user_row.appendChild(this.badge_col);
 
@@ -198,6 +254,9 @@ Participant.prototype.Update = function(update) {
     this.badges = update.badges;
     this.duration = update.duration;
     this.buffered = update.buffered;
+    this.quality = update.quality;
+    this.auto_quality = update.auto_quality;
+    this.audio = update.audio;
     this.state = update.state;
 
     this.UpdateColumn();
@@ -209,6 +268,9 @@ Participant.prototype.UpdateSelf = function(update) {
     this.oldbadges = this.badges;
     this.duration = update.duration;
     this.buffered = update.buffered;
+    this.quality = update.quality;
+    this.auto_quality = update.auto_quality;
+    this.audio = update.audio;
     this.state = update.state;
 
     this.UpdateColumn();
@@ -217,6 +279,8 @@ Participant.prototype.UpdateSelf = function(update) {
 Participant.prototype.UpdateColumn = function() {
     this.time_col.innerText = secondsToTime(this.duration);
     this.buffered_col.innerText = secondsToTime(this.buffered);
+    this.quality_col.innerText = qualityToText(this.quality, this.auto_quality);
+    this.audio_col.innerText = this.audio || "";
 
     this.state_col.innerHTML = '';
     if (this.state == "Play") {
@@ -249,6 +313,24 @@ Room = function(video, title, fold, userlist, logcontainer, loglist) {
     this.logbody = loglist.getElementsByTagName('tbody')[0];
     this.logcontainer = logcontainer;
     this.hls = setupHls(video);
+    // The renditions of the stream, best first, in the order of the levels of the master playlist.
+    this.qualities = [];
+    this.qualitySelect = document.getElementById("quality-select");
+    this.qualitySelect.addEventListener("change", this.OnQualityChange.bind(this));
+    this.audioTracks = [];
+    this.audioSelect = document.getElementById("audio-select");
+    this.audioSelect.addEventListener("change", this.OnAudioChange.bind(this));
+    if (this.hls != null) {
+        this.hls.on(Hls.Events.AUDIO_TRACKS_UPDATED, this.OnAudioTracksUpdated.bind(this));
+    }
+    this.subtitles = [];
+    this.roomSubtitle = null;
+    this.subtitleOffset = 0;
+    this.subtitleSelect = document.getElementById("subtitle-select");
+    this.subtitleSelect.addEventListener("change", this.OnSubtitleChange.bind(this));
+    this.subtitleOffsetInput = document.getElementById("subtitle-offset");
+    this.subtitleOffsetInput.addEventListener("change", this.OnSubtitleOffsetChange.bind(this));
+    document.getElementById("subtitle-default").addEventListener("click", this.OnSubtitleDefaultClick.bind(this));
     this.participants = [];
     this.blockEvents = false;
     this.inSeek = false;
@@ -267,7 +349,12 @@ Room = function(video, title, fold, userlist, logcontainer, loglist) {
         seek: this.OnTimelineClick.bind(this),
     };
 
-    this.player = new Plyr(this.video, {debug: false, listeners: listeners});
+    this.player = new Plyr(this.video, {
+        debug: false,
+        listeners: listeners,
+        // Subtitle tracks are added once the room state arrives.
+        captions: {active: true, update: true},
+    });
 
     this.player.on('play', this.OnPlay.bind(this));
     this.player.on('pause', this.OnPaused.bind(this));
@@ -276,6 +363,17 @@ Room = function(video, title, fold, userlist, logcontainer, loglist) {
     this.player.on('timeupdate', this.OnTimeUpdate.bind(this));
     this.player.on('loadeddata ', this.OnVideoLoaded.bind(this));
 
+    // Previews shown when hovering the timeline, before anyone seeks the whole room.
+    this.thumbnails = [];
+    this.timeline = this.player.elements.progress;
+    if (this.timeline != null) {
+        this.thumbnailPreview = document.createElement("div");
+        this.thumbnailPreview.className = "thumbnail-preview";
+        this.timeline.appendChild(this.thumbnailPreview);
+        this.timeline.addEventListener("mousemove", this.OnTimelineHover.bind(this));
+        this.timeline.addEventListener("mouseleave", this.OnTimelineLeave.bind(this));
+    }
+
     /*this.player.addEventListener("timeupdate", this.OnTimeUpdate.bind(this));
     this.player.addEventListener("pause", this.OnVideoPause.bind(this));
     this.player.addEventListener("play", this.OnVideoPlay.bind(this));*/
@@ -314,6 +412,50 @@ Room.prototype.OnTimelineClick = function(e) {
     }
 }
 
+Room.prototype.OnTimelineHover = function(e) {
+    var rect = this.timeline.getBoundingClientRect();
+    var x = e.clientX - rect.left;
+    var time = x / rect.width * this.video.duration;
+    var thumbnail = this.thumbnails.find((t) => time >= t.start && time < t.end);
+
+    if (isNaN(time) || thumbnail == null) {
+        this.OnTimelineLeave();
+        return;
+    }
+
+    var style = this.thumbnailPreview.style;
+    style.width = thumbnail.width + "px";
+    style.height = thumbnail.height + "px";
+    style.backgroundImage = 'url("' + thumbnail.url + '")';
+    style.backgroundPosition = -thumbnail.x + "px " + -thumbnail.y + "px";
+    style.left = Math.max(0, Math.min(x - thumbnail.width / 2, rect.width - thumbnail.width)) + "px";
+    style.display = "block";
+}
+
+Room.prototype.OnTimelineLeave = function(e) {
+    this.thumbnailPreview.style.display = "none";
+}
+
+Room.prototype.LoadThumbnails = function(stream) {
+    this.thumbnails = [];
+    if (stream.thumbnails == null || this.timeline == null) {
+        return;
+    }
+
+    var slug = stream.slug;
+    var index = new URL("/room/" + ROOM_CODE + "/media/" + stream.thumbnails, window.location.href);
+
+    fetch(index)
+        .then((response) => response.ok ? response.text() : Promise.reject(response.statusText))
+        .then((text) => {
+            // Another stream may have been loaded in the meantime.
+            if (slug == this.loadedSlug) {
+                this.thumbnails = parseThumbnails(text, index);
+            }
+        })
+        .catch((e) => console.error("Failed to load thumbnails: " + e));
+}
+
 Room.prototype.OnPlayClick = function(e) {
     if (this.player.paused) {
         this.RequestPlay();
@@ -340,7 +482,9 @@ Room.prototype.Log = function(src, msg) {
         tdSrc.appendChild(createBadge(9));
     }
 
-    msg = msg.replaceAll('{}', '<span class="user-name">' + src.name + '</span>');
+    if (src != null) {
+        msg = msg.replaceAll('{}', '<span class="user-name">' + src.name + '</span>');
+    }
 
     tdMsg.innerHTML = msg;
 
@@ -355,12 +499,36 @@ Room.prototype.Connect = function() {
     this.self_user = new Participant(this.userlist, null, USERNAME, true, AVATAR, BADGES)
     this.participants = [this.self_user];
     this.username = USERNAME;
+    this.restarting = false;
+
+    this.OpenSocket();
+}
 
+Room.prototype.OpenSocket = function() {
     var host = window.location.host;
 
     this.ws = new WebSocket("ws://" + host + "/websocket/" + ROOM_CODE);
     this.ws.onopen = this.OnWsOpen.bind(this);
     this.ws.onmessage = this.OnWsMessage.bind(this);
+    this.ws.onclose = this.OnWsClose.bind(this);
+}
+
+// Reconnects after a server restart. Everyone else is re-announced in the new RoomState.
+Room.prototype.Reconnect = function() {
+    this.participants
+        .filter((p) => p != this.self_user)
+        .forEach((p) => p.Remove());
+    this.participants = [this.self_user];
+    this.self_user.user_id = null;
+
+    this.OpenSocket();
+}
+
+Room.prototype.OnWsClose = function(event) {
+    if (this.restarting) {
+        console.log("Connection closed, reconnecting in " + RECONNECT_DELAY + "ms");
+        setTimeout(this.Reconnect.bind(this), RECONNECT_DELAY);
+    }
 }
 
 Room.prototype.RequestPlay = function() {
@@ -419,6 +587,8 @@ Room.prototype.OnWsOpen = function(event) {
             name: this.username,
             avatar: AVATAR,
             time: time(),
+            version: PROTOCOL_VERSION,
+            features: PROTOCOL_FEATURES,
         }
     });
 }
@@ -428,7 +598,11 @@ Room.prototype.OnWsMessage = function(event) {
 
     // console.log(message);
 
-    if (message.RoomState != null) {
+    if (message.Welcome != null) {
+        this.OnWelcome(message.Welcome);
+    } else if (message.Rejected != null) {
+        this.OnRejected(message.Rejected);
+    } else if (message.RoomState != null) {
         this.OnRoomState(message.RoomState);
     } else if (message.RoomUpdate != null) { // room has updated
         this.OnRoomUpdate(message.RoomUpdate);
@@ -442,20 +616,73 @@ Room.prototype.OnWsMessage = function(event) {
         this.OnDoSeek(message.DoSeek);
     } else if (message.SetState != null) { // someone changed state
         this.OnSetState(message.SetState);
+    } else if (message.Error != null) { // server refused a message
+        console.error("Server refused message: " + JSON.stringify(message.Error));
+    } else if (message.Restarting != null) { // server is going down for a restart
+        this.OnRestarting(message.Restarting);
+    } else if (message.SubtitlesChanged != null) { // someone changed the room subtitles
+        this.OnSubtitlesChanged(message.SubtitlesChanged);
+    } else if (message.SuggestQuality != null) { // we keep running out of buffer
+        this.OnSuggestQuality(message.SuggestQuality);
+    } else if (message.RateLimited != null) { // we're sending too much
+        console.warn("Server is rate limiting " + message.RateLimited.kind + " messages (" + message.RateLimited.penalty + ")");
     }
 }
 
+Room.prototype.OnWelcome = function(welcome) {
+    console.log("Server speaks protocol v" + welcome.version + ", capabilities: " + welcome.capabilities);
+
+    this.capabilities = welcome.capabilities;
+
+    if (this.restarting) {
+        this.restarting = false;
+        this.Log(null, "Reconnected.");
+    }
+}
+
+Room.prototype.OnRejected = function(rejected) {
+    console.error("Server rejected handshake: " + rejected.reason);
+
+    if (rejected.reason == "UnsupportedVersion") {
+        alert("This page is out of date (protocol v" + PROTOCOL_VERSION + ", server wants v" +
+              rejected.min_version + " to v" + rejected.version + "). Please reload.");
+    }
+}
+
+Room.prototype.OnRestarting = function(restarting) {
+    this.Log(null, "The server is restarting, the room was paused at " +
+             secondsToTime(restarting.duration) + ". Reconnecting...");
+
+    this.restarting = true;
+    this.blockEvents = true;
+    this.video.pause();
+    this.video.currentTime = restarting.duration;
+}
+
 Room.prototype.OnRoomState = function(state) {
     var stream = state.current_stream;
     if (stream != null) {
         var slug = stream.slug;
 
-        var streamUrl = "/static/data/" + slug + "/" + stream.streams[0].playlist;
-        console.log("Loading url: " + streamUrl);
         this.startingDuration = stream.duration;
         this.startingState = stream.state;
 
-        this.hls.loadSource(streamUrl);
+        if (slug == this.loadedSlug) {
+            // Reconnected to the same stream, no need to load it again.
+            this.blockEvents = true;
+            this.video.currentTime = stream.duration;
+        } else {
+            var streamUrl = "/room/" + ROOM_CODE + "/master.m3u8";
+            console.log("Loading url: " + streamUrl);
+
+            this.loadedSlug = slug;
+            this.hls.loadSource(streamUrl);
+            this.SetupQualities(stream.streams);
+            this.SetupAudioTracks(stream.audio_tracks);
+            this.LoadThumbnails(stream);
+        }
+
+        this.SetupSubtitles(stream);
     }
 
     this.SetTime(0);
@@ -470,6 +697,219 @@ Room.prototype.OnRoomState = function(state) {
     });
 }
 
+Room.prototype.SetupQualities = function(streams) {
+    this.qualities = streams
+        .filter((s) => s.quality > 0)
+        .sort((a, b) => b.quality - a.quality);
+
+    this.qualitySelect.innerHTML = '<option value="-1">Auto</option>';
+    this.qualities.forEach((stream) => {
+        var option = document.createElement('option');
+        option.value = stream.quality;
+        option.innerText = qualityToText(stream.quality, false);
+        this.qualitySelect.appendChild(option);
+    });
+    this.qualitySelect.disabled = this.qualities.length == 0;
+}
+
+// The hls.js level playing `quality`, -1 if there is none. hls.js orders its levels by bitrate,
+// lowest first, so they are matched by their height or playlist instead of their position.
+Room.prototype.LevelOf = function(quality) {
+    var stream = this.qualities.find((s) => s.quality == quality);
+    if (this.hls == null || stream == null) {
+        return -1;
+    }
+
+    return (this.hls.levels || []).findIndex((level) => {
+        var urls = [].concat(level.url);
+        return level.height == quality || urls.some((url) => url.split("?")[0].endsWith("/" + stream.playlist));
+    });
+}
+
+// The quality being played, null if the stream has no renditions to choose from.
+Room.prototype.CurrentQuality = function() {
+    var level = this.hls != null ? this.hls.currentLevel : -1;
+    var stream = level >= 0 ? this.qualities.find((s) => this.LevelOf(s.quality) == level) : null;
+
+    return stream != null ? stream.quality : null;
+}
+
+Room.prototype.IsAutoQuality = function() {
+    return this.hls != null && this.hls.autoLevelEnabled;
+}
+
+// Plays `quality`, null to let hls.js pick one.
+Room.prototype.SetQuality = function(quality) {
+    var level = quality != null ? this.LevelOf(quality) : -1;
+    if (quality != null && level < 0) {
+        return;
+    }
+
+    this.hls.currentLevel = level;
+    this.qualitySelect.value = quality != null ? quality : -1;
+}
+
+Room.prototype.OnQualityChange = function(e) {
+    var quality = parseInt(this.qualitySelect.value);
+    this.SetQuality(quality > 0 ? quality : null);
+}
+
+Room.prototype.OnSuggestQuality = function(suggestion) {
+    if (this.LevelOf(suggestion.quality) < 0) {
+        return;
+    }
+
+    this.Log(null, "Playback keeps running out of buffer, a lower quality might help. " +
+             '<a href="#">Switch to ' + qualityToText(suggestion.quality, false) + '</a>');
+
+    this.logbody.lastChild.querySelector("a").addEventListener("click", (e) => {
+        e.preventDefault();
+        this.SetQuality(suggestion.quality);
+    });
+}
+
+Room.prototype.SetupAudioTracks = function(tracks) {
+    this.audioTracks = tracks;
+
+    this.audioSelect.innerHTML = '';
+    tracks.forEach((track) => {
+        var option = document.createElement('option');
+        option.value = track.name;
+        option.innerText = track.name + (track.language != null ? " (" + track.language + ")" : "");
+        this.audioSelect.appendChild(option);
+    });
+    this.audioSelect.disabled = tracks.length < 2;
+}
+
+// The track picked on this device, if any.
+Room.prototype.ChosenAudio = function() {
+    return localStorage.getItem("audio-" + this.loadedSlug);
+}
+
+Room.prototype.CurrentAudio = function() {
+    if (this.hls == null || this.hls.audioTrack < 0 || this.hls.audioTrack >= this.hls.audioTracks.length) {
+        return null;
+    }
+
+    return this.hls.audioTracks[this.hls.audioTrack].name;
+}
+
+Room.prototype.SetAudio = function(name) {
+    if (this.hls == null) {
+        return;
+    }
+
+    var track = this.hls.audioTracks.findIndex((t) => t.name == name);
+    if (track >= 0 && track != this.hls.audioTrack) {
+        this.hls.audioTrack = track;
+    }
+}
+
+Room.prototype.OnAudioTracksUpdated = function(event, data) {
+    var chosen = this.ChosenAudio();
+    if (chosen != null) {
+        this.SetAudio(chosen);
+    }
+
+    this.audioSelect.value = this.CurrentAudio();
+}
+
+Room.prototype.OnAudioChange = function(e) {
+    localStorage.setItem("audio-" + this.loadedSlug, this.audioSelect.value);
+    this.SetAudio(this.audioSelect.value);
+}
+
+Room.prototype.SetupSubtitles = function(stream) {
+    this.subtitles = stream.subtitles;
+    this.roomSubtitle = stream.subtitle;
+    this.subtitleOffset = stream.subtitle_offset;
+    this.subtitleOffsetInput.value = stream.subtitle_offset;
+
+    this.subtitleSelect.innerHTML = '<option value="">Off</option>';
+    this.subtitles.forEach((subtitle) => {
+        var option = document.createElement('option');
+        option.value = subtitle.id;
+        option.innerText = subtitle.label;
+        this.subtitleSelect.appendChild(option);
+    });
+    this.subtitleSelect.disabled = this.subtitles.length == 0;
+
+    this.LoadSubtitles();
+}
+
+// The track picked on this device wins over the room default, an empty pick means off.
+Room.prototype.ChosenSubtitle = function() {
+    var own = localStorage.getItem("subtitle-" + this.loadedSlug);
+
+    return own != null ? (own || null) : this.roomSubtitle;
+}
+
+Room.prototype.LoadSubtitles = function() {
+    this.video.querySelectorAll("track").forEach((track) => track.remove());
+
+    var chosen = this.ChosenSubtitle();
+
+    this.subtitles.forEach((subtitle) => {
+        var track = document.createElement('track');
+        track.kind = "subtitles";
+        track.label = subtitle.label;
+        if (subtitle.language != null) {
+            track.srclang = subtitle.language;
+        }
+        // The server applies the offset, so a new offset needs a fresh copy.
+        track.src = "/room/" + ROOM_CODE + "/subtitles/" + encodeURIComponent(subtitle.id) +
+            "?offset=" + this.subtitleOffset;
+
+        this.video.appendChild(track);
+        track.track.mode = subtitle.id == chosen ? "showing" : "disabled";
+    });
+
+    this.subtitleSelect.value = chosen || "";
+}
+
+Room.prototype.OnSubtitleChange = function(e) {
+    localStorage.setItem("subtitle-" + this.loadedSlug, this.subtitleSelect.value);
+    this.LoadSubtitles();
+}
+
+Room.prototype.OnSubtitleOffsetChange = function(e) {
+    var offset = parseFloat(this.subtitleOffsetInput.value);
+    if (isNaN(offset)) {
+        return;
+    }
+
+    this.SendSubtitles(this.roomSubtitle, offset);
+}
+
+Room.prototype.OnSubtitleDefaultClick = function(e) {
+    this.SendSubtitles(this.subtitleSelect.value || null, this.subtitleOffset);
+}
+
+Room.prototype.SendSubtitles = function(subtitle, offset) {
+    this.Send({SetSubtitles:{
+        subtitle: subtitle,
+        offset: offset,
+    }});
+
+    this.roomSubtitle = subtitle;
+    this.subtitleOffset = offset;
+    this.LoadSubtitles();
+}
+
+Room.prototype.OnSubtitlesChanged = function(changed) {
+    var src = this.participants.find((p) => p.user_id == changed.user);
+    var subtitle = this.subtitles.find((s) => s.id == changed.subtitle);
+    var offset = (changed.offset >= 0 ? "+" : "") + changed.offset + "s";
+
+    this.Log(src, "{} set the room subtitles to " + (subtitle != null ? subtitle.label : "off") +
+             " (" + offset + ").");
+
+    this.roomSubtitle = changed.subtitle;
+    this.subtitleOffset = changed.offset;
+    this.subtitleOffsetInput.value = changed.offset;
+    this.LoadSubtitles();
+}
+
 Room.prototype.OnRoomUpdate = function(update) {
     update.participants.forEach(update => {
         var participant = this.participants.find((p) => p.user_id == update.user_id);
@@ -489,6 +929,9 @@ Room.prototype.UpdateSelf = function() {
     this.self_state = {
         duration: duration,
         buffered: buffered,
+        quality: this.CurrentQuality(),
+        auto_quality: this.IsAutoQuality(),
+        audio: this.CurrentAudio(),
         state: state
     };
 
@@ -510,6 +953,9 @@ Room.prototype.OnPing = function(seek) {
         state: state,
         state_time: this.current_state_set,
         buffered: buffered,
+        quality: this.CurrentQuality(),
+        auto_quality: this.IsAutoQuality(),
+        audio: this.CurrentAudio(),
         time: time(),
     }});
 }
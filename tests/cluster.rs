@@ -0,0 +1,134 @@
+//! The file directory shared by nodes running as separate processes. The other nodes are copies
+//! of this test binary, running `node_process` only.
+
+mod common;
+
+use futures::executor::block_on;
+
+use tmtusync::actors::RoomSnapshot;
+use tmtusync::cluster::{FileDirectory, NodeInfo, RoomDirectory};
+
+use common::TempDir;
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::process::{Command, Output};
+
+const ROOM: &str = "TEST1";
+
+/// Tell a copy of the binary what to do as a node: the directory, the node id and the action.
+const NODE_DIR: &str = "TMTUSYNC_TEST_NODE_DIR";
+const NODE_ID: &str = "TMTUSYNC_TEST_NODE_ID";
+const NODE_ACTION: &str = "TMTUSYNC_TEST_NODE_ACTION";
+
+fn node(id: &str) -> NodeInfo {
+    NodeInfo::new(id, &format!("http://{}.invalid", id))
+}
+
+/// The part of the other nodes, printing what the directory answered. Ignored since it only
+/// does anything when started by `spawn_nodes`.
+#[test]
+#[ignore]
+fn node_process() {
+    let (dir, id, action) = match (std::env::var_os(NODE_DIR), std::env::var(NODE_ID), std::env::var(NODE_ACTION)) {
+        (Some(dir), Ok(id), Ok(action)) => (dir, id, action),
+        _ => return,
+    };
+
+    let directory = FileDirectory::new(dir);
+
+    match action.as_str() {
+        "claim" => {
+            let owner = block_on(directory.claim(ROOM, &node(&id))).unwrap();
+            println!("owner={}", owner.id);
+        }
+        "take_over" => {
+            let snapshot = block_on(directory.take_over(ROOM, &node(&id))).unwrap();
+            println!("snapshot={}", snapshot.map(|s| s.duration.to_string()).unwrap_or_default());
+        }
+        action => panic!("unknown action {:?}", action),
+    }
+}
+
+/// Runs `action` in as many processes as there are `ids` at once, returning what each printed.
+fn spawn_nodes(dir: &Path, ids: &[&str], action: &str) -> HashMap<String, String> {
+    let children = ids
+        .iter()
+        .map(|id| {
+            let child = Command::new(std::env::current_exe().unwrap())
+                .args(&["node_process", "--exact", "--ignored", "--nocapture", "--quiet"])
+                .env(NODE_DIR, dir)
+                .env(NODE_ID, id)
+                .env(NODE_ACTION, action)
+                .stdout(std::process::Stdio::piped())
+                .spawn()
+                .unwrap();
+
+            (id.to_string(), child)
+        })
+        .collect::<Vec<_>>();
+
+    children
+        .into_iter()
+        .map(|(id, child)| {
+            let Output { status, stdout, .. } = child.wait_with_output().unwrap();
+            assert!(status.success(), "node {} failed", id);
+
+            let prefix = format!("{}=", answer_key(action));
+            let answer = String::from_utf8_lossy(&stdout)
+                .lines()
+                .find_map(|line| line.strip_prefix(&prefix).map(String::from))
+                .unwrap_or_else(|| panic!("node {} didn't answer", id));
+
+            (id, answer)
+        })
+        .collect()
+}
+
+fn answer_key(action: &str) -> &'static str {
+    match action {
+        "claim" => "owner",
+        _ => "snapshot",
+    }
+}
+
+fn snapshot(duration: f32) -> RoomSnapshot {
+    RoomSnapshot {
+        name: String::from(ROOM),
+        stream: None,
+        duration,
+        cookies: HashMap::new(),
+        free_user_id: 0,
+        admins: HashSet::new(),
+        subtitle: None,
+        subtitle_offset: 0.0,
+    }
+}
+
+#[test]
+fn one_process_wins_a_room_and_hands_it_over_once() {
+    let dir = TempDir::new("cluster");
+    let ids = ["a", "b", "c", "d"];
+
+    // Every node agrees on the one that got there first.
+    let owners = spawn_nodes(&dir, &ids, "claim");
+    let winners = owners.values().collect::<HashSet<_>>();
+    assert_eq!(winners.len(), 1, "{:?}", owners);
+
+    let winner = owners["a"].clone();
+    let directory = FileDirectory::new(&*dir);
+    assert_eq!(block_on(directory.owner(ROOM)).unwrap(), Some(node(&winner)));
+
+    // Only the owner can hand the room over, and only one node gets to take it over.
+    let loser = ids.iter().find(|id| **id != winner).unwrap();
+    block_on(directory.release(ROOM, &node(loser), Some(&snapshot(1.0)))).unwrap();
+    assert_eq!(block_on(directory.owner(ROOM)).unwrap(), Some(node(&winner)));
+
+    block_on(directory.release(ROOM, &node(&winner), Some(&snapshot(42.0)))).unwrap();
+    assert_eq!(block_on(directory.owner(ROOM)).unwrap(), None);
+
+    let taken = spawn_nodes(&dir, &ids, "take_over");
+    let mut answers = taken.values().cloned().collect::<Vec<_>>();
+    answers.sort();
+    assert_eq!(answers, vec!["", "", "", "42"], "{:?}", taken);
+}
@@ -0,0 +1,92 @@
+//! Helpers shared by the integration tests. Not every test file uses all of them.
+#![allow(dead_code)]
+
+use actix_web::{http::{header, HeaderMap, StatusCode}, test};
+
+use tmtusync::actors::{MediaSource, MediaStream, StreamMetadata};
+use tmtusync::protocol::Stream;
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A fresh directory below the system's temporary directory, removed again when dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Creates the directory. The name is only there to tell the tests' directories apart, a
+    /// random part keeps runs and tests with the same name from sharing one.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("tmtusync-{}-{:016x}", name, rand::random::<u64>()));
+        std::fs::create_dir_all(&path).unwrap();
+
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// An hour long movie with only the master playlist. Tests needing more override the fields
+/// they care about.
+pub fn fixture_stream() -> MediaStream {
+    MediaStream {
+        slug: String::from("test"),
+        name: String::from("Test"),
+        streams: vec![
+            Stream { quality: 0, playlist: String::from("master.m3u8") },
+        ],
+        meta: StreamMetadata {
+            title: String::from("Test movie"),
+            duration: String::from("1h"),
+            imdb: None,
+        },
+        length: Some(3600.0),
+        audio_tracks: Vec::new(),
+        subtitles: Vec::new(),
+        thumbnails: None,
+        source: MediaSource::Local,
+    }
+}
+
+/// Gets `url`, logged in with the identity `cookie` if there is one.
+pub async fn fetch(srv: &test::TestServer, url: &str, cookie: Option<&str>) -> (StatusCode, HeaderMap, Vec<u8>) {
+    let request = match cookie {
+        Some(cookie) => srv.get(url).header(header::COOKIE, cookie),
+        None => srv.get(url),
+    };
+
+    let mut response = request.send().await.unwrap();
+    let body = response.body().await.unwrap().to_vec();
+
+    (response.status(), response.headers().clone(), body)
+}
+
+/// Logs in to `room` through the index form, returning the identity cookie to send along with
+/// later requests.
+pub async fn login(srv: &test::TestServer, room: &str, nickname: &str) -> String {
+    let response = srv
+        .post("/")
+        .send_form(&[("nickname", nickname), ("avatar", "0"), ("room", room)])
+        .await
+        .unwrap();
+
+    response
+        .headers()
+        .get_all(header::SET_COOKIE)
+        .filter_map(|h| h.to_str().ok())
+        .find(|c| c.starts_with("auth-cookie="))
+        .and_then(|c| c.split(';').next())
+        .map(String::from)
+        .expect("no identity cookie")
+}
@@ -0,0 +1,533 @@
+//! Uploading media and transcoding it into HLS. Tests transcoding need ffmpeg and are ignored,
+//! run them with `cargo test -- --ignored` where it is installed.
+
+mod common;
+
+use actix::Actor;
+use actix_web::{http::{header, StatusCode}, test, App};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use tmtusync::actors::{BackfillThumbnails, Job, JobState, MediaStream, RoomRepository, Transcoder, TransportConfig};
+use tmtusync::clock::SystemClock;
+use tmtusync::library::MediaLibrary;
+use tmtusync::server::{self, AdminConfig, AppData};
+use tmtusync::signing::UrlSigner;
+use tmtusync::transcode::{Probe, Rendition, TranscodeConfig};
+use tmtusync::trickplay::{self, TrickplayConfig};
+use tmtusync::upload::{UploadInfo, UploadLimits, UploadStore};
+
+use common::{fetch, fixture_stream, login, TempDir};
+
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+const ROOM: &str = "TEST1";
+
+/// Transcoding even a few seconds of video takes a while on a slow machine.
+const TIMEOUT: Duration = Duration::from_secs(120);
+
+/// A few seconds of test pattern and a beep, as an upload would be.
+fn generate_input(path: &Path) {
+    let status = Command::new("ffmpeg")
+        .args(&["-loglevel", "error", "-y"])
+        .args(&["-f", "lavfi", "-i", "testsrc=duration=3:size=640x360:rate=25"])
+        .args(&["-f", "lavfi", "-i", "sine=duration=3"])
+        .args(&["-c:v", "libx264", "-c:a", "aac", "-shortest"])
+        .arg(path)
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+}
+
+/// Like `generate_input`, with a second audio stream and both tagged with their language.
+fn generate_multi_audio_input(path: &Path) {
+    let status = Command::new("ffmpeg")
+        .args(&["-loglevel", "error", "-y"])
+        .args(&["-f", "lavfi", "-i", "testsrc=duration=3:size=640x360:rate=25"])
+        .args(&["-f", "lavfi", "-i", "sine=frequency=440:duration=3"])
+        .args(&["-f", "lavfi", "-i", "sine=frequency=880:duration=3"])
+        .args(&["-map", "0:v", "-map", "1:a", "-map", "2:a"])
+        .args(&["-metadata:s:a:0", "language=eng", "-metadata:s:a:1", "language=deu"])
+        .args(&["-c:v", "libx264", "-c:a", "aac", "-shortest"])
+        .arg(path)
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+}
+
+fn app_data(dir: &Path, library: MediaLibrary, limits: UploadLimits) -> AppData {
+    let config = TranscodeConfig {
+        media_dir: dir.join("media"),
+        renditions: vec![
+            Rendition::new(720, 2800, 128),
+            Rendition::new(360, 800, 96),
+            Rendition::new(240, 400, 64),
+        ],
+        ..TranscodeConfig::default()
+    };
+
+    let media_dir = config.media_dir.clone();
+
+    AppData {
+        room_repo: RoomRepository::default().start(),
+        clock: SystemClock::shared(),
+        transport: TransportConfig::default(),
+        admin: AdminConfig { address: String::from("127.0.0.1"), ..AdminConfig::default() },
+        audit_dir: None,
+        record_dir: None,
+        cluster: None,
+        library: Some(library.clone()),
+        transcoder: Some(Transcoder::new(config, library).start()),
+        uploads: Some(UploadStore::new(dir.join("uploads"), limits).unwrap()),
+        media_dir,
+        master_playlists: Default::default(),
+        media_signer: UrlSigner::random(chrono::Duration::hours(1)),
+        proxy: None,
+    }
+}
+
+fn serve(data: AppData) -> test::TestServer {
+    test::start(move || {
+        App::new()
+            .wrap(server::identity_service())
+            .configure(server::configure(data.clone()))
+    })
+}
+
+/// Serves `data` with a room to log in to, returning the server and the cookie of an admin,
+/// who gets to add media.
+async fn serve_as_admin(data: AppData) -> (test::TestServer, String) {
+    server::register_room(&data, fixture_stream(), String::from(ROOM)).await.unwrap();
+    let srv = serve(data);
+    let admin = login(&srv, ROOM, "tmtu").await;
+
+    (srv, admin)
+}
+
+async fn wait_for_job(srv: &test::TestServer, admin: &str, job: &Job) -> JobState {
+    let wait = async {
+        loop {
+            let mut response = srv
+                .get(format!("/media/jobs/{}", job.id.0))
+                .header(header::COOKIE, admin)
+                .send()
+                .await
+                .unwrap();
+            let job: Job = response.json().await.unwrap();
+
+            match job.state {
+                JobState::Queued | JobState::Running { .. } => {
+                    tokio::time::delay_for(Duration::from_millis(100)).await
+                }
+                state => return state,
+            }
+        }
+    };
+
+    tokio::time::timeout(TIMEOUT, wait).await.expect("timed out waiting for job")
+}
+
+#[actix_rt::test]
+#[ignore = "needs ffmpeg"]
+async fn upload_is_transcoded_into_library() {
+    let dir = TempDir::new("media");
+    let input = dir.join("input.mp4");
+    generate_input(&input);
+
+    let library = MediaLibrary::open(dir.join("library.json"), dir.join("media")).unwrap();
+    let data = app_data(&dir, library.clone(), UploadLimits::default());
+    let signer = data.media_signer.clone();
+    let (srv, admin) = serve_as_admin(data).await;
+
+    // Only the host and admins add media.
+    let guest = login(&srv, ROOM, "alice").await;
+    let response = srv.post("/media?name=Test%20Movie").header(header::COOKIE, guest).send_body("nope").await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = srv.get("/media/jobs").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let mut response = srv
+        .post("/media?name=Test%20Movie")
+        .header(header::COOKIE, admin.as_str())
+        .send_body(std::fs::read(&input).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let job: Job = response.json().await.unwrap();
+    assert_eq!(job.name, "Test Movie");
+
+    let slug = match wait_for_job(&srv, &admin, &job).await {
+        JobState::Done { slug } => slug,
+        state => panic!("job didn't finish: {:?}", state),
+    };
+    assert_eq!(slug, "test-movie");
+
+    // Nothing is upscaled, the master playlist comes first.
+    let stream = library.find(&slug).expect("media isn't in the library");
+    let qualities = stream.streams.iter().map(|s| s.quality).collect::<Vec<_>>();
+    assert_eq!(qualities, vec![0, 360, 240]);
+    assert_eq!(stream.meta.duration, "3s");
+
+    let media = dir.join("media").join(&slug);
+    let master = std::fs::read_to_string(media.join("master.m3u8")).unwrap();
+    assert!(master.contains("RESOLUTION=640x360,CODECS=\"avc1.4d4028,mp4a.40.2\"\n360p/index.m3u8"), "{}", master);
+    assert!(media.join("240p/index.m3u8").exists());
+
+    // A single sheet of thumbnails, served next to the HLS data to rooms playing the media.
+    assert_eq!(stream.thumbnails.as_deref(), Some(trickplay::THUMBNAIL_INDEX));
+    assert!(media.join("trickplay/sprite0.jpg").exists());
+
+    let response = srv
+        .post("/create")
+        .send_form(&[("room", "MOVIE"), ("nickname", "tmtu"), ("media", slug.as_str())])
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let url = format!(
+        "/room/MOVIE/media/{}?{}",
+        trickplay::THUMBNAIL_INDEX,
+        signer.query("MOVIE", trickplay::THUMBNAIL_INDEX, chrono::Utc::now()),
+    );
+    let (status, _, index) = fetch(&srv, &url, None).await;
+    assert_eq!(status, StatusCode::OK);
+    let index = String::from_utf8(index).unwrap();
+    assert!(index.starts_with("WEBVTT\n\n00:00:00.000 --> 00:00:03"), "{}", index);
+    assert!(index.contains("\nsprite0.jpg#xywh=0,0,160,90\n"), "{}", index);
+
+    // The library survives a restart.
+    let reopened = MediaLibrary::open(library.path(), dir.join("media")).unwrap();
+    assert_eq!(reopened.streams().len(), 1);
+}
+
+#[actix_rt::test]
+#[ignore = "needs ffmpeg"]
+async fn audio_streams_become_audio_tracks() {
+    let dir = TempDir::new("audio");
+    let input = dir.join("input.mp4");
+    generate_multi_audio_input(&input);
+
+    let library = MediaLibrary::open(dir.join("library.json"), dir.join("media")).unwrap();
+    let (srv, admin) = serve_as_admin(app_data(&dir, library.clone(), UploadLimits::default())).await;
+
+    let mut response = srv
+        .post("/media?name=Dubbed")
+        .header(header::COOKIE, admin.as_str())
+        .send_body(std::fs::read(&input).unwrap())
+        .await
+        .unwrap();
+    let job: Job = response.json().await.unwrap();
+
+    let slug = match wait_for_job(&srv, &admin, &job).await {
+        JobState::Done { slug } => slug,
+        state => panic!("job didn't finish: {:?}", state),
+    };
+
+    let stream = library.find(&slug).expect("media isn't in the library");
+    let tracks = stream.audio_tracks.iter().map(|t| (t.name.as_str(), t.uri.as_deref())).collect::<Vec<_>>();
+    assert_eq!(tracks, vec![("eng", Some("audio0/index.m3u8")), ("deu", Some("audio1/index.m3u8"))]);
+
+    let media = dir.join("media").join(&slug);
+    assert!(media.join("audio1/index.m3u8").exists());
+    let master = std::fs::read_to_string(media.join("master.m3u8")).unwrap();
+    assert!(master.contains("AUDIO=\"audio\"\n360p/index.m3u8"), "{}", master);
+}
+
+#[test]
+fn thumbnail_index_covers_every_interval() {
+    let config = TrickplayConfig { interval: 10, width: 160, columns: 2, rows: 2 };
+    let probe = Probe { duration: 45.0, width: 1920, height: 800, audio: Vec::new() };
+
+    let size = trickplay::thumbnail_size(&config, &probe);
+    assert_eq!(size, (160, 68));
+
+    // The last frame ffmpeg took stands in for the rest of the media.
+    let index = trickplay::thumbnail_index(&config, &[0.0, 10.0, 20.0, 30.0, 40.0], probe.duration, size);
+    let cues = index.split("\n\n").skip(1).filter(|c| !c.is_empty()).collect::<Vec<_>>();
+
+    assert_eq!(cues, vec![
+        "00:00:00.000 --> 00:00:10.000\nsprite0.jpg#xywh=0,0,160,68",
+        "00:00:10.000 --> 00:00:20.000\nsprite0.jpg#xywh=160,0,160,68",
+        "00:00:20.000 --> 00:00:30.000\nsprite0.jpg#xywh=0,68,160,68",
+        "00:00:30.000 --> 00:00:40.000\nsprite0.jpg#xywh=160,68,160,68",
+        "00:00:40.000 --> 00:00:45.000\nsprite1.jpg#xywh=0,0,160,68",
+    ]);
+}
+
+#[test]
+fn thumbnail_times_come_from_ffmpeg_log() {
+    let line = "[Parsed_showinfo_2 @ 0x55d0c8a4f300] n:   3 pts:      3 pts_time:30      \
+                duration:1 duration_time:10 pos:-1 fmt:yuvj420p";
+    assert_eq!(trickplay::parse_frame_time(line), Some(30.0));
+
+    assert_eq!(trickplay::parse_frame_time("[Parsed_showinfo_2 @ 0x55d0c8a4f300] config in time_base: 10/1"), None);
+    assert_eq!(trickplay::parse_frame_time("Output #0, image2, to 'sprite%d.jpg':"), None);
+}
+
+#[actix_rt::test]
+#[ignore = "needs ffmpeg"]
+async fn missing_thumbnails_are_backfilled() {
+    let dir = TempDir::new("backfill");
+    let input = dir.join("input.mp4");
+    generate_input(&input);
+
+    let library = MediaLibrary::open(dir.join("library.json"), dir.join("media")).unwrap();
+    let data = app_data(&dir, library.clone(), UploadLimits::default());
+    let transcoder = data.transcoder.clone().unwrap();
+    let (srv, admin) = serve_as_admin(data).await;
+
+    let mut response = srv
+        .post("/media?name=Old%20Movie")
+        .header(header::COOKIE, admin.as_str())
+        .send_body(std::fs::read(&input).unwrap())
+        .await
+        .unwrap();
+    let job: Job = response.json().await.unwrap();
+    let slug = match wait_for_job(&srv, &admin, &job).await {
+        JobState::Done { slug } => slug,
+        state => panic!("job didn't finish: {:?}", state),
+    };
+
+    // As if the media was added before there were thumbnails.
+    std::fs::remove_dir_all(dir.join("media").join(&slug).join(trickplay::TRICKPLAY_DIR)).unwrap();
+    library.add(library.find(&slug).unwrap()).unwrap();
+    assert_eq!(library.find(&slug).unwrap().thumbnails, None);
+
+    let jobs = transcoder.send(BackfillThumbnails).await.unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert!(matches!(wait_for_job(&srv, &admin, &jobs[0]).await, JobState::Done { .. }));
+
+    assert_eq!(library.find(&slug).unwrap().thumbnails.as_deref(), Some(trickplay::THUMBNAIL_INDEX));
+    let index = std::fs::read_to_string(dir.join("media").join(&slug).join(trickplay::THUMBNAIL_INDEX)).unwrap();
+    assert!(index.starts_with("WEBVTT\n\n00:00:00.000 --> 00:00:03"), "{}", index);
+
+    // Nothing is missing anymore.
+    assert!(transcoder.send(BackfillThumbnails).await.unwrap().is_empty());
+}
+
+fn chunk_request(srv: &test::TestServer, admin: &str, id: &str, offset: usize, checksum: Option<String>) -> awc::ClientRequest {
+    let request = srv
+        .put(format!("/uploads/{}", id))
+        .header(header::COOKIE, admin)
+        .header("Upload-Offset", offset.to_string());
+
+    match checksum {
+        Some(checksum) => request.header("Upload-Checksum", checksum),
+        None => request,
+    }
+}
+
+fn sha256(chunk: &[u8]) -> String {
+    format!("sha256 {:x}", Sha256::digest(chunk))
+}
+
+#[actix_rt::test]
+async fn chunked_upload_resumes_after_restart() {
+    let dir = TempDir::new("uploads");
+    let file = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+    let limits = UploadLimits { max_size: 200_000, quota: 250_000 };
+
+    let library = MediaLibrary::open(dir.join("library.json"), dir.join("media")).unwrap();
+    let (srv, admin) = serve_as_admin(app_data(&dir, library.clone(), limits.clone())).await;
+    let create = |name: &str, size: usize| {
+        srv.post("/uploads").header(header::COOKIE, admin.as_str()).send_json(&json!({ "name": name, "size": size }))
+    };
+
+    // Only the host and admins upload.
+    let guest = login(&srv, ROOM, "alice").await;
+    let response = srv
+        .post("/uploads")
+        .header(header::COOKIE, guest)
+        .send_json(&json!({ "name": "Movie", "size": file.len() }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = create("Huge", 300_000).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    let mut response = create("Movie", file.len()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let upload: UploadInfo = response.json().await.unwrap();
+
+    let response = srv.get(format!("/uploads/{}", upload.id)).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // The first upload has reserved its space.
+    let response = create("Other", 200_000).await.unwrap();
+    assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+
+    let (first, second, last) = (&file[..40_000], &file[40_000..80_000], &file[80_000..]);
+
+    let response = chunk_request(&srv, &admin, &upload.id, 0, Some(sha256(first)))
+        .send_body(first.to_vec())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Everything stored survives a restart.
+    drop(srv);
+    let (srv, admin) = serve_as_admin(app_data(&dir, library.clone(), limits)).await;
+
+    let mut response = srv.get(format!("/uploads/{}", upload.id)).header(header::COOKIE, admin.as_str()).send().await.unwrap();
+    let resumed: UploadInfo = response.json().await.unwrap();
+    assert_eq!(resumed.offset, 40_000);
+
+    let response = chunk_request(&srv, &admin, &upload.id, 0, None).send_body(first.to_vec()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(response.headers().get("Upload-Offset").unwrap(), "40000");
+
+    // A corrupted chunk is dropped.
+    let response = chunk_request(&srv, &admin, &upload.id, 40_000, Some(sha256(first)))
+        .send_body(second.to_vec())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let response = chunk_request(&srv, &admin, &upload.id, 40_000, Some(sha256(second)))
+        .send_body(second.to_vec())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let mut response = chunk_request(&srv, &admin, &upload.id, 80_000, None).send_body(last.to_vec()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let done: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(done["upload"]["offset"], 100_000);
+
+    // Handed over to transcoding, which doesn't get anywhere with these bytes.
+    let job: Job = serde_json::from_value(done["job"].clone()).unwrap();
+    assert_eq!(job.name, "Movie");
+    assert!(matches!(wait_for_job(&srv, &admin, &job).await, JobState::Failed { .. }));
+
+    let response = srv.get(format!("/uploads/{}", upload.id)).header(header::COOKIE, admin.as_str()).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn rooms_with_dashes_in_their_code_are_managed_by_their_admins() {
+    let dir = TempDir::new("dashes");
+    let library = MediaLibrary::open(dir.join("library.json"), dir.join("media")).unwrap();
+    let mut data = app_data(&dir, library, UploadLimits::default());
+    data.transcoder = None;
+
+    // A room called like the end of the other one's code, which the admin isn't in.
+    server::register_room(&data, fixture_stream(), String::from("movie-night")).await.unwrap();
+    server::register_room(&data, fixture_stream(), String::from("night")).await.unwrap();
+    let srv = serve(data);
+
+    let create = |cookie: String| {
+        srv.post("/uploads").header(header::COOKIE, cookie).send_json(&json!({ "name": "Movie", "size": 1_000 }))
+    };
+
+    let admin = login(&srv, "movie-night", "tmtu").await;
+    let response = create(admin).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+
+    let guest = login(&srv, "movie-night", "al-ice").await;
+    let response = create(guest).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[actix_rt::test]
+async fn files_waiting_for_transcoding_count_towards_quota() {
+    let dir = TempDir::new("staged");
+    let limits = UploadLimits { max_size: 200_000, quota: 250_000 };
+
+    let library = MediaLibrary::open(dir.join("library.json"), dir.join("media")).unwrap();
+    let mut data = app_data(&dir, library, limits);
+    // Nothing gets transcoded, so the files stay where they are.
+    data.transcoder = None;
+    let (srv, admin) = serve_as_admin(data).await;
+
+    std::fs::write(dir.join("uploads").join("1-0.upload"), vec![0; 150_000]).unwrap();
+
+    let response = srv
+        .post("/uploads")
+        .header(header::COOKIE, admin.as_str())
+        .send_json(&json!({ "name": "Movie", "size": 150_000 }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+
+    let mut response = srv
+        .post("/uploads")
+        .header(header::COOKIE, admin.as_str())
+        .send_json(&json!({ "name": "Movie", "size": 100_000 }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let upload: UploadInfo = response.json().await.unwrap();
+
+    // Completed, but waiting for a transcoder, the upload still takes up its space.
+    let response = chunk_request(&srv, &admin, &upload.id, 0, None).send_body(vec![0; 100_000]).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = srv
+        .post("/uploads")
+        .header(header::COOKIE, admin.as_str())
+        .send_json(&json!({ "name": "Other", "size": 1 }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+}
+
+#[actix_rt::test]
+async fn create_page_lists_library() {
+    let dir = TempDir::new("library");
+    let library = MediaLibrary::open(dir.join("library.json"), dir.join("media")).unwrap();
+
+    library.add(MediaStream {
+        slug: String::from("uploaded"),
+        name: String::from("Uploaded movie"),
+        ..fixture_stream()
+    }).unwrap();
+
+    let srv = serve(app_data(&dir, library, UploadLimits::default()));
+
+    let mut response = srv.get("/create").send().await.unwrap();
+    let page = String::from_utf8(response.body().await.unwrap().to_vec()).unwrap();
+    assert!(page.contains(r#"<option value="uploaded">Uploaded movie</option>"#), "{}", page);
+}
+
+#[actix_rt::test]
+async fn admins_create_rooms_from_library() {
+    let dir = TempDir::new("create");
+    let library = MediaLibrary::open(dir.join("library.json"), dir.join("media")).unwrap();
+
+    library.add(MediaStream {
+        slug: String::from("uploaded"),
+        name: String::from("Uploaded movie"),
+        ..fixture_stream()
+    }).unwrap();
+
+    let srv = serve(app_data(&dir, library, UploadLimits::default()));
+    let create = |nickname: &'static str, room: &'static str, media: &'static str| {
+        srv.post("/create").send_form(&[("room", room), ("nickname", nickname), ("media", media)])
+    };
+
+    let response = create("alice", "MOVIE", "uploaded").await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = create("tmtu", "MOVIE", "missing").await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // The creator ends up in the room, which others can join.
+    let mut response = create("tmtu", "MOVIE", "uploaded").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let page = String::from_utf8(response.body().await.unwrap().to_vec()).unwrap();
+    assert!(page.contains("<title>MOVIE - tmtusync</title>"), "{}", page);
+    let response = srv
+        .post("/")
+        .send_form(&[("nickname", "alice"), ("avatar", "0"), ("room", "MOVIE")])
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = create("tmtu", "MOVIE", "uploaded").await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+}
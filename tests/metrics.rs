@@ -0,0 +1,91 @@
+//! The Prometheus metrics served on `/metrics`. They are process wide, so they are checked in a
+//! test binary of their own, where no other test moves them.
+
+mod common;
+
+use actix::Actor;
+use actix_web::{http::StatusCode, test, App};
+use futures::StreamExt;
+
+use tmtusync::actors::{RoomRepository, TransportConfig};
+use tmtusync::client::{ClientConfig, ClientEvent, RoomClient};
+use tmtusync::clock::SystemClock;
+use tmtusync::metrics;
+use tmtusync::protocol::ToSessionMessage;
+use tmtusync::server::{self, AdminConfig, AppData};
+use tmtusync::signing::UrlSigner;
+
+use common::{fetch, fixture_stream};
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+const ROOM: &str = "METRICS";
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn app_data() -> AppData {
+    AppData {
+        room_repo: RoomRepository::default().start(),
+        clock: SystemClock::shared(),
+        transport: TransportConfig::default(),
+        admin: AdminConfig::default(),
+        audit_dir: None,
+        record_dir: None,
+        cluster: None,
+        library: None,
+        transcoder: None,
+        uploads: None,
+        media_dir: PathBuf::from("data/media"),
+        master_playlists: Default::default(),
+        media_signer: UrlSigner::random(chrono::Duration::hours(1)),
+        proxy: None,
+    }
+}
+
+/// Joins the room, returning once the participant is in it.
+async fn join(srv: &test::TestServer, name: &str) -> RoomClient {
+    let mut client = RoomClient::connect(ClientConfig::new(&srv.url("/"), ROOM, name))
+        .await
+        .expect("failed to join room");
+
+    tokio::time::timeout(TIMEOUT, async {
+        while let Some(event) = client.next().await {
+            if let ClientEvent::Message(ToSessionMessage::RoomState { .. }) = event {
+                return;
+            }
+        }
+
+        panic!("event stream ended before the room state");
+    }).await.expect("no room state");
+
+    client
+}
+
+#[actix_rt::test]
+async fn rooms_and_connections_are_counted() {
+    metrics::register();
+
+    let data = app_data();
+    server::register_room(&data, fixture_stream(), String::from(ROOM)).await.unwrap();
+    let srv = test::start(move || {
+        App::new()
+            .wrap(server::identity_service())
+            .configure(server::configure(data.clone()))
+    });
+
+    let _alice = join(&srv, "alice").await;
+    let _bob = join(&srv, "bob").await;
+
+    let (status, _, body) = fetch(&srv, "/metrics", None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let metrics = String::from_utf8(body).unwrap();
+    for expected in &[
+        String::from("tmtusync_rooms 1"),
+        String::from("tmtusync_websocket_connects_total 2"),
+        format!("tmtusync_room_participants{{room=\"{}\"}} 2", ROOM),
+    ] {
+        assert!(metrics.lines().any(|l| l == expected), "no {:?} in\n{}", expected, metrics);
+    }
+}
@@ -0,0 +1,58 @@
+//! The protocol definitions checked in under `protocol/` have to match what is generated from
+//! `protocol.rs`. Run with `TMTUSYNC_UPDATE_PROTOCOL=1` to regenerate them after changing it.
+
+use tmtusync::schema;
+
+use std::fs;
+use std::path::Path;
+
+const SCHEMA: &str = "protocol/schema.json";
+const TYPESCRIPT: &str = "protocol/protocol.d.ts";
+
+/// Whether the checked in files should be overwritten instead of compared.
+fn update() -> bool {
+    std::env::var_os("TMTUSYNC_UPDATE_PROTOCOL").is_some()
+}
+
+fn checked_in(path: &str) -> String {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(path);
+
+    fs::read_to_string(&path).unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e))
+}
+
+fn write(path: &str, contents: &str) {
+    fs::write(Path::new(env!("CARGO_MANIFEST_DIR")).join(path), contents).unwrap();
+}
+
+#[test]
+fn json_schema_is_up_to_date() {
+    let generated = schema::json_schema();
+
+    if update() {
+        write(SCHEMA, &(serde_json::to_string_pretty(&generated).unwrap() + "\n"));
+        return;
+    }
+
+    let expected: serde_json::Value = serde_json::from_str(&checked_in(SCHEMA)).unwrap();
+    assert!(
+        expected == generated,
+        "{} is out of date, run the tests with TMTUSYNC_UPDATE_PROTOCOL=1 to regenerate it",
+        SCHEMA,
+    );
+}
+
+#[test]
+fn typescript_is_up_to_date() {
+    let generated = schema::typescript();
+
+    if update() {
+        write(TYPESCRIPT, &generated);
+        return;
+    }
+
+    assert!(
+        checked_in(TYPESCRIPT) == generated,
+        "{} is out of date, run the tests with TMTUSYNC_UPDATE_PROTOCOL=1 to regenerate it",
+        TYPESCRIPT,
+    );
+}
@@ -0,0 +1,1483 @@
+//! End-to-end tests driving real websocket sessions against the full app.
+
+mod common;
+
+use actix::Actor;
+use actix_web::{http::{header, StatusCode}, test, web, App, HttpResponse};
+use futures::{SinkExt, StreamExt};
+
+use tmtusync::actors::{MediaSource, MediaStream, RoomRepository, ShutdownRooms, TransportConfig};
+use tmtusync::client::{ClientConfig, ClientError, ClientEvent, PlayerState, RoomClient};
+use tmtusync::audit::{AuditEvent, AuditEventKind};
+use tmtusync::codec::{Encoding, Frame};
+use tmtusync::clock::{Clock, MockClock, SharedClock, SystemClock};
+use tmtusync::cluster::{ClaimError, Cluster, MemoryDirectory, NodeInfo, RoomDirectory};
+use tmtusync::library;
+use tmtusync::recording::{self, RecordedEvent};
+use tmtusync::subtitles;
+use tmtusync::proxy::{HlsProxy, ProxyConfig};
+use tmtusync::protocol::{
+    BadgeId, PlayState, ProtocolError, RejectReason, Stream, Time, ToSessionMessage, UserId, UserMessage,
+    PROTOCOL_VERSION,
+};
+use tmtusync::server::{self, AdminConfig, AppData};
+use tmtusync::signing::UrlSigner;
+
+use common::{fetch, fixture_stream, login, TempDir};
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+const ROOM: &str = "TEST1";
+const CLUSTER_SECRET: &str = "cluster secret";
+
+/// How long to wait for an expected message. Generous, since pings only go out every 5 seconds.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn start_server() -> test::TestServer {
+    start_server_with_clock(SystemClock::shared()).await
+}
+
+async fn start_server_with_clock(clock: SharedClock) -> test::TestServer {
+    start_server_with(clock, None).await
+}
+
+/// App data without any rooms.
+fn app_data(clock: SharedClock, record_dir: Option<PathBuf>) -> AppData {
+    AppData {
+        room_repo: RoomRepository::default().start(),
+        clock: clock.clone(),
+        transport: TransportConfig::default(),
+        admin: AdminConfig::default(),
+        audit_dir: None,
+        record_dir,
+        cluster: None,
+        library: None,
+        transcoder: None,
+        uploads: None,
+        media_dir: PathBuf::from("data/media"),
+        master_playlists: Default::default(),
+        media_signer: UrlSigner::new(&b"test key"[..], chrono::Duration::hours(1)),
+        // The remote stand-ins are on loopback.
+        proxy: Some(HlsProxy::new(ProxyConfig { allow_private: true, ..ProxyConfig::default() }, clock)),
+    }
+}
+
+async fn start_server_with(clock: SharedClock, record_dir: Option<PathBuf>) -> test::TestServer {
+    let data = app_data(clock, record_dir);
+    server::register_room(&data, fixture_stream(), String::from(ROOM)).await.unwrap();
+
+    serve(data)
+}
+
+fn serve(data: AppData) -> test::TestServer {
+    test::start(move || {
+        App::new()
+            .wrap(server::identity_service())
+            .configure(server::configure(data.clone()))
+    })
+}
+
+async fn join(srv: &test::TestServer, name: &str) -> RoomClient {
+    RoomClient::connect(ClientConfig::new(&srv.url("/"), ROOM, name))
+        .await
+        .expect("failed to join room")
+}
+
+/// Waits for the first message matching `pred`, skipping everything else.
+async fn expect<T>(
+    client: &mut RoomClient,
+    mut pred: impl FnMut(&ToSessionMessage) -> Option<T>,
+) -> T {
+    let wait = async {
+        while let Some(event) = client.next().await {
+            match event {
+                ClientEvent::Message(message) => {
+                    if let Some(value) = pred(&message) {
+                        return value;
+                    }
+                }
+                ClientEvent::Closed => panic!("connection closed while waiting"),
+            }
+        }
+
+        panic!("event stream ended while waiting");
+    };
+
+    tokio::time::timeout(TIMEOUT, wait)
+        .await
+        .expect("timed out waiting for message")
+}
+
+async fn expect_room_state(client: &mut RoomClient) -> (UserId, Vec<UserId>) {
+    expect(client, |m| match m {
+        ToSessionMessage::RoomState { user_id, participants, current_stream } => {
+            assert!(current_stream.is_some());
+
+            Some((*user_id, participants.iter().map(|p| p.user_id).collect()))
+        }
+        _ => None,
+    }).await
+}
+
+#[actix_rt::test]
+async fn join_receives_room_state() {
+    let srv = start_server().await;
+    let mut alice = join(&srv, "alice").await;
+
+    let (id, participants) = expect_room_state(&mut alice).await;
+
+    assert_eq!(alice.user_id(), Some(id));
+    assert!(participants.is_empty());
+    assert_eq!(alice.room().stream.unwrap().slug, "test");
+}
+
+#[actix_rt::test]
+async fn unknown_room_is_refused() {
+    let srv = start_server().await;
+
+    let result = RoomClient::connect(ClientConfig::new(&srv.url("/"), "NOPE", "alice")).await;
+
+    assert!(matches!(result, Err(ClientError::RoomNotFound(_))));
+}
+
+#[actix_rt::test]
+async fn new_participant_is_announced() {
+    let srv = start_server().await;
+    let mut alice = join(&srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+
+    let mut bob = join(&srv, "bob").await;
+    let (bob_id, participants) = expect_room_state(&mut bob).await;
+    assert_eq!(participants, vec![alice_id]);
+
+    let announced = expect(&mut alice, |m| match m {
+        ToSessionMessage::NewParticipant { user_id, name, .. } => Some((*user_id, name.clone())),
+        _ => None,
+    }).await;
+    assert_eq!(announced, (bob_id, String::from("bob")));
+}
+
+#[actix_rt::test]
+async fn ping_is_answered_with_state() {
+    let srv = start_server().await;
+    let mut alice = join(&srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+
+    // The client answers the ping with a `State`, which makes the room broadcast an update
+    // containing our position.
+    expect(&mut alice, |m| match m {
+        ToSessionMessage::Ping => Some(()),
+        _ => None,
+    }).await;
+
+    let update = expect(&mut alice, |m| match m {
+        ToSessionMessage::RoomUpdate { participants } => {
+            participants.iter().find(|p| p.user_id == alice_id).cloned()
+        }
+        _ => None,
+    }).await;
+    assert_eq!(update.state, PlayState::Pause);
+}
+
+#[actix_rt::test]
+async fn seek_is_broadcast() {
+    let srv = start_server().await;
+    let mut alice = join(&srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+    let mut bob = join(&srv, "bob").await;
+    expect_room_state(&mut bob).await;
+
+    alice.seek(42.0);
+
+    let seek = expect(&mut bob, |m| match m {
+        ToSessionMessage::DoSeek { user, duration } => Some((*user, *duration)),
+        _ => None,
+    }).await;
+    assert_eq!(seek, (alice_id, 42.0));
+}
+
+#[actix_rt::test]
+async fn set_state_is_broadcast() {
+    let srv = start_server().await;
+    let mut alice = join(&srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+    let mut bob = join(&srv, "bob").await;
+    expect_room_state(&mut bob).await;
+
+    alice.set_state(PlayState::Play);
+
+    let state = expect(&mut bob, |m| match m {
+        ToSessionMessage::SetState { user, state } => Some((*user, *state)),
+        _ => None,
+    }).await;
+    assert_eq!(state, (alice_id, PlayState::Play));
+}
+
+#[actix_rt::test]
+async fn late_joiner_sees_room_position() {
+    let srv = start_server().await;
+    let mut alice = join(&srv, "alice").await;
+    expect_room_state(&mut alice).await;
+
+    alice.seek(100.0);
+
+    // Give the room a moment to process the seek before joining.
+    tokio::time::delay_for(Duration::from_millis(200)).await;
+
+    let mut bob = join(&srv, "bob").await;
+    expect_room_state(&mut bob).await;
+
+    let stream = bob.room().stream.unwrap();
+    assert_eq!(stream.state, PlayState::Pause);
+    assert!(stream.duration >= 100.0, "room at {}", stream.duration);
+}
+
+#[actix_rt::test]
+async fn leaving_is_announced() {
+    let srv = start_server().await;
+    let mut alice = join(&srv, "alice").await;
+    expect_room_state(&mut alice).await;
+    let mut bob = join(&srv, "bob").await;
+    let (bob_id, _) = expect_room_state(&mut bob).await;
+
+    bob.close();
+
+    let left = expect(&mut alice, |m| match m {
+        ToSessionMessage::ByeParticipant { user_id } => Some(*user_id),
+        _ => None,
+    }).await;
+    assert_eq!(left, bob_id);
+}
+
+/// Waits for a ping and the room update caused by our answer, after which the room has a time
+/// mapping for the participant.
+async fn expect_mapped(client: &mut RoomClient, user_id: UserId) {
+    expect(client, |m| match m {
+        ToSessionMessage::Ping => Some(()),
+        _ => None,
+    }).await;
+
+    expect(client, |m| match m {
+        ToSessionMessage::RoomUpdate { participants } => {
+            participants.iter().find(|p| p.user_id == user_id).map(|_| ())
+        }
+        _ => None,
+    }).await;
+}
+
+#[actix_rt::test]
+async fn pings_follow_the_clock() {
+    let clock = MockClock::new(chrono::Utc::now());
+    let srv = start_server_with_clock(clock.shared()).await;
+    let mut alice = join(&srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+
+    // The first ping goes out right away, the next one only once the clock has moved on.
+    expect_mapped(&mut alice, alice_id).await;
+
+    // Longer than the ping interval, on the wall clock the room would have pinged again by now.
+    let ping = expect(&mut alice, |m| match m {
+        ToSessionMessage::Ping => Some(()),
+        _ => None,
+    });
+    assert!(tokio::time::timeout(Duration::from_secs(6), ping).await.is_err(), "pinged before the clock moved");
+
+    clock.advance(Duration::from_secs(5));
+    expect_mapped(&mut alice, alice_id).await;
+}
+
+#[actix_rt::test]
+async fn playing_room_advances_with_clock() {
+    let clock = MockClock::new(chrono::Utc::now());
+    let srv = start_server_with_clock(clock.shared()).await;
+    let mut alice = join(&srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+    expect_mapped(&mut alice, alice_id).await;
+
+    alice.set_state(PlayState::Play);
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    clock.advance(Duration::from_secs(10));
+
+    let mut bob = join(&srv, "bob").await;
+    expect_room_state(&mut bob).await;
+
+    // The client stamps its messages with the wall clock, so allow for the real time spent
+    // between answering the ping and asking to play.
+    let stream = bob.room().stream.unwrap();
+    assert_eq!(stream.state, PlayState::Play);
+    assert!((stream.duration - 10.0).abs() < 0.5, "room at {}", stream.duration);
+}
+
+#[actix_rt::test]
+async fn invalid_seek_is_refused() {
+    let srv = start_server().await;
+    let mut alice = join(&srv, "alice").await;
+    expect_room_state(&mut alice).await;
+
+    alice.seek(-5.0);
+
+    let error = expect(&mut alice, |m| match m {
+        ToSessionMessage::Error(ProtocolError::InvalidNumber { field }) => Some(field.clone()),
+        _ => None,
+    }).await;
+    assert_eq!(error, "duration");
+}
+
+#[actix_rt::test]
+async fn extreme_timestamps_are_refused() {
+    let srv = start_server().await;
+    let mut alice = join(&srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+    let mut bob = join(&srv, "bob").await;
+    expect_room_state(&mut bob).await;
+
+    for time in &[i64::MIN, i64::MAX] {
+        alice.send(UserMessage::SetState { state: PlayState::Play, time: Time(*time) });
+
+        let error = expect(&mut alice, |m| match m {
+            ToSessionMessage::Error(ProtocolError::InvalidTime { field }) => Some(field.clone()),
+            _ => None,
+        }).await;
+        assert_eq!(error, "time");
+    }
+
+    // Still connected, and nothing reached the room.
+    alice.set_state(PlayState::Play);
+    let state = expect(&mut bob, |m| match m {
+        ToSessionMessage::SetState { user, state } => Some((*user, *state)),
+        _ => None,
+    }).await;
+    assert_eq!(state, (alice_id, PlayState::Play));
+}
+
+#[actix_rt::test]
+async fn seek_past_end_is_clamped() {
+    let srv = start_server().await;
+    let mut alice = join(&srv, "alice").await;
+    expect_room_state(&mut alice).await;
+    let mut bob = join(&srv, "bob").await;
+    expect_room_state(&mut bob).await;
+
+    alice.seek(5000.0);
+
+    let duration = expect(&mut bob, |m| match m {
+        ToSessionMessage::DoSeek { duration, .. } => Some(*duration),
+        _ => None,
+    }).await;
+    assert_eq!(duration, 3600.0);
+}
+
+/// A room on `clock` whose transports give up on silent clients after half a second.
+async fn start_server_with_transport(clock: SharedClock, transport: TransportConfig) -> test::TestServer {
+    let mut data = app_data(clock, None);
+    data.transport = transport;
+    server::register_room(&data, fixture_stream(), String::from(ROOM)).await.unwrap();
+
+    serve(data)
+}
+
+fn short_heartbeat() -> TransportConfig {
+    TransportConfig {
+        heartbeat_interval: Duration::from_millis(100),
+        client_timeout: Duration::from_millis(500),
+        ..TransportConfig::default()
+    }
+}
+
+#[actix_rt::test]
+async fn silent_client_is_disconnected() {
+    let srv = start_server_with_transport(SystemClock::shared(), short_heartbeat()).await;
+    let cookie = login(&srv, ROOM, "alice").await;
+
+    let (_, mut socket) = awc::Client::new()
+        .ws(srv.url(&format!("/websocket/{}", ROOM)).replacen("http", "ws", 1))
+        .header(header::COOKIE, cookie)
+        .connect()
+        .await
+        .unwrap();
+
+    // Reads the pings but never answers them, or says anything else.
+    let connected = std::time::Instant::now();
+    let pings = tokio::time::timeout(TIMEOUT, async {
+        let mut pings = 0;
+
+        while let Some(Ok(frame)) = socket.next().await {
+            match frame {
+                awc::ws::Frame::Ping(_) => pings += 1,
+                awc::ws::Frame::Close(_) => break,
+                _ => {}
+            }
+        }
+
+        pings
+    }).await.expect("silent client wasn't disconnected");
+
+    assert!(pings > 0);
+    assert!(connected.elapsed() >= Duration::from_millis(500), "disconnected after {:?}", connected.elapsed());
+}
+
+#[actix_rt::test]
+async fn answering_pings_keeps_client_connected() {
+    // The room's own pings follow the clock, so after the first one only the websocket pongs
+    // are left to show the client is alive.
+    let clock = MockClock::new(chrono::Utc::now());
+    let srv = start_server_with_transport(clock.shared(), short_heartbeat()).await;
+    let mut alice = join(&srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+    expect_mapped(&mut alice, alice_id).await;
+
+    let closed = tokio::time::timeout(Duration::from_millis(1500), async {
+        while let Some(event) = alice.next().await {
+            if let ClientEvent::Closed = event {
+                return;
+            }
+        }
+    }).await;
+    assert!(closed.is_err(), "disconnected although the client answered every ping");
+}
+
+/// A room keeping an audit log in `dir`, where logins from the test client count as admin logins.
+async fn start_server_with_audit_log(dir: &Path, transport: TransportConfig) -> test::TestServer {
+    let mut data = app_data(SystemClock::shared(), None);
+    data.transport = transport;
+    data.audit_dir = Some(dir.to_owned());
+    data.admin.address = String::from("127.0.0.1");
+    server::register_room(&data, fixture_stream(), String::from(ROOM)).await.unwrap();
+
+    serve(data)
+}
+
+async fn fetch_audit_log(srv: &test::TestServer, cookie: &str) -> (StatusCode, Vec<AuditEvent>) {
+    match fetch(srv, &format!("/room/{}/audit", ROOM), Some(cookie)).await {
+        (StatusCode::OK, _, body) => (StatusCode::OK, serde_json::from_slice(&body).unwrap()),
+        (status, _, _) => (status, Vec::new()),
+    }
+}
+
+#[actix_rt::test]
+async fn only_host_and_admins_read_audit_log() {
+    let dir = TempDir::new("audit");
+    let srv = start_server_with_audit_log(&dir, TransportConfig::default()).await;
+
+    let mut alice = join(&srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+    assert_eq!(alice_id, UserId(0));
+    let mut bob = join(&srv, "bob").await;
+    expect_room_state(&mut bob).await;
+
+    let (status, events) = fetch_audit_log(&srv, alice.cookie()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(events.iter().any(|e| e.user == Some(alice_id) && e.event == AuditEventKind::Join));
+
+    assert_eq!(fetch_audit_log(&srv, bob.cookie()).await.0, StatusCode::FORBIDDEN);
+
+    // Logging in with the host's nickname hands out a cookie of its own.
+    let impostor = login(&srv, ROOM, "alice").await;
+    assert_ne!(impostor, alice.cookie());
+    assert_eq!(fetch_audit_log(&srv, &impostor).await.0, StatusCode::FORBIDDEN);
+
+    let admin = login(&srv, ROOM, "tmtu").await;
+    assert_eq!(fetch_audit_log(&srv, &admin).await.0, StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn heartbeat_timeouts_are_audited() {
+    let dir = TempDir::new("audit");
+    let srv = start_server_with_audit_log(&dir, short_heartbeat()).await;
+    let cookie = login(&srv, ROOM, "alice").await;
+
+    let (_, mut socket) = awc::Client::new()
+        .ws(srv.url(&format!("/websocket/{}", ROOM)).replacen("http", "ws", 1))
+        .header(header::COOKIE, cookie)
+        .connect()
+        .await
+        .unwrap();
+
+    let hello = UserMessage::Hello {
+        name: String::from("alice"),
+        avatar: BadgeId(0),
+        time: Time::now(),
+        version: PROTOCOL_VERSION,
+        features: Vec::new(),
+    };
+    socket.send(awc::ws::Message::Text(serde_json::to_string(&hello).unwrap())).await.unwrap();
+
+    // Joins the room, then never answers a ping.
+    tokio::time::timeout(TIMEOUT, async {
+        while let Some(Ok(frame)) = socket.next().await {
+            if let awc::ws::Frame::Close(_) = frame {
+                break;
+            }
+        }
+    }).await.expect("silent client wasn't disconnected");
+
+    let admin = login(&srv, ROOM, "tmtu").await;
+    let (status, events) = fetch_audit_log(&srv, &admin).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(
+        events.iter().any(|e| e.event == AuditEventKind::Kick { reason: String::from("timed out") }),
+        "no kick in {:?}",
+        events,
+    );
+}
+
+fn hello(version: u32) -> UserMessage {
+    UserMessage::Hello {
+        name: String::from("alice"),
+        avatar: BadgeId(0),
+        time: Time::now(),
+        version,
+        features: Vec::new(),
+    }
+}
+
+/// Sends `messages` over a raw socket, skipping the client's handshake, and returns why the
+/// server rejected them once it has closed the socket.
+async fn handshake_rejection(srv: &test::TestServer, messages: &[UserMessage]) -> RejectReason {
+    let cookie = login(srv, ROOM, "alice").await;
+
+    let (_, mut socket) = awc::Client::new()
+        .ws(srv.url(&format!("/websocket/{}", ROOM)).replacen("http", "ws", 1))
+        .header(header::COOKIE, cookie)
+        .connect()
+        .await
+        .unwrap();
+
+    for message in messages {
+        socket.send(awc::ws::Message::Text(serde_json::to_string(message).unwrap())).await.unwrap();
+    }
+
+    tokio::time::timeout(TIMEOUT, async {
+        let mut rejection = None;
+
+        while let Some(Ok(frame)) = socket.next().await {
+            match frame {
+                awc::ws::Frame::Text(text) => {
+                    if let Ok(ToSessionMessage::Rejected { reason, .. }) = serde_json::from_slice(&text) {
+                        rejection = Some(reason);
+                    }
+                }
+                awc::ws::Frame::Close(_) => break,
+                _ => {}
+            }
+        }
+
+        rejection.expect("socket closed without a rejection")
+    }).await.expect("rejected client wasn't disconnected")
+}
+
+#[actix_rt::test]
+async fn unsupported_versions_are_rejected() {
+    let srv = start_server().await;
+
+    let reason = handshake_rejection(&srv, &[hello(PROTOCOL_VERSION + 1)]).await;
+    assert!(matches!(reason, RejectReason::UnsupportedVersion), "{:?}", reason);
+}
+
+#[actix_rt::test]
+async fn messages_before_hello_are_rejected() {
+    let srv = start_server().await;
+
+    let reason = handshake_rejection(&srv, &[UserMessage::Seek { duration: 1.0, time: Time::now() }]).await;
+    assert!(matches!(reason, RejectReason::HandshakeRequired), "{:?}", reason);
+}
+
+#[actix_rt::test]
+async fn duplicate_hellos_are_rejected() {
+    let srv = start_server().await;
+
+    let reason = handshake_rejection(&srv, &[hello(PROTOCOL_VERSION), hello(PROTOCOL_VERSION)]).await;
+    assert!(matches!(reason, RejectReason::DuplicateHello), "{:?}", reason);
+
+    // Whoever was rejected doesn't stay in the room.
+    let mut bob = join(&srv, "bob").await;
+    let (_, participants) = expect_room_state(&mut bob).await;
+    assert!(participants.is_empty(), "{:?}", participants);
+}
+
+#[actix_rt::test]
+async fn binary_encodings_are_negotiated() {
+    let srv = start_server().await;
+
+    for encoding in &[Encoding::MessagePack, Encoding::Cbor] {
+        let cookie = login(&srv, ROOM, "alice").await;
+        let (response, mut socket) = awc::Client::new()
+            .ws(srv.url(&format!("/websocket/{}", ROOM)).replacen("http", "ws", 1))
+            .protocols(&[encoding.protocol(), Encoding::Json.protocol()])
+            .header(header::COOKIE, cookie)
+            .connect()
+            .await
+            .unwrap();
+        assert_eq!(response.headers().get(header::SEC_WEBSOCKET_PROTOCOL).unwrap(), encoding.protocol());
+
+        let hello = match encoding.encode(&hello(PROTOCOL_VERSION)).unwrap() {
+            Frame::Binary(bytes) => awc::ws::Message::Binary(bytes.into()),
+            Frame::Text(_) => panic!("{:?} encoded as text", encoding),
+        };
+        socket.send(hello).await.unwrap();
+
+        // Everything comes back in binary frames of the same encoding.
+        tokio::time::timeout(TIMEOUT, async {
+            while let Some(frame) = socket.next().await {
+                match frame.unwrap() {
+                    awc::ws::Frame::Binary(bytes) => match encoding.decode_binary(&bytes).unwrap() {
+                        ToSessionMessage::RoomState { current_stream, .. } => {
+                            assert_eq!(current_stream.unwrap().slug, "test");
+                            return;
+                        }
+                        _ => {}
+                    },
+                    awc::ws::Frame::Text(text) => panic!("{:?} session got a text frame: {:?}", encoding, text),
+                    _ => {}
+                }
+            }
+
+            panic!("{:?} session closed before the room state", encoding);
+        }).await.expect("no room state");
+
+        socket.close().await.unwrap();
+    }
+}
+
+async fn expect_seek(client: &mut RoomClient) -> f32 {
+    expect(client, |m| match m {
+        ToSessionMessage::DoSeek { duration, .. } => Some(*duration),
+        _ => None,
+    }).await
+}
+
+#[actix_rt::test]
+async fn seeks_are_rate_limited_by_the_clock() {
+    let clock = MockClock::new(chrono::Utc::now());
+    let transport = TransportConfig { seek_debounce: Duration::from_secs(0), ..TransportConfig::default() };
+    let srv = start_server_with_transport(clock.shared(), transport).await;
+    let mut alice = join(&srv, "alice").await;
+    expect_room_state(&mut alice).await;
+    let mut bob = join(&srv, "bob").await;
+    expect_room_state(&mut bob).await;
+
+    // A burst of five goes through, the sixth is dropped.
+    for position in 1..=6 {
+        alice.seek(position as f32);
+    }
+
+    let kind = expect(&mut alice, |m| match m {
+        ToSessionMessage::RateLimited { kind, .. } => Some(kind.clone()),
+        _ => None,
+    }).await;
+    assert_eq!(kind, "Seek");
+
+    for position in 1..=5 {
+        assert_eq!(expect_seek(&mut bob).await, position as f32);
+    }
+
+    // One more token a second, on the room's clock.
+    clock.advance(Duration::from_secs(1));
+    alice.seek(7.0);
+    assert_eq!(expect_seek(&mut bob).await, 7.0);
+}
+
+#[actix_rt::test]
+async fn seeks_are_debounced_by_the_clock() {
+    let clock = MockClock::new(chrono::Utc::now());
+    let srv = start_server_with_clock(clock.shared()).await;
+    let mut alice = join(&srv, "alice").await;
+    expect_room_state(&mut alice).await;
+    let mut bob = join(&srv, "bob").await;
+    expect_room_state(&mut bob).await;
+
+    alice.seek(10.0);
+    assert_eq!(expect_seek(&mut bob).await, 10.0);
+
+    // Held back until the debounce window is over on the clock, however long that takes.
+    alice.seek(20.0);
+    alice.seek(30.0);
+    let early = tokio::time::timeout(Duration::from_millis(500), expect_seek(&mut bob)).await;
+    assert!(early.is_err(), "seek passed on before the clock moved");
+
+    // Only the last of the held back seeks is passed on.
+    clock.advance(Duration::from_millis(250));
+    assert_eq!(expect_seek(&mut bob).await, 30.0);
+}
+
+/// Reads the recording at `path` once it holds `goodbyes` goodbyes.
+async fn recorded_goodbyes(path: &Path, goodbyes: usize) -> Vec<RecordedEvent> {
+    let wait = async {
+        loop {
+            let events = recording::load(path).unwrap();
+            let recorded = events.iter().filter(|e| matches!(e, RecordedEvent::Incoming {
+                message: UserMessage::Goodbye,
+                ..
+            })).count();
+
+            if recorded >= goodbyes {
+                return events;
+            }
+
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+    };
+
+    tokio::time::timeout(TIMEOUT, wait).await.expect("goodbyes weren't recorded")
+}
+
+#[actix_rt::test]
+async fn recorded_session_replays_identically() {
+    let dir = TempDir::new("recording");
+    let clock = MockClock::new(chrono::Utc::now());
+    let srv = start_server_with(clock.shared(), Some(dir.to_path_buf())).await;
+
+    let mut alice = join(&srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+    expect_mapped(&mut alice, alice_id).await;
+
+    let mut bob = join(&srv, "bob").await;
+    let (bob_id, _) = expect_room_state(&mut bob).await;
+    expect_mapped(&mut bob, bob_id).await;
+
+    alice.seek(600.0);
+    expect(&mut bob, |m| match m {
+        ToSessionMessage::DoSeek { .. } => Some(()),
+        _ => None,
+    }).await;
+
+    clock.advance(Duration::from_secs(5));
+    expect_mapped(&mut alice, alice_id).await;
+
+    bob.close();
+    expect(&mut alice, |m| match m {
+        ToSessionMessage::ByeParticipant { .. } => Some(()),
+        _ => None,
+    }).await;
+    alice.close();
+
+    let path = std::fs::read_dir(&*dir).unwrap().next().unwrap().unwrap().path();
+    let events = recorded_goodbyes(&path, 2).await;
+
+    // The handshake is answered by the transports.
+    let welcomes = events.iter().filter(|e| matches!(e, RecordedEvent::Transport {
+        message: ToSessionMessage::Welcome { .. },
+        ..
+    })).count();
+    assert_eq!(welcomes, 2);
+
+    let report = recording::replay(events).await.unwrap();
+    assert!(report.messages > 0);
+    assert!(report.is_clean(), "{:#?}", report.mismatches);
+}
+
+#[actix_rt::test]
+async fn shutdown_pauses_room_and_restores_it() {
+    let data = app_data(SystemClock::shared(), None);
+    server::register_room(&data, fixture_stream(), String::from(ROOM)).await.unwrap();
+    let room_repo = data.room_repo.clone();
+    let srv = serve(data);
+
+    let mut alice = join(&srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+    expect_mapped(&mut alice, alice_id).await;
+
+    alice.set_state(PlayState::Play);
+    tokio::time::delay_for(Duration::from_millis(200)).await;
+
+    let rooms = room_repo.send(ShutdownRooms).await.unwrap();
+    assert_eq!(rooms.len(), 1);
+
+    let duration = expect(&mut alice, |m| match m {
+        ToSessionMessage::Restarting { duration } => Some(*duration),
+        _ => None,
+    }).await;
+    assert_eq!(duration, rooms[0].duration);
+    assert!(duration > 0.0, "room at {}", duration);
+
+    let closed = async {
+        while let Some(event) = alice.next().await {
+            if let ClientEvent::Closed = event {
+                return;
+            }
+        }
+    };
+    tokio::time::timeout(TIMEOUT, closed).await.expect("connection wasn't closed");
+
+    // Rejoining the restored room keeps the user id and the paused position.
+    let data = app_data(SystemClock::shared(), None);
+    server::restore_room(&data, rooms[0].clone()).await.unwrap();
+    let srv = serve(data);
+
+    let mut alice = join(&srv, "alice").await;
+    let (id, _) = expect_room_state(&mut alice).await;
+    assert_eq!(id, alice_id);
+
+    let stream = alice.room().stream.unwrap();
+    assert_eq!(stream.state, PlayState::Pause);
+    assert_eq!(stream.duration, duration);
+}
+
+#[actix_rt::test]
+async fn sessions_on_other_nodes_reach_the_owner() {
+    let directory = MemoryDirectory::default();
+    let media = TempDir::new("cluster-media");
+    write_rendition(&media.join("test"), 1_000);
+    std::fs::rename(media.join("test/index.m3u8"), media.join("test/master.m3u8")).unwrap();
+
+    // Addresses are only known once the servers are up. Only the owner's is ever used, by the
+    // other node when forwarding.
+    let mut owner = app_data(SystemClock::shared(), None);
+    owner.media_dir = media.to_path_buf();
+    owner.cluster = Some(Cluster::new(NodeInfo::new("a", ""), directory.shared(), CLUSTER_SECRET));
+    let owner_srv = serve(owner.clone());
+    let node = NodeInfo::new("a", &owner_srv.url(""));
+    owner.cluster = Some(Cluster::new(node.clone(), directory.shared(), CLUSTER_SECRET));
+    server::register_room(&owner, fixture_stream(), String::from(ROOM)).await.unwrap();
+
+    // The other node has the room configured as well, but doesn't get to own it.
+    let mut other = app_data(SystemClock::shared(), None);
+    other.cluster = Some(Cluster::new(NodeInfo::new("b", ""), directory.shared(), CLUSTER_SECRET));
+    let registered = server::register_room(&other, fixture_stream(), String::from(ROOM)).await;
+    assert!(matches!(registered, Err(ClaimError::OwnedElsewhere(ref owner)) if owner == "a"), "{:?}", registered);
+    let other_srv = serve(other);
+
+    // Only the nodes know the secret the room's metadata is guarded by.
+    let (status, _, _) = fetch(&owner_srv, &format!("/cluster/rooms/{}", ROOM), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let mut alice = join(&owner_srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+
+    let mut bob = join(&other_srv, "bob").await;
+    let (bob_id, participants) = expect_room_state(&mut bob).await;
+    assert_eq!(participants, vec![alice_id]);
+
+    let joined = expect(&mut alice, |m| match m {
+        ToSessionMessage::NewParticipant { user_id, .. } => Some(*user_id),
+        _ => None,
+    }).await;
+    assert_eq!(joined, bob_id);
+
+    bob.seek(42.0);
+
+    let seek = expect(&mut alice, |m| match m {
+        ToSessionMessage::DoSeek { user, duration } => Some((*user, *duration)),
+        _ => None,
+    }).await;
+    assert_eq!(seek, (bob_id, 42.0));
+
+    // The media is only on the owner, which gets the requests for it, checking who is asking.
+    let url = format!("/room/{}/media/master.m3u8", ROOM);
+    let (status, _, _) = fetch(&other_srv, &url, None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let query = owner.media_signer.query(ROOM, "master.m3u8", chrono::Utc::now());
+    let (status, headers, playlist) = fetch(&other_srv, &format!("{}?{}", url, query), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers.get(header::CONTENT_TYPE).unwrap(), "application/vnd.apple.mpegurl");
+    let playlist = String::from_utf8(playlist).unwrap();
+    assert!(playlist.contains("\nsegment00000.ts?expires="), "{}", playlist);
+
+    // The owner shuts down, handing the room over as `shutdown::on_signal` does.
+    let rooms = owner.room_repo.send(ShutdownRooms).await.unwrap();
+    directory.release(ROOM, &node, Some(&rooms[0])).await.unwrap();
+
+    // Whoever turns up at the other node next finds the room there, where it was left.
+    let mut carol = join(&other_srv, "carol").await;
+    expect_room_state(&mut carol).await;
+    assert_eq!(carol.room().stream.unwrap().duration, rooms[0].duration);
+    assert_eq!(directory.owner(ROOM).await.unwrap().map(|owner| owner.id), Some(String::from("b")));
+}
+
+#[actix_rt::test]
+async fn silent_clients_on_other_nodes_time_out() {
+    let directory = MemoryDirectory::default();
+
+    let mut owner = app_data(SystemClock::shared(), None);
+    owner.transport = short_heartbeat();
+    owner.cluster = Some(Cluster::new(NodeInfo::new("a", ""), directory.shared(), CLUSTER_SECRET));
+    let owner_srv = serve(owner.clone());
+    owner.cluster = Some(Cluster::new(NodeInfo::new("a", &owner_srv.url("")), directory.shared(), CLUSTER_SECRET));
+    server::register_room(&owner, fixture_stream(), String::from(ROOM)).await.unwrap();
+
+    let mut other = app_data(SystemClock::shared(), None);
+    other.cluster = Some(Cluster::new(NodeInfo::new("b", ""), directory.shared(), CLUSTER_SECRET));
+    let other_srv = serve(other);
+
+    let mut alice = join(&owner_srv, "alice").await;
+    expect_room_state(&mut alice).await;
+
+    let cookie = login(&other_srv, ROOM, "bob").await;
+    let (_, mut socket) = awc::Client::new()
+        .ws(other_srv.url(&format!("/websocket/{}", ROOM)).replacen("http", "ws", 1))
+        .header(header::COOKIE, cookie)
+        .connect()
+        .await
+        .unwrap();
+
+    let hello = UserMessage::Hello {
+        name: String::from("bob"),
+        avatar: BadgeId(0),
+        time: Time::now(),
+        version: PROTOCOL_VERSION,
+        features: Vec::new(),
+    };
+    socket.send(awc::ws::Message::Text(serde_json::to_string(&hello).unwrap())).await.unwrap();
+
+    let bob_id = expect(&mut alice, |m| match m {
+        ToSessionMessage::NewParticipant { user_id, .. } => Some(*user_id),
+        _ => None,
+    }).await;
+
+    // Bob gets the owner's pings through the other node, but never answers them.
+    let pings = tokio::time::timeout(TIMEOUT, async {
+        let mut pings = 0;
+
+        while let Some(Ok(frame)) = socket.next().await {
+            match frame {
+                awc::ws::Frame::Ping(_) => pings += 1,
+                awc::ws::Frame::Close(_) => break,
+                _ => {}
+            }
+        }
+
+        pings
+    }).await.expect("silent client wasn't disconnected");
+    assert!(pings > 0);
+
+    let left = expect(&mut alice, |m| match m {
+        ToSessionMessage::ByeParticipant { user_id } => Some(*user_id),
+        _ => None,
+    }).await;
+    assert_eq!(left, bob_id);
+}
+
+#[actix_rt::test]
+async fn hosts_read_the_audit_log_through_other_nodes() {
+    let dir = TempDir::new("cluster-audit");
+    let directory = MemoryDirectory::default();
+
+    let mut owner = app_data(SystemClock::shared(), None);
+    owner.audit_dir = Some(dir.to_path_buf());
+    owner.cluster = Some(Cluster::new(NodeInfo::new("a", ""), directory.shared(), CLUSTER_SECRET));
+    let owner_srv = serve(owner.clone());
+    owner.cluster = Some(Cluster::new(NodeInfo::new("a", &owner_srv.url("")), directory.shared(), CLUSTER_SECRET));
+    server::register_room(&owner, fixture_stream(), String::from(ROOM)).await.unwrap();
+
+    let mut other = app_data(SystemClock::shared(), None);
+    other.cluster = Some(Cluster::new(NodeInfo::new("b", ""), directory.shared(), CLUSTER_SECRET));
+    let other_srv = serve(other);
+
+    // The host joined through the other node and asks it for the log.
+    let mut alice = join(&other_srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+    assert_eq!(alice_id, UserId(0));
+    let mut bob = join(&other_srv, "bob").await;
+    expect_room_state(&mut bob).await;
+
+    let (status, events) = fetch_audit_log(&other_srv, alice.cookie()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(events.iter().any(|e| e.user == Some(alice_id) && e.event == AuditEventKind::Join));
+
+    let (status, _) = fetch_audit_log(&other_srv, bob.cookie()).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, headers, _) = fetch(&other_srv, &format!("/room/{}/audit?format=csv", ROOM), Some(alice.cookie())).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers.get(header::CONTENT_TYPE).unwrap(), "text/csv");
+    assert!(headers.get(header::CONTENT_DISPOSITION).is_some());
+}
+
+fn stream_with_renditions() -> MediaStream {
+    MediaStream {
+        streams: vec![
+            Stream { quality: 0, playlist: String::from("master.m3u8") },
+            Stream { quality: 480, playlist: String::from("480p/index.m3u8") },
+            Stream { quality: 720, playlist: String::from("720p/index.m3u8") },
+        ],
+        ..fixture_stream()
+    }
+}
+
+#[actix_rt::test]
+async fn low_buffer_suggests_lower_quality() {
+    let data = app_data(SystemClock::shared(), None);
+    server::register_room(&data, stream_with_renditions(), String::from(ROOM)).await.unwrap();
+    let srv = serve(data);
+
+    let mut config = ClientConfig::new(&srv.url("/"), ROOM, "alice");
+    config.features = vec![String::from("quality")];
+    let mut alice = RoomClient::connect(config).await.expect("failed to join room");
+    expect_room_state(&mut alice).await;
+
+    alice.set_player(PlayerState {
+        state: PlayState::Play,
+        buffered: 1.0,
+        quality: Some(720),
+        auto_quality: false,
+        ..PlayerState::default()
+    });
+
+    for _ in 0..3 {
+        alice.report_state();
+    }
+
+    let quality = expect(&mut alice, |m| match m {
+        ToSessionMessage::SuggestQuality { quality } => Some(*quality),
+        _ => None,
+    }).await;
+    assert_eq!(quality, 480);
+}
+
+#[actix_rt::test]
+async fn unknown_qualities_are_ignored() {
+    let data = app_data(SystemClock::shared(), None);
+    server::register_room(&data, stream_with_renditions(), String::from(ROOM)).await.unwrap();
+    let srv = serve(data);
+
+    let mut alice = join(&srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+
+    for quality in &[1080, 0, 720] {
+        alice.set_player(PlayerState { quality: Some(*quality), ..PlayerState::default() });
+        alice.report_state();
+    }
+
+    // The stream has no 1080p, and 0 is its master playlist, so the first quality anyone hears
+    // about is the 720p.
+    let quality = expect(&mut alice, |m| match m {
+        ToSessionMessage::RoomUpdate { participants } => {
+            participants.iter().find(|p| p.user_id == alice_id).and_then(|p| p.quality)
+        }
+        _ => None,
+    }).await;
+    assert_eq!(quality, 720);
+}
+
+/// A media playlist in `dir` with a single 6 second segment of `size` bytes.
+fn write_rendition(dir: &Path, size: usize) {
+    std::fs::create_dir_all(dir).unwrap();
+    std::fs::write(dir.join("segment00000.ts"), vec![0u8; size]).unwrap();
+    std::fs::write(
+        dir.join("index.m3u8"),
+        "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXTINF:6.000000,\nsegment00000.ts\n#EXT-X-ENDLIST\n",
+    ).unwrap();
+}
+
+#[actix_rt::test]
+async fn master_playlist_lists_renditions() {
+    let dir = TempDir::new("master");
+
+    write_rendition(&dir.join("test/480p"), 1_500);
+    write_rendition(&dir.join("test/720p"), 3_000);
+    std::fs::write(
+        dir.join("test/master.m3u8"),
+        "#EXTM3U\n\
+         #EXT-X-STREAM-INF:BANDWIDTH=4000,RESOLUTION=1280x720,CODECS=\"avc1.4d4028,mp4a.40.2\"\n720p/index.m3u8\n\
+         #EXT-X-STREAM-INF:BANDWIDTH=2000,RESOLUTION=854x480,CODECS=\"avc1.4d4028,mp4a.40.2\"\n480p/index.m3u8\n",
+    ).unwrap();
+
+    let clock = MockClock::new(chrono::Utc::now());
+    let mut data = app_data(clock.shared(), None);
+    data.media_dir = dir.to_path_buf();
+    let signer = data.media_signer.clone();
+    server::register_room(&data, stream_with_renditions(), String::from(ROOM)).await.unwrap();
+    let srv = serve(data);
+
+    let response = srv.get(format!("/room/{}/master.m3u8", ROOM)).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let alice = join(&srv, "alice").await;
+    let (_, _, playlist) = fetch(&srv, &format!("/room/{}/master.m3u8", ROOM), Some(alice.cookie())).await;
+    let playlist = String::from_utf8(playlist).unwrap();
+
+    // Best first, with the bandwidth measured from the segments and the rest taken from the
+    // stream's own master playlist.
+    let sign = |path: &str| signer.query(ROOM, path, clock.now());
+    let expected = format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n\
+         #EXT-X-STREAM-INF:BANDWIDTH=4000,AVERAGE-BANDWIDTH=4000,RESOLUTION=1280x720,\
+         CODECS=\"avc1.4d4028,mp4a.40.2\"\nmedia/720p/index.m3u8?{}\n\
+         #EXT-X-STREAM-INF:BANDWIDTH=2000,AVERAGE-BANDWIDTH=2000,RESOLUTION=854x480,\
+         CODECS=\"avc1.4d4028,mp4a.40.2\"\nmedia/480p/index.m3u8?{}\n",
+        sign("720p/index.m3u8"),
+        sign("480p/index.m3u8"),
+    );
+    assert_eq!(playlist, expected);
+
+    // Measured only once, the segments aren't looked at again.
+    std::fs::remove_file(dir.join("test/720p/segment00000.ts")).unwrap();
+    let (status, _, playlist) = fetch(&srv, &format!("/room/{}/master.m3u8", ROOM), Some(alice.cookie())).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(String::from_utf8(playlist).unwrap(), expected);
+
+    let response = srv.get("/room/NOPE/master.m3u8").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn media_is_served_to_participants_and_signed_urls() {
+    let dir = TempDir::new("media");
+    write_rendition(&dir.join("test/480p"), 1_500);
+    std::fs::write(dir.join("secret"), "not media").unwrap();
+
+    let clock = MockClock::new(chrono::Utc::now());
+    let mut data = app_data(clock.shared(), None);
+    data.media_dir = dir.to_path_buf();
+    let signer = data.media_signer.clone();
+    server::register_room(&data, stream_with_renditions(), String::from(ROOM)).await.unwrap();
+    let srv = serve(data);
+
+    let playlist_url = format!("/room/{}/media/480p/index.m3u8", ROOM);
+    let (status, _, _) = fetch(&srv, &playlist_url, None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Nothing is left in the public static tree.
+    let (status, _, _) = fetch(&srv, "/static/data/test/480p/index.m3u8", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let alice = join(&srv, "alice").await;
+    let (status, _, playlist) = fetch(&srv, &playlist_url, Some(alice.cookie())).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let query = signer.query(ROOM, "480p/segment00000.ts", clock.now());
+    let playlist = String::from_utf8(playlist).unwrap();
+    assert!(playlist.contains(&format!("\nsegment00000.ts?{}\n", query)), "{}", playlist);
+
+    // The signed segment needs no cookie, until it expires.
+    let segment_url = format!("/room/{}/media/480p/segment00000.ts?{}", ROOM, query);
+    let (status, _, segment) = fetch(&srv, &segment_url, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(segment.len(), 1_500);
+
+    let (status, _, _) = fetch(&srv, &segment_url.replace("signature=", "signature=0"), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Signed for another file.
+    let (status, _, _) = fetch(&srv, &format!("/room/{}/media/480p/index.m3u8?{}", ROOM, query), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (status, _, _) = fetch(&srv, &format!("/room/{}/media/../secret", ROOM), Some(alice.cookie())).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    clock.advance(Duration::from_secs(2 * 3600));
+    let (status, _, _) = fetch(&srv, &segment_url, None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+/// A remote host serving a master playlist with one rendition below `/hls`, counting how often
+/// its first segment is fetched. That one takes a while, so requests for it overlap.
+fn remote_stand_in(segment_fetches: Arc<AtomicUsize>) -> test::TestServer {
+    let playlist = |body: &'static str| {
+        move || async move { HttpResponse::Ok().content_type("application/x-mpegURL").body(body) }
+    };
+
+    test::start(move || {
+        let fetches = segment_fetches.clone();
+
+        App::new()
+            .route("/hls/master.m3u8", web::get().to(playlist(
+                "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=800000\nlow/index.m3u8\n",
+            )))
+            .route("/hls/low/index.m3u8", web::get().to(playlist(
+                "#EXTM3U\n#EXT-X-TARGETDURATION:6\n#EXT-X-MAP:URI=\"init.mp4\"\n\
+                 #EXTINF:6.0,\nseg0.ts\n#EXTINF:6.0,\n/hls/low/seg1.ts\n\
+                 #EXTINF:6.0,\nhttp://169.254.169.254/latest/seg2.ts\n#EXT-X-ENDLIST\n",
+            )))
+            .route("/hls/low/seg0.ts", web::get().to(move || {
+                fetches.fetch_add(1, Ordering::SeqCst);
+
+                async {
+                    tokio::time::delay_for(Duration::from_millis(200)).await;
+                    HttpResponse::Ok().content_type("video/mp2t").body("segment zero")
+                }
+            }))
+    })
+}
+
+/// The URIs of a playlist, without the tags.
+fn playlist_uris(playlist: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(playlist)
+        .lines()
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+#[actix_rt::test]
+async fn remote_streams_are_proxied() {
+    let segment_fetches = Arc::new(AtomicUsize::new(0));
+    let remote = remote_stand_in(segment_fetches.clone());
+    let proxied = |url: String| format!(
+        "/room/{}/remote?url={}&expires=",
+        ROOM,
+        url::form_urlencoded::byte_serialize(url.as_bytes()).collect::<String>(),
+    );
+
+    let data = app_data(SystemClock::shared(), None);
+    let signer = data.media_signer.clone();
+    let stream = MediaStream {
+        source: MediaSource::Remote { url: remote.url("/hls/master.m3u8") },
+        ..fixture_stream()
+    };
+    server::register_room(&data, stream, String::from(ROOM)).await.unwrap();
+    let srv = serve(data);
+
+    let (status, _, _) = fetch(&srv, &format!("/room/{}/master.m3u8", ROOM), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let alice = join(&srv, "alice").await;
+    let (status, _, master) = fetch(&srv, &format!("/room/{}/master.m3u8", ROOM), Some(alice.cookie())).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let renditions = playlist_uris(&master);
+    assert_eq!(renditions.len(), 1);
+    assert!(renditions[0].starts_with(&proxied(remote.url("/hls/low/index.m3u8"))), "{:?}", renditions);
+
+    // Relative, absolute and attribute URIs on the stream's host all go through the proxy. Since
+    // players only ever talk to this server, it doesn't open itself up to other origins.
+    let (status, headers, playlist) = fetch(&srv, &renditions[0], None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    assert!(String::from_utf8_lossy(&playlist).contains(&proxied(remote.url("/hls/low/init.mp4"))));
+
+    let segments = playlist_uris(&playlist);
+    assert_eq!(segments.len(), 3);
+    assert!(segments[0].starts_with(&proxied(remote.url("/hls/low/seg0.ts"))), "{:?}", segments);
+    assert!(segments[1].starts_with(&proxied(remote.url("/hls/low/seg1.ts"))), "{:?}", segments);
+
+    // Anywhere else is left to the player, the proxy doesn't sign it.
+    assert_eq!(segments[2], "http://169.254.169.254/latest/seg2.ts");
+
+    // Fetched from the remote host once for the whole room.
+    for _ in 0..2 {
+        let (status, headers, segment) = fetch(&srv, &segments[0], None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers.get(header::CONTENT_TYPE).unwrap(), "video/mp2t");
+        assert_eq!(segment, b"segment zero");
+    }
+    assert_eq!(segment_fetches.load(Ordering::SeqCst), 1);
+
+    // Missing on the remote host.
+    let (status, _, _) = fetch(&srv, &segments[1], None).await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+    // Not an open proxy.
+    let forged = format!("{}9999999999&signature=00", proxied(String::from("http://127.0.0.1:1/")));
+    let (status, _, _) = fetch(&srv, &forged, Some(alice.cookie())).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Not even with a valid signature for somewhere off the stream's host.
+    let elsewhere = "http://169.254.169.254/latest/meta-data";
+    let signed = format!(
+        "{}{}",
+        proxied(String::from(elsewhere)).trim_end_matches("expires="),
+        signer.query(ROOM, elsewhere, chrono::Utc::now()),
+    );
+    let (status, _, _) = fetch(&srv, &signed, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn concurrent_requests_share_one_remote_fetch() {
+    let segment_fetches = Arc::new(AtomicUsize::new(0));
+    let remote = remote_stand_in(segment_fetches.clone());
+
+    let data = app_data(SystemClock::shared(), None);
+    let signer = data.media_signer.clone();
+    let stream = MediaStream {
+        source: MediaSource::Remote { url: remote.url("/hls/master.m3u8") },
+        ..fixture_stream()
+    };
+    server::register_room(&data, stream, String::from(ROOM)).await.unwrap();
+    let srv = serve(data);
+
+    let segment = remote.url("/hls/low/seg0.ts");
+    let url = format!(
+        "/room/{}/remote?url={}&{}",
+        ROOM,
+        url::form_urlencoded::byte_serialize(segment.as_bytes()).collect::<String>(),
+        signer.query(ROOM, &segment, chrono::Utc::now()),
+    );
+
+    // All of them ask while the remote host is still responding to the first.
+    let responses = futures::future::join_all((0..5).map(|_| fetch(&srv, &url, None))).await;
+    for (status, headers, segment) in responses {
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers.get(header::CONTENT_TYPE).unwrap(), "video/mp2t");
+        assert_eq!(segment, b"segment zero");
+    }
+    assert_eq!(segment_fetches.load(Ordering::SeqCst), 1);
+}
+
+#[actix_rt::test]
+async fn remote_hosts_on_private_addresses_are_refused() {
+    let segment_fetches = Arc::new(AtomicUsize::new(0));
+    let remote = remote_stand_in(segment_fetches.clone());
+
+    let mut data = app_data(SystemClock::shared(), None);
+    data.proxy = Some(HlsProxy::new(ProxyConfig::default(), data.clock.clone()));
+    let stream = MediaStream {
+        source: MediaSource::Remote { url: remote.url("/hls/master.m3u8") },
+        ..fixture_stream()
+    };
+    server::register_room(&data, stream, String::from(ROOM)).await.unwrap();
+    let srv = serve(data);
+
+    let alice = join(&srv, "alice").await;
+    let (status, _, _) = fetch(&srv, &format!("/room/{}/master.m3u8", ROOM), Some(alice.cookie())).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[actix_rt::test]
+async fn subtitles_are_served_as_webvtt_with_room_offset() {
+    let dir = TempDir::new("subtitles");
+    let media = dir.join("test");
+    std::fs::create_dir_all(&media).unwrap();
+
+    std::fs::write(
+        media.join("movie.en.srt"),
+        "1\r\n00:00:01,000 --> 00:00:02,500\r\n<font color=\"red\">Hello</font> <i>there</i>\r\n\r\n\
+         2\r\n00:00:03,000 --> 00:00:04,000\r\nSecond line\r\n",
+    ).unwrap();
+    std::fs::write(
+        media.join("movie.de.ass"),
+        "[Script Info]\nTitle: Test\n\n[Events]\n\
+         Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n\
+         Dialogue: 0,0:00:01.50,0:00:03.00,Default,,0,0,0,,{\\i1}Hallo{\\i0}, Welt\\NZweite Zeile\n",
+    ).unwrap();
+
+    let mut data = app_data(SystemClock::shared(), None);
+    data.media_dir = dir.to_path_buf();
+    let stream = MediaStream {
+        subtitles: subtitles::discover(&media).unwrap(),
+        ..fixture_stream()
+    };
+    server::register_room(&data, stream, String::from(ROOM)).await.unwrap();
+    let srv = serve(data);
+
+    let mut alice = join(&srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+    let cookie = alice.cookie().to_string();
+
+    let subtitles = |track: &str| {
+        let url = format!("/room/{}/subtitles/{}", ROOM, track);
+        let (srv, cookie) = (&srv, cookie.clone());
+
+        async move {
+            let (status, _, body) = fetch(srv, &url, Some(&cookie)).await;
+
+            (status, String::from_utf8(body).unwrap())
+        }
+    };
+
+    // The offset of the room is applied to the cues, which change along with it.
+    let (_, headers, _) = fetch(&srv, &format!("/room/{}/subtitles/movie.en.srt", ROOM), Some(&cookie)).await;
+    assert_eq!(headers.get(header::CACHE_CONTROL).unwrap(), "no-cache");
+
+    let (_, srt) = subtitles("movie.en.srt").await;
+    assert_eq!(srt, "WEBVTT\n\n\
+        00:00:01.000 --> 00:00:02.500\nHello <i>there</i>\n\n\
+        00:00:03.000 --> 00:00:04.000\nSecond line\n\n");
+
+    let (_, ass) = subtitles("movie.de.ass").await;
+    assert_eq!(ass, "WEBVTT\n\n00:00:01.500 --> 00:00:03.000\nHallo, Welt\nZweite Zeile\n\n");
+
+    let (status, _) = subtitles("..%2Flibrary.json").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let tracks = alice.room().stream.unwrap().subtitles;
+    assert_eq!(tracks.iter().map(|t| t.language.as_deref()).collect::<Vec<_>>(), vec![Some("de"), Some("en")]);
+
+    let mut bob = join(&srv, "bob").await;
+    expect_room_state(&mut bob).await;
+
+    // Only the host and admins pick the room's subtitles, bob's choice goes nowhere. His next
+    // message gets through, so alice would have heard about it by then.
+    bob.set_subtitles(Some(String::from("movie.de.ass")), 3.0);
+    bob.set_state(PlayState::Play);
+    expect(&mut alice, |m| match m {
+        ToSessionMessage::SubtitlesChanged { .. } => panic!("subtitles changed by a guest: {:?}", m),
+        ToSessionMessage::SetState { .. } => Some(()),
+        _ => None,
+    }).await;
+
+    alice.set_subtitles(Some(String::from("movie.en.srt")), -1.5);
+
+    let changed = expect(&mut bob, |m| match m {
+        ToSessionMessage::SubtitlesChanged { user, subtitle, offset } => Some((*user, subtitle.clone(), *offset)),
+        _ => None,
+    }).await;
+    assert_eq!(changed, (alice_id, Some(String::from("movie.en.srt")), -1.5));
+
+    // The first cue is cut short at the start.
+    let (_, shifted) = subtitles("movie.en.srt").await;
+    assert_eq!(shifted, "WEBVTT\n\n\
+        00:00:00.000 --> 00:00:01.000\nHello <i>there</i>\n\n\
+        00:00:01.500 --> 00:00:02.500\nSecond line\n\n");
+
+    // Late joiners start out with the room's choice.
+    let mut carol = join(&srv, "carol").await;
+    expect_room_state(&mut carol).await;
+    let stream = carol.room().stream.unwrap();
+    assert_eq!(stream.subtitle.as_deref(), Some("movie.en.srt"));
+    assert_eq!(stream.subtitle_offset, -1.5);
+}
+
+#[actix_rt::test]
+async fn audio_tracks_are_listed_and_reported() {
+    let dir = TempDir::new("audio");
+    let media = dir.join("test");
+
+    write_rendition(&media.join("480p"), 1_500);
+    write_rendition(&media.join("720p"), 3_000);
+    write_rendition(&media.join("audio_en"), 750);
+    write_rendition(&media.join("audio_de"), 600);
+    std::fs::write(
+        media.join("master.m3u8"),
+        "#EXTM3U\n\
+         #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aud\",NAME=\"English\",LANGUAGE=\"en\",DEFAULT=YES,URI=\"audio_en/index.m3u8\"\n\
+         #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aud\",NAME=\"Deutsch, Kommentar\",LANGUAGE=\"de\",URI=\"audio_de/index.m3u8\"\n\
+         #EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",NAME=\"English\",URI=\"subs/index.m3u8\"\n\
+         #EXT-X-STREAM-INF:BANDWIDTH=4000,AUDIO=\"aud\"\n720p/index.m3u8\n",
+    ).unwrap();
+
+    let mut stream = stream_with_renditions();
+    library::discover_tracks(&dir, &mut stream);
+
+    let mut data = app_data(SystemClock::shared(), None);
+    data.media_dir = dir.to_path_buf();
+    server::register_room(&data, stream, String::from(ROOM)).await.unwrap();
+    let srv = serve(data);
+
+    let mut alice = join(&srv, "alice").await;
+    let (alice_id, _) = expect_room_state(&mut alice).await;
+
+    let tracks = alice.room().stream.unwrap().audio_tracks;
+    let names = tracks.iter().map(|t| (t.name.as_str(), t.language.as_deref(), t.default)).collect::<Vec<_>>();
+    assert_eq!(names, vec![("English", Some("en"), true), ("Deutsch, Kommentar", Some("de"), false)]);
+
+    // The generated playlist keeps the group, adding the audio to every rendition's bandwidth.
+    let (_, _, playlist) = fetch(&srv, &format!("/room/{}/master.m3u8", ROOM), Some(alice.cookie())).await;
+    let playlist = String::from_utf8(playlist).unwrap();
+    assert!(playlist.contains(
+        "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aud\",NAME=\"Deutsch, Kommentar\",LANGUAGE=\"de\",\
+         DEFAULT=NO,AUTOSELECT=YES,URI=\"media/audio_de/index.m3u8?expires="
+    ), "{}", playlist);
+    assert!(playlist.contains(
+        "#EXT-X-STREAM-INF:BANDWIDTH=5000,AVERAGE-BANDWIDTH=5000,AUDIO=\"aud\"\nmedia/720p/index.m3u8?expires="
+    ), "{}", playlist);
+
+    alice.set_player(PlayerState {
+        audio: Some(String::from("Deutsch, Kommentar")),
+        ..PlayerState::default()
+    });
+    alice.report_state();
+
+    let audio = expect(&mut alice, |m| match m {
+        ToSessionMessage::RoomUpdate { participants } => {
+            participants.iter().find(|p| p.user_id == alice_id).and_then(|p| p.audio.clone())
+        }
+        _ => None,
+    }).await;
+    assert_eq!(audio, "Deutsch, Kommentar");
+}